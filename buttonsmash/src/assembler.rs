@@ -0,0 +1,692 @@
+/*
+ * Text assembly of micro VM programs, one opcode per line:
+ *
+ *   # Comment (`;` works too)
+ *   proc 0:
+ *       bind.short 4 -> proc 1
+ *       bind.long 4 -> toggle 10
+ *       stop
+ *   proc 1:
+ *       load r0 3
+ *   again:
+ *       toggle 10
+ *       wait 500
+ *       loop r0 again
+ *       stop
+ *
+ * Registers are written as `r0`, variables as `v0` and procedure arguments in
+ * place of outputs, layers and loaded values as `arg0`/`arg1`. Jumps take a
+ * label or a relative offset (`+2`, `-3`).
+ */
+
+use core::fmt;
+use std::collections::HashMap;
+
+use crate::consts::{OutputState, Trigger, ARG0, ARG1};
+use crate::opcodes::Opcode;
+use crate::threads::Retrigger;
+
+/// Problem with an assembly line.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AsmErrorKind {
+    UnknownMnemonic(String),
+    /// Operands don't match the opcode.
+    InvalidOperands(String),
+    InvalidNumber(String),
+    UnknownLabel(String),
+    DuplicateLabel(String),
+    /// Relative jump doesn't fit the offset.
+    JumpTooFar(String),
+}
+
+/// Assembly error with a line number (starting at 1).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub kind: AsmErrorKind,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            AsmErrorKind::UnknownMnemonic(name) => write!(f, "unknown mnemonic `{}`", name),
+            AsmErrorKind::InvalidOperands(usage) => {
+                write!(f, "invalid operands, expected `{}`", usage)
+            }
+            AsmErrorKind::InvalidNumber(token) => write!(f, "invalid number `{}`", token),
+            AsmErrorKind::UnknownLabel(label) => write!(f, "unknown label `{}`", label),
+            AsmErrorKind::DuplicateLabel(label) => write!(f, "label `{}` defined twice", label),
+            AsmErrorKind::JumpTooFar(label) => write!(f, "jump to `{}` is too far", label),
+        }
+    }
+}
+
+/// Trigger names used by `bind.*` mnemonics.
+const TRIGGERS: [(Trigger, &str); 6] = [
+    (Trigger::ShortClick, "short"),
+    (Trigger::LongClick, "long"),
+    (Trigger::Activated, "activate"),
+    (Trigger::Deactivated, "deactivate"),
+    (Trigger::LongActivated, "long_activate"),
+    (Trigger::LongDeactivated, "long_deactivate"),
+];
+
+const RETRIGGERS: [(Retrigger, &str); 4] = [
+    (Retrigger::Parallel, "parallel"),
+    (Retrigger::Restart, "restart"),
+    (Retrigger::Ignore, "ignore"),
+    (Retrigger::Cancel, "cancel"),
+];
+
+fn trigger_name(trigger: Trigger) -> &'static str {
+    TRIGGERS
+        .iter()
+        .find(|(known, _)| *known == trigger)
+        .map_or("short", |(_, name)| name)
+}
+
+const STATES: [(OutputState, &str); 3] = [
+    (OutputState::Off, "off"),
+    (OutputState::On, "on"),
+    (OutputState::Toggle, "toggle"),
+];
+
+fn state_name(state: OutputState) -> &'static str {
+    STATES
+        .iter()
+        .find(|(known, _)| *known == state)
+        .map_or("toggle", |(_, name)| name)
+}
+
+fn retrigger_name(policy: Retrigger) -> &'static str {
+    RETRIGGERS
+        .iter()
+        .find(|(known, _)| *known == policy)
+        .map_or("parallel", |(_, name)| name)
+}
+
+/// Line without a comment.
+fn strip_comment(line: &str) -> &str {
+    match line.find(['#', ';']) {
+        Some(idx) => &line[..idx],
+        None => line,
+    }
+}
+
+/// Label defined by the line, if it's a label line.
+fn label_of(line: &str) -> Option<&str> {
+    let label = line.strip_suffix(':')?;
+    let valid = !label.is_empty()
+        && !label.starts_with("proc ")
+        && label
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+    valid.then_some(label)
+}
+
+/// Parses operands of a single line.
+struct Operands<'a> {
+    tokens: Vec<&'a str>,
+    pos: usize,
+    pc: usize,
+    labels: &'a HashMap<String, usize>,
+}
+
+impl<'a> Operands<'a> {
+    fn next(&mut self) -> Option<&'a str> {
+        let token = self.tokens.get(self.pos).copied();
+        self.pos += 1;
+        token
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    /// Expect a keyword (eg. `proc` in bindings).
+    fn keyword(&mut self, keyword: &str) -> bool {
+        if self.tokens.get(self.pos) == Some(&keyword) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn number<T: TryFrom<i64>>(&mut self) -> Result<T, AsmErrorKind> {
+        let token = self.next().unwrap_or_default();
+        let value = match token.strip_prefix("0x") {
+            Some(hex) => i64::from_str_radix(hex, 16).ok(),
+            None => token.parse().ok(),
+        };
+        value
+            .and_then(|value| T::try_from(value).ok())
+            .ok_or_else(|| AsmErrorKind::InvalidNumber(token.to_string()))
+    }
+
+    /// Output, layer or loaded value, which can be an argument reference.
+    /// Other operands are not replaced by the arguments.
+    fn argument(&mut self) -> Result<u8, AsmErrorKind> {
+        let reference = match self.tokens.get(self.pos) {
+            Some(&"arg0") => ARG0,
+            Some(&"arg1") => ARG1,
+            _ => return self.number(),
+        };
+        self.pos += 1;
+        Ok(reference)
+    }
+
+    /// Number with a prefix, like `r1` or `v3`.
+    fn prefixed(&mut self, prefix: char) -> Result<u8, AsmErrorKind> {
+        let token = self.next().unwrap_or_default();
+        token
+            .strip_prefix(prefix)
+            .and_then(|idx| idx.parse().ok())
+            .ok_or_else(|| AsmErrorKind::InvalidNumber(token.to_string()))
+    }
+
+    fn register(&mut self) -> Result<u8, AsmErrorKind> {
+        self.prefixed('r')
+    }
+
+    fn variable(&mut self) -> Result<u8, AsmErrorKind> {
+        self.prefixed('v')
+    }
+
+    /// Absolute address of a label or an address.
+    fn address(&mut self) -> Result<u16, AsmErrorKind> {
+        let token = self.next().unwrap_or_default();
+        if let Some(target) = self.labels.get(token) {
+            return u16::try_from(*target).map_err(|_| AsmErrorKind::JumpTooFar(token.to_string()));
+        }
+        token
+            .parse()
+            .map_err(|_| AsmErrorKind::UnknownLabel(token.to_string()))
+    }
+
+    /// Offset to a label or a relative `+n`/`-n` offset.
+    fn offset(&mut self) -> Result<i8, AsmErrorKind> {
+        let token = self.next().unwrap_or_default();
+        if let Some(target) = self.labels.get(token) {
+            let offset = *target as i64 - self.pc as i64;
+            return i8::try_from(offset).map_err(|_| AsmErrorKind::JumpTooFar(token.to_string()));
+        }
+        if token.starts_with(['+', '-']) {
+            return token
+                .parse()
+                .map_err(|_| AsmErrorKind::InvalidNumber(token.to_string()));
+        }
+        Err(AsmErrorKind::UnknownLabel(token.to_string()))
+    }
+
+    fn state(&mut self) -> Result<OutputState, AsmErrorKind> {
+        let token = self.next().unwrap_or_default();
+        STATES
+            .iter()
+            .find(|(_, name)| *name == token)
+            .map(|(state, _)| *state)
+            .ok_or_else(|| AsmErrorKind::InvalidOperands("on|off|toggle".to_string()))
+    }
+
+    fn retrigger(&mut self) -> Result<Retrigger, AsmErrorKind> {
+        let token = self.next().unwrap_or_default();
+        RETRIGGERS
+            .iter()
+            .find(|(_, name)| *name == token)
+            .map(|(policy, _)| *policy)
+            .ok_or_else(|| {
+                AsmErrorKind::InvalidOperands("parallel|restart|ignore|cancel".to_string())
+            })
+    }
+}
+
+/// Binding opcode: `bind.<trigger> IN -> proc P [A B]`, and for short/long
+/// clicks also `-> toggle OUT`.
+fn parse_bind(trigger: &str, ops: &mut Operands) -> Result<Opcode, AsmErrorKind> {
+    let usage = || AsmErrorKind::InvalidOperands(format!("bind.{} IN -> proc P [A B]", trigger));
+    if trigger == "hold" {
+        let in_idx = ops.number()?;
+        if !ops.keyword("layer") {
+            return Err(AsmErrorKind::InvalidOperands(
+                "bind.hold IN -> layer L".to_string(),
+            ));
+        }
+        return Ok(Opcode::BindLayerHold(in_idx, ops.argument()?));
+    }
+
+    let in_idx = ops.number()?;
+    if ops.keyword("toggle") {
+        let out_idx = ops.argument()?;
+        return match trigger {
+            "short" => Ok(Opcode::BindShortToggle(in_idx, out_idx)),
+            "long" => Ok(Opcode::BindLongToggle(in_idx, out_idx)),
+            _ => Err(usage()),
+        };
+    }
+    if !ops.keyword("proc") {
+        return Err(usage());
+    }
+    let proc = ops.number()?;
+    if !ops.is_empty() {
+        let trigger = TRIGGERS
+            .iter()
+            .find(|(_, name)| *name == trigger)
+            .map(|(trigger, _)| *trigger)
+            .ok_or_else(usage)?;
+        return Ok(Opcode::BindCallWith(
+            in_idx,
+            trigger,
+            proc,
+            ops.argument()?,
+            ops.argument()?,
+        ));
+    }
+    Ok(match trigger {
+        "short" => Opcode::BindShortCall(in_idx, proc),
+        "long" => Opcode::BindLongCall(in_idx, proc),
+        "activate" => Opcode::BindActivateCall(in_idx, proc),
+        "deactivate" => Opcode::BindDeactivateCall(in_idx, proc),
+        "long_activate" => Opcode::BindLongActivate(in_idx, proc),
+        "long_deactivate" => Opcode::BindLongDeactivate(in_idx, proc),
+        _ => return Err(usage()),
+    })
+}
+
+/// Parse a single instruction.
+fn parse_instruction(mnemonic: &str, ops: &mut Operands) -> Result<Opcode, AsmErrorKind> {
+    let opcode = match mnemonic {
+        "noop" => Opcode::Noop,
+        "stop" => Opcode::Stop,
+        "call" => {
+            let proc = ops.number()?;
+            if ops.is_empty() {
+                Opcode::Call(proc)
+            } else {
+                Opcode::CallWith(proc, ops.argument()?, ops.argument()?)
+            }
+        }
+
+        "toggle" => Opcode::Toggle(ops.argument()?),
+        "activate" => Opcode::Activate(ops.argument()?),
+        "deactivate" => Opcode::Deactivate(ops.argument()?),
+        "activate.for" => Opcode::ActivateFor(ops.argument()?, ops.number()?),
+        "remote" => Opcode::SetRemote(ops.number()?, ops.argument()?, ops.state()?),
+        "wait" => Opcode::Wait(ops.number()?),
+        "wait.release" => Opcode::WaitForRelease(ops.number()?),
+
+        "layer.push" => Opcode::LayerPush(ops.argument()?),
+        "layer.pop" => Opcode::LayerPop,
+        "layer.set" => Opcode::LayerSet(ops.argument()?),
+        "layer.default" => Opcode::LayerDefault,
+        "zone.assign" => Opcode::ZoneAssign(ops.number()?, ops.number()?),
+        "zone.select" => Opcode::ZoneSelect(ops.number()?),
+
+        "bind.clear" => Opcode::BindClearAll,
+
+        "load" => Opcode::Load(ops.register()?, ops.argument()?),
+        "read.input" => Opcode::ReadInput(ops.register()?, ops.number()?),
+        "read.output" => Opcode::ReadOutput(ops.register()?, ops.argument()?),
+        "eq" => Opcode::Eq(ops.register()?, ops.register()?),
+        "lt" => Opcode::Lt(ops.register()?, ops.register()?),
+        "gt" => Opcode::Gt(ops.register()?, ops.register()?),
+        "and" => Opcode::And(ops.register()?, ops.register()?),
+        "or" => Opcode::Or(ops.register()?, ops.register()?),
+        "not" => Opcode::Not(ops.register()?),
+        "call.if" => Opcode::CallConditionally(ops.register()?, ops.number()?, ops.number()?),
+
+        "set" => Opcode::Set(ops.variable()?, ops.number()?),
+        "inc" => Opcode::Inc(ops.variable()?),
+        "dec" => Opcode::Dec(ops.variable()?),
+        "add" => Opcode::Add(ops.variable()?, ops.number()?),
+        "mod" => Opcode::Mod(ops.variable()?, ops.number()?),
+        "load.var" => Opcode::LoadVar(ops.register()?, ops.variable()?),
+        "store.var" => Opcode::StoreVar(ops.variable()?, ops.register()?),
+        "var.eq" => Opcode::VarEq(ops.register()?, ops.variable()?, ops.number()?),
+        "var.lt" => Opcode::VarLt(ops.register()?, ops.variable()?, ops.number()?),
+        "var.gt" => Opcode::VarGt(ops.register()?, ops.variable()?, ops.number()?),
+
+        "jump" => Opcode::Jump(ops.offset()?),
+        "jump.to" => Opcode::JumpTo(ops.address()?),
+        "jump.zero" => Opcode::JumpIfZero(ops.register()?, ops.offset()?),
+        "jump.nonzero" => Opcode::JumpIfNotZero(ops.register()?, ops.offset()?),
+        "loop" => Opcode::Loop(ops.register()?, ops.offset()?),
+
+        "retrigger" => Opcode::SetRetrigger(ops.number()?, ops.retrigger()?),
+        "cancel" => Opcode::Cancel(ops.number()?),
+        "syscall" => Opcode::Syscall(ops.number()?, ops.number()?),
+        "error.handler" => Opcode::SetErrorHandler(ops.number()?),
+        _ if mnemonic.starts_with("bind.") => parse_bind(&mnemonic[5..], ops)?,
+        _ => return Err(AsmErrorKind::UnknownMnemonic(mnemonic.to_string())),
+    };
+    if !ops.is_empty() {
+        let extra = ops.tokens[ops.pos..].join(" ");
+        return Err(AsmErrorKind::InvalidOperands(format!(
+            "{} without {}",
+            mnemonic, extra
+        )));
+    }
+    Ok(opcode)
+}
+
+/// Assemble program from text.
+pub fn assemble(source: &str) -> Result<Vec<Opcode>, AsmError> {
+    // First pass: addresses of labels.
+    let mut labels = HashMap::new();
+    let mut instructions = Vec::new();
+    for (idx, line) in source.lines().enumerate() {
+        let line_no = idx + 1;
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(label) = label_of(line) {
+            let previous = labels.insert(label.to_string(), instructions.len());
+            if previous.is_some() {
+                return Err(AsmError {
+                    line: line_no,
+                    kind: AsmErrorKind::DuplicateLabel(label.to_string()),
+                });
+            }
+            continue;
+        }
+        instructions.push((line_no, line));
+    }
+
+    // Second pass: opcodes.
+    let mut program = Vec::with_capacity(instructions.len());
+    for (pc, (line_no, line)) in instructions.into_iter().enumerate() {
+        let error = |kind| AsmError {
+            line: line_no,
+            kind,
+        };
+        let mut tokens = line
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|token| !token.is_empty() && *token != "->");
+        let mnemonic = tokens.next().unwrap_or_default();
+        let mut ops = Operands {
+            tokens: tokens.collect(),
+            pos: 0,
+            pc,
+            labels: &labels,
+        };
+        let opcode = if mnemonic == "proc" {
+            // `proc N:` starts a procedure.
+            let proc = ops.next().and_then(|proc| proc.strip_suffix(':'));
+            match proc.and_then(|proc| proc.parse().ok()) {
+                Some(proc) if ops.is_empty() => Opcode::Start(proc),
+                _ => return Err(error(AsmErrorKind::InvalidOperands("proc N:".to_string()))),
+            }
+        } else {
+            parse_instruction(mnemonic, &mut ops).map_err(error)?
+        };
+        program.push(opcode);
+    }
+    Ok(program)
+}
+
+/// Output, layer or loaded value, which might reference an argument.
+fn operand(value: u8) -> String {
+    match value {
+        ARG0 => "arg0".to_string(),
+        ARG1 => "arg1".to_string(),
+        _ => value.to_string(),
+    }
+}
+
+/// Absolute target of a jump at `pc`.
+fn jump_target(pc: usize, opcode: &Opcode) -> Option<usize> {
+    let offset = match *opcode {
+        Opcode::JumpTo(target) => return Some(target as usize),
+        Opcode::Jump(offset)
+        | Opcode::JumpIfZero(_, offset)
+        | Opcode::JumpIfNotZero(_, offset)
+        | Opcode::Loop(_, offset) => offset,
+        _ => return None,
+    };
+    pc.checked_add_signed(offset as isize)
+}
+
+/// Format opcode at `pc` in the assembly syntax. Jump targets within the
+/// program are written as labels.
+fn format_opcode(pc: usize, opcode: &Opcode, program_len: usize) -> String {
+    let target = || match jump_target(pc, opcode) {
+        Some(target) if target < program_len => format!("l{}", target),
+        _ => match *opcode {
+            Opcode::JumpTo(target) => target.to_string(),
+            Opcode::Jump(offset)
+            | Opcode::JumpIfZero(_, offset)
+            | Opcode::JumpIfNotZero(_, offset)
+            | Opcode::Loop(_, offset) => format!("{:+}", offset),
+            _ => String::new(),
+        },
+    };
+    match *opcode {
+        Opcode::Noop => "noop".to_string(),
+        Opcode::Start(proc) => format!("proc {}:", proc),
+        Opcode::Stop => "stop".to_string(),
+        Opcode::Call(proc) => format!("call {}", proc),
+        Opcode::CallWith(proc, first, second) => {
+            format!("call {} {} {}", proc, operand(first), operand(second))
+        }
+
+        Opcode::Toggle(out_idx) => format!("toggle {}", operand(out_idx)),
+        Opcode::Activate(out_idx) => format!("activate {}", operand(out_idx)),
+        Opcode::Deactivate(out_idx) => format!("deactivate {}", operand(out_idx)),
+        Opcode::ActivateFor(out_idx, ms) => format!("activate.for {} {}", operand(out_idx), ms),
+        Opcode::SetRemote(dev, out_idx, state) => {
+            format!("remote {} {} {}", dev, operand(out_idx), state_name(state))
+        }
+        Opcode::Wait(ms) => format!("wait {}", ms),
+        Opcode::WaitForRelease(ms) => format!("wait.release {}", ms),
+
+        Opcode::LayerPush(layer) => format!("layer.push {}", operand(layer)),
+        Opcode::LayerPop => "layer.pop".to_string(),
+        Opcode::LayerSet(layer) => format!("layer.set {}", operand(layer)),
+        Opcode::LayerDefault => "layer.default".to_string(),
+        Opcode::ZoneAssign(in_idx, zone) => format!("zone.assign {} {}", in_idx, zone),
+        Opcode::ZoneSelect(zone) => format!("zone.select {}", zone),
+
+        Opcode::BindClearAll => "bind.clear".to_string(),
+        Opcode::BindShortCall(in_idx, proc) => format!("bind.short {} -> proc {}", in_idx, proc),
+        Opcode::BindLongCall(in_idx, proc) => format!("bind.long {} -> proc {}", in_idx, proc),
+        Opcode::BindActivateCall(in_idx, proc) => {
+            format!("bind.activate {} -> proc {}", in_idx, proc)
+        }
+        Opcode::BindDeactivateCall(in_idx, proc) => {
+            format!("bind.deactivate {} -> proc {}", in_idx, proc)
+        }
+        Opcode::BindLongActivate(in_idx, proc) => {
+            format!("bind.long_activate {} -> proc {}", in_idx, proc)
+        }
+        Opcode::BindLongDeactivate(in_idx, proc) => {
+            format!("bind.long_deactivate {} -> proc {}", in_idx, proc)
+        }
+        Opcode::BindCallWith(in_idx, trigger, proc, first, second) => format!(
+            "bind.{} {} -> proc {} {} {}",
+            trigger_name(trigger),
+            in_idx,
+            proc,
+            operand(first),
+            operand(second)
+        ),
+        Opcode::BindShortToggle(in_idx, out_idx) => {
+            format!("bind.short {} -> toggle {}", in_idx, operand(out_idx))
+        }
+        Opcode::BindLongToggle(in_idx, out_idx) => {
+            format!("bind.long {} -> toggle {}", in_idx, operand(out_idx))
+        }
+        Opcode::BindLayerHold(in_idx, layer) => {
+            format!("bind.hold {} -> layer {}", in_idx, operand(layer))
+        }
+
+        Opcode::Load(reg, value) => format!("load r{} {}", reg, operand(value)),
+        Opcode::ReadInput(reg, in_idx) => format!("read.input r{} {}", reg, in_idx),
+        Opcode::ReadOutput(reg, out_idx) => format!("read.output r{} {}", reg, operand(out_idx)),
+        Opcode::Eq(first, second) => format!("eq r{} r{}", first, second),
+        Opcode::Lt(first, second) => format!("lt r{} r{}", first, second),
+        Opcode::Gt(first, second) => format!("gt r{} r{}", first, second),
+        Opcode::And(first, second) => format!("and r{} r{}", first, second),
+        Opcode::Or(first, second) => format!("or r{} r{}", first, second),
+        Opcode::Not(reg) => format!("not r{}", reg),
+        Opcode::CallConditionally(reg, if_true, if_false) => {
+            format!("call.if r{} {} {}", reg, if_true, if_false)
+        }
+
+        Opcode::Set(var, value) => format!("set v{} {}", var, value),
+        Opcode::Inc(var) => format!("inc v{}", var),
+        Opcode::Dec(var) => format!("dec v{}", var),
+        Opcode::Add(var, value) => format!("add v{} {}", var, value),
+        Opcode::Mod(var, value) => format!("mod v{} {}", var, value),
+        Opcode::LoadVar(reg, var) => format!("load.var r{} v{}", reg, var),
+        Opcode::StoreVar(var, reg) => format!("store.var v{} r{}", var, reg),
+        Opcode::VarEq(reg, var, value) => format!("var.eq r{} v{} {}", reg, var, value),
+        Opcode::VarLt(reg, var, value) => format!("var.lt r{} v{} {}", reg, var, value),
+        Opcode::VarGt(reg, var, value) => format!("var.gt r{} v{} {}", reg, var, value),
+
+        Opcode::Jump(_) => format!("jump {}", target()),
+        Opcode::JumpTo(_) => format!("jump.to {}", target()),
+        Opcode::JumpIfZero(reg, _) => format!("jump.zero r{} {}", reg, target()),
+        Opcode::JumpIfNotZero(reg, _) => format!("jump.nonzero r{} {}", reg, target()),
+        Opcode::Loop(reg, _) => format!("loop r{} {}", reg, target()),
+
+        Opcode::SetRetrigger(proc, policy) => {
+            format!("retrigger {} {}", proc, retrigger_name(policy))
+        }
+        Opcode::Cancel(proc) => format!("cancel {}", proc),
+        Opcode::Syscall(id, arg) => format!("syscall {} {}", id, arg),
+        Opcode::SetErrorHandler(proc) => format!("error.handler {}", proc),
+    }
+}
+
+/// Disassemble program into text accepted by `assemble`.
+pub fn disassemble(program: &[Opcode]) -> String {
+    let mut targets = vec![false; program.len()];
+    for (pc, opcode) in program.iter().enumerate() {
+        if let Some(target) = jump_target(pc, opcode) {
+            if let Some(is_target) = targets.get_mut(target) {
+                *is_target = true;
+            }
+        }
+    }
+
+    let mut text = String::new();
+    for (pc, opcode) in program.iter().enumerate() {
+        if targets[pc] {
+            text.push_str(&format!("l{}:\n", pc));
+        }
+        if !matches!(opcode, Opcode::Start(_)) {
+            text.push_str("    ");
+        }
+        text.push_str(&format_opcode(pc, opcode, program.len()));
+        text.push('\n');
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "\
+# Blink output given by the argument.
+proc 0:
+    bind.short 4 -> proc 1        ; comment after an opcode
+    bind.long 4 -> toggle 10
+    bind.long_deactivate 5 -> proc 2 7 arg1
+    bind.hold 6 -> layer 1
+    retrigger 1 restart
+    stop
+proc 1:
+    load r0 3
+again:
+    toggle 10
+    wait 500
+    loop r0 again
+    jump.zero r0 +2
+    call 2 11 0
+    stop
+proc 2:
+    activate.for arg0 1000
+    remote 3 arg0 off
+    stop
+";
+
+    #[test]
+    fn it_assembles_source() {
+        let program = assemble(SOURCE).unwrap();
+        assert_eq!(
+            program,
+            vec![
+                Opcode::Start(0),
+                Opcode::BindShortCall(4, 1),
+                Opcode::BindLongToggle(4, 10),
+                Opcode::BindCallWith(5, Trigger::LongDeactivated, 2, 7, ARG1),
+                Opcode::BindLayerHold(6, 1),
+                Opcode::SetRetrigger(1, Retrigger::Restart),
+                Opcode::Stop,
+                Opcode::Start(1),
+                Opcode::Load(0, 3),
+                Opcode::Toggle(10),
+                Opcode::Wait(500),
+                Opcode::Loop(0, -2),
+                Opcode::JumpIfZero(0, 2),
+                Opcode::CallWith(2, 11, 0),
+                Opcode::Stop,
+                Opcode::Start(2),
+                Opcode::ActivateFor(ARG0, 1000),
+                Opcode::SetRemote(3, ARG0, OutputState::Off),
+                Opcode::Stop,
+            ]
+        );
+    }
+
+    #[test]
+    fn it_disassembles_to_same_program() {
+        let program = assemble(SOURCE).unwrap();
+        let text = disassemble(&program);
+        assert!(text.contains("proc 1:\n    load r0 3\nl9:\n    toggle 10\n"));
+        assert!(text.contains("    loop r0 l9\n    jump.zero r0 l14\n"));
+        assert_eq!(assemble(&text).unwrap(), program);
+        assert_eq!(disassemble(&assemble(&text).unwrap()), text);
+    }
+
+    #[test]
+    fn it_reports_errors_with_lines() {
+        let error = |source| assemble(source).unwrap_err();
+        assert_eq!(
+            error("proc 0:\n    blink 1\n"),
+            AsmError {
+                line: 2,
+                kind: AsmErrorKind::UnknownMnemonic("blink".to_string())
+            }
+        );
+        assert_eq!(
+            error("proc 0:\n  toggle 300\n").kind,
+            AsmErrorKind::InvalidNumber("300".to_string())
+        );
+        assert_eq!(
+            error("proc 0:\n  jump nowhere\n").kind,
+            AsmErrorKind::UnknownLabel("nowhere".to_string())
+        );
+        assert_eq!(
+            error("a:\nproc 0:\na:\n").kind,
+            AsmErrorKind::DuplicateLabel("a".to_string())
+        );
+        assert_eq!(error("proc 0:\n  toggle 1 2\n").line, 2);
+        // Arguments only replace outputs, layers and loaded values.
+        for line in ["wait arg0", "call arg0", "read.input r0 arg1"] {
+            assert_eq!(
+                assemble(&format!("proc 0:\n  {}\n", line))
+                    .unwrap_err()
+                    .kind,
+                AsmErrorKind::InvalidNumber(line[line.len() - 4..].to_string())
+            );
+        }
+        assert!(assemble("proc 0:\n  load r0 arg1\n  layer.set arg0\n").is_ok());
+        assert_eq!(
+            error("proc x:\n").kind,
+            AsmErrorKind::InvalidOperands("proc N:".to_string())
+        );
+    }
+}
@@ -7,6 +7,8 @@ pub enum Action {
     Single(Command),
     /// Button executes a procedure.
     Proc(ProcIdx),
+    /// Button executes a procedure with arguments.
+    ProcWith(ProcIdx, Args),
     /// No operation - Action is undefined.
     Noop,
 }
@@ -55,6 +57,15 @@ impl Default for Binding {
     }
 }
 
+/// Binding was not added.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BindError {
+    /// Input 0 is reserved and can't be bound.
+    ReservedInput,
+    /// All binding slots are in use.
+    Full,
+}
+
 /// Keeps bindings and finds the valid ones.
 pub struct BindingList<const N: usize> {
     bindings: [Binding; N],
@@ -139,8 +150,10 @@ impl<const N: usize> BindingList<N> {
     }
 
     /// Bind input (overwrite based on input idx and layer or add new binding)
-    pub fn bind(&mut self, binding: Binding) {
-        assert!(binding.idx != 0);
+    pub fn bind(&mut self, binding: Binding) -> Result<(), BindError> {
+        if binding.idx == 0 {
+            return Err(BindError::ReservedInput);
+        }
 
         if let Some(idx) =
             self.find_idx_filtered(binding.idx, Some(binding.layer), Some(binding.trigger))
@@ -148,13 +161,16 @@ impl<const N: usize> BindingList<N> {
             // Overwrite this index.
             self.bindings[idx] = binding;
         } else {
-            assert!(self.added < N, "Too many bindings added");
+            if self.added == N {
+                return Err(BindError::Full);
+            }
             self.bindings[self.added] = binding;
             self.added += 1;
             // Sort by layer to return lowest layer on .filter() without defined
             // precise layer.
             self.bindings[0..self.added].sort_by_key(|b| (b.idx, b.layer));
         }
+        Ok(())
     }
 }
 
@@ -177,17 +193,17 @@ mod tests {
             Binding::long(2, 0, 2),
             Binding::long(3, 0, 3),
         ] {
-            blst.bind(binding);
+            blst.bind(binding).unwrap();
         }
         assert_eq!(blst.added, 9);
 
         // Overwrite some
-        blst.bind(Binding::short(3, 0, 4));
-        blst.bind(Binding::long(1, 0, 2));
+        blst.bind(Binding::short(3, 0, 4)).unwrap();
+        blst.bind(Binding::long(1, 0, 2)).unwrap();
 
         // Add a new one, and ovewrite it
-        blst.bind(Binding::short(3, 2, 5));
-        blst.bind(Binding::short(3, 2, 6));
+        blst.bind(Binding::short(3, 2, 5)).unwrap();
+        blst.bind(Binding::short(3, 2, 6)).unwrap();
 
         assert_eq!(blst.added, 10);
 
@@ -216,4 +232,15 @@ mod tests {
         blst.clear();
         assert_eq!(blst.added, 0);
     }
+
+    #[test]
+    fn it_rejects_invalid_bindings() {
+        let mut blst: BindingList<2> = BindingList::new();
+        assert_eq!(blst.bind(Binding::short(0, 0, 1)), Err(BindError::ReservedInput));
+        assert_eq!(blst.bind(Binding::short(1, 0, 1)), Ok(()));
+        assert_eq!(blst.bind(Binding::short(2, 0, 2)), Ok(()));
+        assert_eq!(blst.bind(Binding::short(3, 0, 3)), Err(BindError::Full));
+        // Overwriting doesn't need a free slot.
+        assert_eq!(blst.bind(Binding::short(2, 0, 4)), Ok(()));
+    }
 }
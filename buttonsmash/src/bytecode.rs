@@ -0,0 +1,768 @@
+/*
+ * Binary program format, used to store programs in flash and send them over
+ * the bus. All numbers are little-endian.
+ *
+ * Offset | Size | Field
+ * -------+------+----------------------------------------------
+ *      0 |    4 | Magic "BSVM"
+ *      4 |    1 | Format version
+ *      5 |    1 | Number of procedures (P)
+ *      6 |    2 | Number of opcodes
+ *      8 |    4 | Length of the opcode data in bytes (L)
+ *     12 |  3*P | Procedure table: procedure ID (1) + opcode index (2)
+ *        |    L | Opcodes: tag (1) + operands
+ *        |    1 | Number of subscriptions (S), since version 2
+ *        |  2*S | Subscriptions: device address (1) + output index (1)
+ *        |    4 | CRC32 (IEEE) of all preceding bytes
+ *
+ * Subscriptions list the remote devices allowed to set the local outputs.
+ * Version 1 programs have none.
+ */
+
+use core::fmt;
+
+use crate::consts::{DevAddr, OutIdx, OutputState, Trigger, MAX_DEVICES, MAX_OUTPUTS};
+use crate::opcodes::Opcode;
+use crate::threads::Retrigger;
+
+pub const MAGIC: [u8; 4] = *b"BSVM";
+/// Current format version.
+pub const VERSION: u8 = 2;
+const HEADER_LEN: usize = 12;
+const PROC_ENTRY_LEN: usize = 3;
+const SUBSCRIPTION_LEN: usize = 2;
+const CRC_LEN: usize = 4;
+
+/// Program can't be encoded.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EncodeError {
+    /// Too many opcodes, procedures or subscriptions for the format.
+    TooLong,
+}
+
+/// Binary program was rejected.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DecodeError {
+    /// Data ends before the end of the program.
+    Truncated,
+    /// Data doesn't start with the magic bytes.
+    BadMagic,
+    UnsupportedVersion(u8),
+    /// Data was corrupted.
+    BadChecksum,
+    /// Unknown opcode tag at the byte offset.
+    UnknownOpcode {
+        offset: usize,
+        tag: u8,
+    },
+    /// Operand value out of its domain (eg. unknown trigger).
+    InvalidOperand {
+        offset: usize,
+    },
+    /// Opcode data doesn't match lengths from the header.
+    LengthMismatch,
+    /// Procedure table doesn't match the Start opcodes.
+    ProcedureTableMismatch,
+    /// Program is longer than the output buffer.
+    TooLong {
+        length: usize,
+        capacity: usize,
+    },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "program is truncated"),
+            DecodeError::BadMagic => write!(f, "not a program"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version {}", version)
+            }
+            DecodeError::BadChecksum => write!(f, "checksum mismatch"),
+            DecodeError::UnknownOpcode { offset, tag } => {
+                write!(f, "unknown opcode 0x{:02x} at byte {}", tag, offset)
+            }
+            DecodeError::InvalidOperand { offset } => {
+                write!(f, "invalid operand at byte {}", offset)
+            }
+            DecodeError::LengthMismatch => write!(f, "opcode data doesn't match the header"),
+            DecodeError::ProcedureTableMismatch => write!(f, "invalid procedure table"),
+            DecodeError::TooLong { length, capacity } => {
+                write!(f, "program of {} opcodes exceeds {}", length, capacity)
+            }
+        }
+    }
+}
+
+/// CRC32 (IEEE 802.3) lookup table.
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut idx = 0;
+    while idx < 256 {
+        let mut crc = idx as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[idx] = crc;
+        idx += 1;
+    }
+    table
+};
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF;
+    for byte in data {
+        crc = CRC_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+fn trigger_code(trigger: Trigger) -> u8 {
+    match trigger {
+        Trigger::ShortClick => 0,
+        Trigger::LongClick => 1,
+        Trigger::Activated => 2,
+        Trigger::Deactivated => 3,
+        Trigger::LongActivated => 4,
+        Trigger::LongDeactivated => 5,
+    }
+}
+
+fn state_code(state: OutputState) -> u8 {
+    match state {
+        OutputState::Off => 0,
+        OutputState::On => 1,
+        OutputState::Toggle => 2,
+    }
+}
+
+fn retrigger_code(policy: Retrigger) -> u8 {
+    match policy {
+        Retrigger::Parallel => 0,
+        Retrigger::Restart => 1,
+        Retrigger::Ignore => 2,
+        Retrigger::Cancel => 3,
+    }
+}
+
+/// Append encoded opcode to the buffer.
+fn encode_opcode(opcode: &Opcode, out: &mut Vec<u8>) {
+    match *opcode {
+        Opcode::Noop => out.push(0x00),
+        Opcode::Start(proc) => out.extend([0x01, proc]),
+        Opcode::Stop => out.push(0x02),
+        Opcode::Call(proc) => out.extend([0x03, proc]),
+        Opcode::CallWith(proc, first, second) => out.extend([0x04, proc, first, second]),
+
+        Opcode::Toggle(out_idx) => out.extend([0x10, out_idx]),
+        Opcode::Activate(out_idx) => out.extend([0x11, out_idx]),
+        Opcode::Deactivate(out_idx) => out.extend([0x12, out_idx]),
+        Opcode::ActivateFor(out_idx, ms) => {
+            out.extend([0x13, out_idx]);
+            out.extend(ms.to_le_bytes());
+        }
+        Opcode::Wait(ms) => {
+            out.push(0x14);
+            out.extend(ms.to_le_bytes());
+        }
+        Opcode::WaitForRelease(ms) => {
+            out.push(0x15);
+            out.extend(ms.to_le_bytes());
+        }
+        Opcode::SetRemote(dev, out_idx, state) => {
+            out.extend([0x16, dev, out_idx, state_code(state)]);
+        }
+
+        Opcode::LayerPush(layer) => out.extend([0x20, layer]),
+        Opcode::LayerPop => out.push(0x21),
+        Opcode::LayerSet(layer) => out.extend([0x22, layer]),
+        Opcode::LayerDefault => out.push(0x23),
+        Opcode::ZoneAssign(in_idx, zone) => out.extend([0x24, in_idx, zone]),
+        Opcode::ZoneSelect(zone) => out.extend([0x25, zone]),
+
+        Opcode::BindClearAll => out.push(0x30),
+        Opcode::BindShortCall(in_idx, proc) => out.extend([0x31, in_idx, proc]),
+        Opcode::BindLongCall(in_idx, proc) => out.extend([0x32, in_idx, proc]),
+        Opcode::BindActivateCall(in_idx, proc) => out.extend([0x33, in_idx, proc]),
+        Opcode::BindDeactivateCall(in_idx, proc) => out.extend([0x34, in_idx, proc]),
+        Opcode::BindLongActivate(in_idx, proc) => out.extend([0x35, in_idx, proc]),
+        Opcode::BindLongDeactivate(in_idx, proc) => out.extend([0x36, in_idx, proc]),
+        Opcode::BindCallWith(in_idx, trigger, proc, first, second) => {
+            out.extend([0x37, in_idx, trigger_code(trigger), proc, first, second])
+        }
+        Opcode::BindShortToggle(in_idx, out_idx) => out.extend([0x38, in_idx, out_idx]),
+        Opcode::BindLongToggle(in_idx, out_idx) => out.extend([0x39, in_idx, out_idx]),
+        Opcode::BindLayerHold(in_idx, layer) => out.extend([0x3A, in_idx, layer]),
+
+        Opcode::Load(reg, value) => out.extend([0x40, reg, value]),
+        Opcode::ReadInput(reg, in_idx) => out.extend([0x41, reg, in_idx]),
+        Opcode::ReadOutput(reg, out_idx) => out.extend([0x42, reg, out_idx]),
+        Opcode::Eq(first, second) => out.extend([0x43, first, second]),
+        Opcode::Lt(first, second) => out.extend([0x44, first, second]),
+        Opcode::Gt(first, second) => out.extend([0x45, first, second]),
+        Opcode::And(first, second) => out.extend([0x46, first, second]),
+        Opcode::Or(first, second) => out.extend([0x47, first, second]),
+        Opcode::Not(reg) => out.extend([0x48, reg]),
+        Opcode::CallConditionally(reg, if_true, if_false) => {
+            out.extend([0x49, reg, if_true, if_false])
+        }
+
+        Opcode::Set(var, value) => {
+            out.extend([0x50, var]);
+            out.extend(value.to_le_bytes());
+        }
+        Opcode::Inc(var) => out.extend([0x51, var]),
+        Opcode::Dec(var) => out.extend([0x52, var]),
+        Opcode::Add(var, value) => {
+            out.extend([0x53, var]);
+            out.extend(value.to_le_bytes());
+        }
+        Opcode::Mod(var, value) => {
+            out.extend([0x54, var]);
+            out.extend(value.to_le_bytes());
+        }
+        Opcode::LoadVar(reg, var) => out.extend([0x55, reg, var]),
+        Opcode::StoreVar(var, reg) => out.extend([0x56, var, reg]),
+        Opcode::VarEq(reg, var, value) => {
+            out.extend([0x57, reg, var]);
+            out.extend(value.to_le_bytes());
+        }
+        Opcode::VarLt(reg, var, value) => {
+            out.extend([0x58, reg, var]);
+            out.extend(value.to_le_bytes());
+        }
+        Opcode::VarGt(reg, var, value) => {
+            out.extend([0x59, reg, var]);
+            out.extend(value.to_le_bytes());
+        }
+
+        Opcode::Jump(offset) => out.extend([0x60, offset as u8]),
+        Opcode::JumpTo(target) => {
+            out.push(0x61);
+            out.extend(target.to_le_bytes());
+        }
+        Opcode::JumpIfZero(reg, offset) => out.extend([0x62, reg, offset as u8]),
+        Opcode::JumpIfNotZero(reg, offset) => out.extend([0x63, reg, offset as u8]),
+        Opcode::Loop(reg, offset) => out.extend([0x64, reg, offset as u8]),
+
+        Opcode::SetRetrigger(proc, policy) => out.extend([0x70, proc, retrigger_code(policy)]),
+        Opcode::Cancel(proc) => out.extend([0x71, proc]),
+        Opcode::Syscall(id, arg) => out.extend([0x72, id, arg]),
+        Opcode::SetErrorHandler(proc) => out.extend([0x73, proc]),
+    }
+}
+
+/// Encode program with a header and a checksum.
+pub fn encode(program: &[Opcode]) -> Result<Vec<u8>, EncodeError> {
+    encode_with(program, &[])
+}
+
+/// Encode program with the remote devices allowed to set its outputs, as
+/// (device address, output) pairs.
+pub fn encode_with(
+    program: &[Opcode],
+    subscriptions: &[(DevAddr, OutIdx)],
+) -> Result<Vec<u8>, EncodeError> {
+    let length = u16::try_from(program.len()).map_err(|_| EncodeError::TooLong)?;
+    let mut procedures = Vec::new();
+    let mut code = Vec::new();
+    for (idx, opcode) in program.iter().enumerate() {
+        if let Opcode::Start(proc) = opcode {
+            procedures.push((*proc, idx as u16));
+        }
+        encode_opcode(opcode, &mut code);
+    }
+    let proc_count = u8::try_from(procedures.len()).map_err(|_| EncodeError::TooLong)?;
+    let code_len = u32::try_from(code.len()).map_err(|_| EncodeError::TooLong)?;
+    let sub_count = u8::try_from(subscriptions.len()).map_err(|_| EncodeError::TooLong)?;
+
+    let mut out = Vec::with_capacity(HEADER_LEN + procedures.len() * PROC_ENTRY_LEN + code.len());
+    out.extend(MAGIC);
+    out.push(VERSION);
+    out.push(proc_count);
+    out.extend(length.to_le_bytes());
+    out.extend(code_len.to_le_bytes());
+    for (proc, idx) in procedures {
+        out.push(proc);
+        out.extend(idx.to_le_bytes());
+    }
+    out.extend(code);
+    out.push(sub_count);
+    for (device, out_idx) in subscriptions {
+        out.extend([*device, *out_idx]);
+    }
+    out.extend(crc32(&out).to_le_bytes());
+    Ok(out)
+}
+
+/// Reads operands of the opcode data. Offsets are counted from the data start.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// Offset of the data within the whole program, for error reporting.
+    base: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], base: usize) -> Self {
+        Self { data, pos: 0, base }
+    }
+
+    fn offset(&self) -> usize {
+        self.base + self.pos
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + N)
+            .ok_or(DecodeError::LengthMismatch)?;
+        self.pos += N;
+        Ok(bytes.try_into().unwrap_or([0; N]))
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    fn i8(&mut self) -> Result<i8, DecodeError> {
+        Ok(self.u8()? as i8)
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_le_bytes(self.bytes()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+
+    fn trigger(&mut self) -> Result<Trigger, DecodeError> {
+        let offset = self.offset();
+        Ok(match self.u8()? {
+            0 => Trigger::ShortClick,
+            1 => Trigger::LongClick,
+            2 => Trigger::Activated,
+            3 => Trigger::Deactivated,
+            4 => Trigger::LongActivated,
+            5 => Trigger::LongDeactivated,
+            _ => return Err(DecodeError::InvalidOperand { offset }),
+        })
+    }
+
+    fn state(&mut self) -> Result<OutputState, DecodeError> {
+        let offset = self.offset();
+        Ok(match self.u8()? {
+            0 => OutputState::Off,
+            1 => OutputState::On,
+            2 => OutputState::Toggle,
+            _ => return Err(DecodeError::InvalidOperand { offset }),
+        })
+    }
+
+    fn retrigger(&mut self) -> Result<Retrigger, DecodeError> {
+        let offset = self.offset();
+        Ok(match self.u8()? {
+            0 => Retrigger::Parallel,
+            1 => Retrigger::Restart,
+            2 => Retrigger::Ignore,
+            3 => Retrigger::Cancel,
+            _ => return Err(DecodeError::InvalidOperand { offset }),
+        })
+    }
+
+    fn opcode(&mut self) -> Result<Opcode, DecodeError> {
+        let offset = self.offset();
+        let r = self;
+        Ok(match r.u8()? {
+            0x00 => Opcode::Noop,
+            0x01 => Opcode::Start(r.u8()?),
+            0x02 => Opcode::Stop,
+            0x03 => Opcode::Call(r.u8()?),
+            0x04 => Opcode::CallWith(r.u8()?, r.u8()?, r.u8()?),
+
+            0x10 => Opcode::Toggle(r.u8()?),
+            0x11 => Opcode::Activate(r.u8()?),
+            0x12 => Opcode::Deactivate(r.u8()?),
+            0x13 => Opcode::ActivateFor(r.u8()?, r.u32()?),
+            0x14 => Opcode::Wait(r.u32()?),
+            0x15 => Opcode::WaitForRelease(r.u32()?),
+            0x16 => Opcode::SetRemote(r.u8()?, r.u8()?, r.state()?),
+
+            0x20 => Opcode::LayerPush(r.u8()?),
+            0x21 => Opcode::LayerPop,
+            0x22 => Opcode::LayerSet(r.u8()?),
+            0x23 => Opcode::LayerDefault,
+            0x24 => Opcode::ZoneAssign(r.u8()?, r.u8()?),
+            0x25 => Opcode::ZoneSelect(r.u8()?),
+
+            0x30 => Opcode::BindClearAll,
+            0x31 => Opcode::BindShortCall(r.u8()?, r.u8()?),
+            0x32 => Opcode::BindLongCall(r.u8()?, r.u8()?),
+            0x33 => Opcode::BindActivateCall(r.u8()?, r.u8()?),
+            0x34 => Opcode::BindDeactivateCall(r.u8()?, r.u8()?),
+            0x35 => Opcode::BindLongActivate(r.u8()?, r.u8()?),
+            0x36 => Opcode::BindLongDeactivate(r.u8()?, r.u8()?),
+            0x37 => Opcode::BindCallWith(r.u8()?, r.trigger()?, r.u8()?, r.u8()?, r.u8()?),
+            0x38 => Opcode::BindShortToggle(r.u8()?, r.u8()?),
+            0x39 => Opcode::BindLongToggle(r.u8()?, r.u8()?),
+            0x3A => Opcode::BindLayerHold(r.u8()?, r.u8()?),
+
+            0x40 => Opcode::Load(r.u8()?, r.u8()?),
+            0x41 => Opcode::ReadInput(r.u8()?, r.u8()?),
+            0x42 => Opcode::ReadOutput(r.u8()?, r.u8()?),
+            0x43 => Opcode::Eq(r.u8()?, r.u8()?),
+            0x44 => Opcode::Lt(r.u8()?, r.u8()?),
+            0x45 => Opcode::Gt(r.u8()?, r.u8()?),
+            0x46 => Opcode::And(r.u8()?, r.u8()?),
+            0x47 => Opcode::Or(r.u8()?, r.u8()?),
+            0x48 => Opcode::Not(r.u8()?),
+            0x49 => Opcode::CallConditionally(r.u8()?, r.u8()?, r.u8()?),
+
+            0x50 => Opcode::Set(r.u8()?, r.u16()?),
+            0x51 => Opcode::Inc(r.u8()?),
+            0x52 => Opcode::Dec(r.u8()?),
+            0x53 => Opcode::Add(r.u8()?, r.u16()?),
+            0x54 => Opcode::Mod(r.u8()?, r.u16()?),
+            0x55 => Opcode::LoadVar(r.u8()?, r.u8()?),
+            0x56 => Opcode::StoreVar(r.u8()?, r.u8()?),
+            0x57 => Opcode::VarEq(r.u8()?, r.u8()?, r.u16()?),
+            0x58 => Opcode::VarLt(r.u8()?, r.u8()?, r.u16()?),
+            0x59 => Opcode::VarGt(r.u8()?, r.u8()?, r.u16()?),
+
+            0x60 => Opcode::Jump(r.i8()?),
+            0x61 => Opcode::JumpTo(r.u16()?),
+            0x62 => Opcode::JumpIfZero(r.u8()?, r.i8()?),
+            0x63 => Opcode::JumpIfNotZero(r.u8()?, r.i8()?),
+            0x64 => Opcode::Loop(r.u8()?, r.i8()?),
+
+            0x70 => Opcode::SetRetrigger(r.u8()?, r.retrigger()?),
+            0x71 => Opcode::Cancel(r.u8()?),
+            0x72 => Opcode::Syscall(r.u8()?, r.u8()?),
+            0x73 => Opcode::SetErrorHandler(r.u8()?),
+
+            tag => return Err(DecodeError::UnknownOpcode { offset, tag }),
+        })
+    }
+}
+
+/// Positions of the program parts, from a header with a valid checksum.
+struct Layout {
+    proc_count: usize,
+    length: usize,
+    code_start: usize,
+    code_end: usize,
+    /// Subscription entries, empty before version 2.
+    subscriptions: core::ops::Range<usize>,
+}
+
+fn layout(bytes: &[u8]) -> Result<Layout, DecodeError> {
+    if bytes.len() < HEADER_LEN + CRC_LEN {
+        return Err(DecodeError::Truncated);
+    }
+    if bytes[0..4] != MAGIC {
+        return Err(DecodeError::BadMagic);
+    }
+    // Version decides where the checksum is.
+    if !(1..=VERSION).contains(&bytes[4]) {
+        return Err(DecodeError::UnsupportedVersion(bytes[4]));
+    }
+    let proc_count = bytes[5] as usize;
+    let length = u16::from_le_bytes([bytes[6], bytes[7]]) as usize;
+    let code_len = u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize;
+    let code_start = HEADER_LEN + proc_count * PROC_ENTRY_LEN;
+    let code_end = code_start
+        .checked_add(code_len)
+        .ok_or(DecodeError::Truncated)?;
+    let subscriptions = match bytes[4] {
+        1 => code_end..code_end,
+        _ => {
+            let count = *bytes.get(code_end).ok_or(DecodeError::Truncated)? as usize;
+            code_end + 1..code_end + 1 + count * SUBSCRIPTION_LEN
+        }
+    };
+    let crc_start = subscriptions.end;
+    if bytes.len() < crc_start + CRC_LEN {
+        return Err(DecodeError::Truncated);
+    }
+    let stored_crc = u32::from_le_bytes([
+        bytes[crc_start],
+        bytes[crc_start + 1],
+        bytes[crc_start + 2],
+        bytes[crc_start + 3],
+    ]);
+    if crc32(&bytes[..crc_start]) != stored_crc {
+        return Err(DecodeError::BadChecksum);
+    }
+    Ok(Layout {
+        proc_count,
+        length,
+        code_start,
+        code_end,
+        subscriptions,
+    })
+}
+
+/// Decode program into the buffer and return its length. Doesn't allocate.
+pub fn decode_into(bytes: &[u8], program: &mut [Opcode]) -> Result<usize, DecodeError> {
+    let Layout {
+        proc_count,
+        length,
+        code_start,
+        code_end,
+        ..
+    } = layout(bytes)?;
+    let code_len = code_end - code_start;
+    if length > program.len() {
+        return Err(DecodeError::TooLong {
+            length,
+            capacity: program.len(),
+        });
+    }
+
+    let mut reader = Reader::new(&bytes[code_start..code_end], code_start);
+    for slot in program.iter_mut().take(length) {
+        *slot = reader.opcode()?;
+    }
+    if reader.pos != code_len {
+        return Err(DecodeError::LengthMismatch);
+    }
+
+    // Every procedure is in the table exactly once.
+    let starts = program[..length]
+        .iter()
+        .filter(|opcode| matches!(opcode, Opcode::Start(_)))
+        .count();
+    if starts != proc_count {
+        return Err(DecodeError::ProcedureTableMismatch);
+    }
+    for entry in bytes[HEADER_LEN..code_start].chunks_exact(PROC_ENTRY_LEN) {
+        let idx = u16::from_le_bytes([entry[1], entry[2]]) as usize;
+        if program[..length].get(idx) != Some(&Opcode::Start(entry[0])) {
+            return Err(DecodeError::ProcedureTableMismatch);
+        }
+    }
+    Ok(length)
+}
+
+/// Remote devices allowed to set the outputs, as (device address, output)
+/// pairs. Doesn't allocate.
+pub fn subscriptions(
+    bytes: &[u8],
+) -> Result<impl Iterator<Item = (DevAddr, OutIdx)> + '_, DecodeError> {
+    let range = layout(bytes)?.subscriptions;
+    let entries = bytes[range.clone()].chunks_exact(SUBSCRIPTION_LEN);
+    for (idx, entry) in entries.clone().enumerate() {
+        if entry[0] as usize >= MAX_DEVICES || entry[1] as usize >= MAX_OUTPUTS {
+            let offset = range.start + idx * SUBSCRIPTION_LEN;
+            return Err(DecodeError::InvalidOperand { offset });
+        }
+    }
+    Ok(entries.map(|entry| (entry[0], entry[1])))
+}
+
+/// Decode program.
+pub fn decode(bytes: &[u8]) -> Result<Vec<Opcode>, DecodeError> {
+    let length = match bytes.get(6..8) {
+        Some(length) => u16::from_le_bytes([length[0], length[1]]) as usize,
+        None => return Err(DecodeError::Truncated),
+    };
+    let mut program = vec![Opcode::Noop; length];
+    let length = decode_into(bytes, &mut program)?;
+    program.truncate(length);
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts::{ARG0, ARG1};
+
+    /// Every opcode variant, so the roundtrip covers the whole format.
+    fn all_opcodes() -> Vec<Opcode> {
+        vec![
+            Opcode::Start(0),
+            Opcode::Noop,
+            Opcode::Call(1),
+            Opcode::CallWith(1, ARG0, ARG1),
+            Opcode::Toggle(1),
+            Opcode::Activate(2),
+            Opcode::Deactivate(3),
+            Opcode::ActivateFor(4, 70_000),
+            Opcode::SetRemote(5, 6, OutputState::Toggle),
+            Opcode::Wait(1000),
+            Opcode::WaitForRelease(5000),
+            Opcode::LayerPush(1),
+            Opcode::LayerPop,
+            Opcode::LayerSet(2),
+            Opcode::LayerDefault,
+            Opcode::ZoneAssign(5, 1),
+            Opcode::ZoneSelect(1),
+            Opcode::BindClearAll,
+            Opcode::BindShortCall(1, 1),
+            Opcode::BindLongCall(1, 1),
+            Opcode::BindActivateCall(1, 1),
+            Opcode::BindDeactivateCall(1, 1),
+            Opcode::BindLongActivate(1, 1),
+            Opcode::BindLongDeactivate(1, 1),
+            Opcode::BindCallWith(2, Trigger::LongDeactivated, 1, 4, 5),
+            Opcode::BindShortToggle(3, 4),
+            Opcode::BindLongToggle(3, 5),
+            Opcode::BindLayerHold(6, 1),
+            Opcode::Load(0, 200),
+            Opcode::ReadInput(1, 3),
+            Opcode::ReadOutput(2, 4),
+            Opcode::Eq(0, 1),
+            Opcode::Lt(0, 1),
+            Opcode::Gt(0, 1),
+            Opcode::And(0, 1),
+            Opcode::Or(0, 1),
+            Opcode::Not(0),
+            Opcode::CallConditionally(0, 1, 1),
+            Opcode::Set(0, 0xBEEF),
+            Opcode::Inc(1),
+            Opcode::Dec(1),
+            Opcode::Add(1, 300),
+            Opcode::Mod(1, 3),
+            Opcode::LoadVar(0, 1),
+            Opcode::StoreVar(1, 0),
+            Opcode::VarEq(0, 1, 2),
+            Opcode::VarLt(0, 1, 2),
+            Opcode::VarGt(0, 1, 2),
+            Opcode::Jump(1),
+            Opcode::JumpTo(0x0102),
+            Opcode::JumpIfZero(0, -2),
+            Opcode::JumpIfNotZero(0, 3),
+            Opcode::Loop(0, -4),
+            Opcode::SetRetrigger(1, Retrigger::Cancel),
+            Opcode::Cancel(1),
+            Opcode::Syscall(2, 3),
+            Opcode::SetErrorHandler(1),
+            Opcode::Stop,
+            Opcode::Start(1),
+            Opcode::Stop,
+        ]
+    }
+
+    #[test]
+    fn it_computes_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn it_roundtrips_programs() {
+        let program = all_opcodes();
+        let bytes = encode(&program).unwrap();
+        assert_eq!(bytes[0..4], MAGIC);
+        assert_eq!(bytes[4], VERSION);
+        assert_eq!(bytes[5], 2);
+        assert_eq!(decode(&bytes), Ok(program.clone()));
+
+        let mut small = [Opcode::Noop; 8];
+        assert_eq!(
+            decode_into(&bytes, &mut small),
+            Err(DecodeError::TooLong {
+                length: program.len(),
+                capacity: 8
+            })
+        );
+    }
+
+    #[test]
+    fn it_stores_subscriptions() {
+        let program = [Opcode::Start(0), Opcode::Stop];
+        let bytes = encode_with(&program, &[(3, 1), (5, 2)]).unwrap();
+        assert_eq!(decode(&bytes), Ok(program.to_vec()));
+        let stored: Vec<_> = subscriptions(&bytes).unwrap().collect();
+        assert_eq!(stored, [(3, 1), (5, 2)]);
+
+        // Version 1 has no subscription section.
+        let mut old = encode(&program).unwrap();
+        old.remove(old.len() - CRC_LEN - 1);
+        old[4] = 1;
+        let end = old.len() - CRC_LEN;
+        let crc = crc32(&old[..end]);
+        old[end..].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(decode(&old), Ok(program.to_vec()));
+        assert_eq!(subscriptions(&old).unwrap().count(), 0);
+
+        let bytes = encode_with(&program, &[(3, 1), (MAX_DEVICES as u8, 2)]).unwrap();
+        let offset = bytes.len() - CRC_LEN - SUBSCRIPTION_LEN;
+        assert_eq!(
+            subscriptions(&bytes).err(),
+            Some(DecodeError::InvalidOperand { offset })
+        );
+        let mut corrupted = bytes.clone();
+        corrupted[offset - 1] ^= 0x01;
+        assert_eq!(decode(&corrupted), Err(DecodeError::BadChecksum));
+    }
+
+    #[test]
+    fn it_rejects_corrupted_programs() {
+        let program = [Opcode::Start(0), Opcode::Toggle(1), Opcode::Stop];
+        let bytes = encode(&program).unwrap();
+        let code_start = HEADER_LEN + PROC_ENTRY_LEN;
+
+        assert_eq!(decode(&bytes[..10]), Err(DecodeError::Truncated));
+        assert_eq!(
+            decode(&bytes[..bytes.len() - 1]),
+            Err(DecodeError::Truncated)
+        );
+
+        let mut corrupted = bytes.clone();
+        corrupted[0] = b'X';
+        assert_eq!(decode(&corrupted), Err(DecodeError::BadMagic));
+
+        let mut corrupted = bytes.clone();
+        corrupted[code_start + 3] ^= 0x01;
+        assert_eq!(decode(&corrupted), Err(DecodeError::BadChecksum));
+
+        // Valid checksum, but unknown content.
+        let reseal = |mut bytes: Vec<u8>| {
+            let end = bytes.len() - CRC_LEN;
+            let crc = crc32(&bytes[..end]);
+            bytes[end..].copy_from_slice(&crc.to_le_bytes());
+            bytes
+        };
+        let mut corrupted = bytes.clone();
+        corrupted[4] = VERSION + 1;
+        assert_eq!(
+            decode(&reseal(corrupted)),
+            Err(DecodeError::UnsupportedVersion(VERSION + 1))
+        );
+
+        let mut corrupted = bytes.clone();
+        corrupted[code_start + 2] = 0xEE;
+        assert_eq!(
+            decode(&reseal(corrupted)),
+            Err(DecodeError::UnknownOpcode {
+                offset: code_start + 2,
+                tag: 0xEE
+            })
+        );
+
+        let mut corrupted = bytes.clone();
+        corrupted[HEADER_LEN + 1] = 1;
+        assert_eq!(
+            decode(&reseal(corrupted)),
+            Err(DecodeError::ProcedureTableMismatch)
+        );
+
+        let bound = encode(&[Opcode::BindCallWith(1, Trigger::Activated, 0, 0, 0)]).unwrap();
+        let mut corrupted = bound.clone();
+        corrupted[HEADER_LEN + 2] = 9;
+        assert_eq!(
+            decode(&reseal(corrupted)),
+            Err(DecodeError::InvalidOperand {
+                offset: HEADER_LEN + 2
+            })
+        );
+    }
+}
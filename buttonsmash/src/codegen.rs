@@ -0,0 +1,267 @@
+/*
+ * Generates Rust source with the compiled programs, so the firmware can ship
+ * a verified default program in flash. Each device gets a module with a
+ * `PROGRAM` constant and the remote outputs it accepts, for
+ * `Executor::set_subscriptions`:
+ *
+ *   pub mod fusebox {
+ *       pub const PROGRAM: [buttonsmash::opcodes::Opcode; 5] = [...];
+ *       pub const SUBSCRIPTIONS: [(DevAddr, OutIdx); 1] = [(1, 4)];
+ *   }
+ *
+ * Meant for `build.rs`:
+ *
+ *   let out = Path::new(&env::var("OUT_DIR").unwrap()).join("programs.rs");
+ *   codegen::generate_file("code.yaml", &out).unwrap_or_else(|err| panic!("{}", err));
+ *   println!("cargo:rerun-if-changed=code.yaml");
+ *
+ * and `include!(concat!(env!("OUT_DIR"), "/programs.rs"));` in the firmware.
+ */
+
+use std::collections::BTreeSet;
+use std::fmt::{self, Write};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::compiler;
+use crate::config::{Config, ConfigError, ConfigErrorKind, Device};
+use crate::opcodes::Opcode;
+
+/// Failure of `generate_file`.
+#[derive(Debug)]
+pub enum CodegenError {
+    Io(PathBuf, io::Error),
+    Config(PathBuf, ConfigError),
+}
+
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodegenError::Io(path, err) => write!(f, "{}: {}", path.display(), err),
+            CodegenError::Config(path, err) => write!(f, "{}:{}", path.display(), err),
+        }
+    }
+}
+
+/// Rust expression of the opcode. Enum arguments need their type paths.
+fn expression(opcode: &Opcode) -> String {
+    match opcode {
+        Opcode::SetRemote(dev, out, state) => format!(
+            "Opcode::SetRemote({}, {}, buttonsmash::consts::OutputState::{:?})",
+            dev, out, state
+        ),
+        Opcode::BindCallWith(in_idx, trigger, proc_idx, arg0, arg1) => format!(
+            "Opcode::BindCallWith({}, buttonsmash::consts::Trigger::{:?}, {}, {}, {})",
+            in_idx, trigger, proc_idx, arg0, arg1
+        ),
+        Opcode::SetRetrigger(proc_idx, retrigger) => format!(
+            "Opcode::SetRetrigger({}, buttonsmash::threads::Retrigger::{:?})",
+            proc_idx, retrigger
+        ),
+        opcode => format!("Opcode::{:?}", opcode),
+    }
+}
+
+fn name_error(device: &Device, expected: &'static str) -> ConfigError {
+    let mark = device.name.as_ref().map(|name| name.mark);
+    ConfigError::new(
+        mark.unwrap_or_default(),
+        ConfigErrorKind::Expected(expected),
+    )
+}
+
+/// Rust keywords, strict and reserved in any edition. Written as raw
+/// identifiers, eg. `r#type`.
+const KEYWORDS: [&str; 50] = [
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "crate",
+    "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if", "impl",
+    "in", "let", "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref",
+    "return", "self", "static", "struct", "super", "trait", "true", "try", "type", "typeof",
+    "unsafe", "unsized", "use", "virtual", "where", "while",
+];
+
+/// Module name of the device, eg. `first-floor` -> `first_floor`.
+fn module_name(device: &Device) -> Result<String, ConfigError> {
+    let name: String = device
+        .name()
+        .chars()
+        .map(|c| match c {
+            '-' | ' ' | '.' => '_',
+            c => c.to_ascii_lowercase(),
+        })
+        .collect();
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && name != "_"
+        // Path keywords can't be raw identifiers.
+        && !matches!(name.as_str(), "self" | "super" | "crate");
+    match valid {
+        true if KEYWORDS.contains(&name.as_str()) => Ok(format!("r#{}", name)),
+        true => Ok(name),
+        false => Err(name_error(
+            device,
+            "a device name usable as a Rust module name",
+        )),
+    }
+}
+
+/// Rust source with `PROGRAM` and `SUBSCRIPTIONS` constants for each device
+/// of the configuration.
+pub fn generate(config: &Config) -> Result<String, ConfigError> {
+    let programs = compiler::compile(config)?;
+    let mut modules = BTreeSet::new();
+    let mut source =
+        String::from("// Generated by buttonsmash from the device configuration, do not edit.\n");
+    for (device, program) in config.devices.iter().zip(&programs) {
+        let module = module_name(device)?;
+        if !modules.insert(module.clone()) {
+            return Err(name_error(
+                device,
+                "a device name distinct from the others as a module name",
+            ));
+        }
+        let _ = writeln!(source, "\npub mod {} {{", module);
+        let _ = writeln!(source, "    use buttonsmash::consts::{{DevAddr, OutIdx}};");
+        let _ = writeln!(source, "    use buttonsmash::opcodes::Opcode;\n");
+        let _ = writeln!(
+            source,
+            "    pub const PROGRAM: [Opcode; {}] = [",
+            program.opcodes.len()
+        );
+        for opcode in &program.opcodes {
+            let _ = writeln!(source, "        {},", expression(opcode));
+        }
+        source += "    ];\n";
+        let subscriptions: Vec<_> = program
+            .subscriptions
+            .iter()
+            .map(|sub| format!("({}, {})", sub.address, sub.output))
+            .collect();
+        let _ = writeln!(
+            source,
+            "    pub const SUBSCRIPTIONS: [(DevAddr, OutIdx); {}] = [{}];",
+            subscriptions.len(),
+            subscriptions.join(", ")
+        );
+        source += "}\n";
+    }
+    Ok(source)
+}
+
+/// Compile a configuration file into a Rust source file, see `generate`.
+/// The output is only written when it changed, to not trigger rebuilds.
+pub fn generate_file(config: impl AsRef<Path>, out: impl AsRef<Path>) -> Result<(), CodegenError> {
+    let (config, out) = (config.as_ref(), out.as_ref());
+    let source =
+        fs::read_to_string(config).map_err(|err| CodegenError::Io(config.to_path_buf(), err))?;
+    let generated = Config::parse(&source)
+        .and_then(|parsed| generate(&parsed))
+        .map_err(|err| CodegenError::Config(config.to_path_buf(), err))?;
+    if fs::read_to_string(out).is_ok_and(|current| current == generated) {
+        return Ok(());
+    }
+    fs::write(out, generated).map_err(|err| CodegenError::Io(out.to_path_buf(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts::{OutputState, Trigger};
+    use crate::threads::Retrigger;
+
+    #[test]
+    fn it_generates_programs() {
+        let config = Config::parse(
+            "
+hall:
+  address: 1
+  inputs:
+    - door: 1
+  outputs:
+    - lamp: 2
+  bindings:
+    - input: door
+      short: {toggle: lamp}
+first-floor:
+  address: 2
+  inputs:
+    - switch: 1
+  bindings:
+    - input: switch
+      short: {toggle: hall.lamp}
+",
+        )
+        .unwrap();
+        let source = generate(&config).unwrap();
+        assert_eq!(
+            source,
+            "// Generated by buttonsmash from the device configuration, do not edit.
+
+pub mod hall {
+    use buttonsmash::consts::{DevAddr, OutIdx};
+    use buttonsmash::opcodes::Opcode;
+
+    pub const PROGRAM: [Opcode; 3] = [
+        Opcode::Start(0),
+        Opcode::BindShortToggle(1, 2),
+        Opcode::Stop,
+    ];
+    pub const SUBSCRIPTIONS: [(DevAddr, OutIdx); 1] = [(2, 2)];
+}
+
+pub mod first_floor {
+    use buttonsmash::consts::{DevAddr, OutIdx};
+    use buttonsmash::opcodes::Opcode;
+
+    pub const PROGRAM: [Opcode; 6] = [
+        Opcode::Start(0),
+        Opcode::BindShortCall(1, 1),
+        Opcode::Stop,
+        Opcode::Start(1),
+        Opcode::SetRemote(1, 2, buttonsmash::consts::OutputState::Toggle),
+        Opcode::Stop,
+    ];
+    pub const SUBSCRIPTIONS: [(DevAddr, OutIdx); 0] = [];
+}
+"
+        );
+
+        let config = Config::parse("hall:\n  inputs: []\nHall:\n  inputs: []\n").unwrap();
+        let err = generate(&config).unwrap_err();
+        assert_eq!(err.mark.line, 3);
+    }
+
+    #[test]
+    fn it_escapes_keywords() {
+        let config = Config::parse("type:\n  inputs: []\nLoop:\n  inputs: []\n").unwrap();
+        let modules: Vec<_> = config
+            .devices
+            .iter()
+            .map(|device| module_name(device).unwrap())
+            .collect();
+        assert_eq!(modules, ["r#type", "r#loop"]);
+        assert!(generate(&config).unwrap().contains("\npub mod r#loop {\n"));
+
+        let config = Config::parse("Self:\n  inputs: []\n").unwrap();
+        let err = module_name(&config.devices[0]).unwrap_err();
+        assert_eq!(err.mark.line, 1);
+    }
+
+    #[test]
+    fn it_qualifies_enum_arguments() {
+        assert_eq!(
+            expression(&Opcode::SetRemote(3, 4, OutputState::Toggle)),
+            "Opcode::SetRemote(3, 4, buttonsmash::consts::OutputState::Toggle)"
+        );
+        assert_eq!(
+            expression(&Opcode::BindCallWith(1, Trigger::LongClick, 2, 0, 5)),
+            "Opcode::BindCallWith(1, buttonsmash::consts::Trigger::LongClick, 2, 0, 5)"
+        );
+        assert_eq!(
+            expression(&Opcode::SetRetrigger(2, Retrigger::Parallel)),
+            "Opcode::SetRetrigger(2, buttonsmash::threads::Retrigger::Parallel)"
+        );
+        assert_eq!(expression(&Opcode::Jump(-3)), "Opcode::Jump(-3)");
+    }
+}
@@ -0,0 +1,868 @@
+/*
+ * Compiles device configuration into VM programs, one per device.
+ *
+ * Procedure 0 is generated: it assigns zones and maps the bindings. A
+ * procedure defined with `id: 0` is appended to it. Named procedures get the
+ * lowest free IDs and bindings with more than a single simple action get a
+ * generated procedure.
+ *
+ * Outputs of other devices are named `device.output`. They compile into remote
+ * commands sent to the device `address` and the receiving device gets a
+ * subscription that allows the sender to set this output. Both devices need an
+ * address. Subscriptions are stored with the bytecode and checked by the
+ * executor.
+ */
+
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+use crate::config::{
+    ActionDef, ActionKind, BindingDef, Config, ConfigError, ConfigErrorKind, Device, Io, Mark,
+    Name, RawOpcode, Step, TriggerDef,
+};
+use crate::consts::{
+    DevAddr, InIdx, LayerIdx, OutIdx, OutputState, ProcIdx, Trigger, MAX_INPUTS, MAX_LAYERS,
+    MAX_OUTPUTS, MAX_PROCEDURES, MAX_ZONES,
+};
+use crate::opcodes::Opcode;
+use crate::threads::Retrigger;
+use crate::verifier;
+
+/// Program compiled for a single device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DeviceProgram {
+    pub device: String,
+    pub opcodes: Vec<Opcode>,
+    /// IDs of the named procedures.
+    pub procedures: Vec<(String, ProcIdx)>,
+    /// Local outputs set by other devices.
+    pub subscriptions: Vec<Subscription>,
+}
+
+/// Remote device allowed to set a local output.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Subscription {
+    pub device: String,
+    pub address: DevAddr,
+    pub output: OutIdx,
+}
+
+/// Output of this or another device.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Target {
+    Local(OutIdx),
+    Remote(DevAddr, OutIdx),
+}
+
+impl DeviceProgram {
+    /// ID of a named procedure.
+    pub fn procedure(&self, name: &str) -> Option<ProcIdx> {
+        self.procedures
+            .iter()
+            .find(|(known, _)| known == name)
+            .map(|(_, id)| *id)
+    }
+}
+
+/// Outputs of other devices by the device name.
+type RemoteOutputs<'a> = Vec<(&'a str, OutIdx)>;
+
+/// Opcodes with the position of the configuration they were compiled from.
+type Body = Vec<(Opcode, Mark)>;
+
+struct Compiler<'a> {
+    device: &'a Device,
+    config: Option<&'a Config>,
+    inputs: HashMap<&'a str, &'a Io>,
+    outputs: HashMap<&'a str, &'a Io>,
+    procedures: HashMap<&'a str, ProcIdx>,
+    /// Procedure bodies by their IDs.
+    bodies: BTreeMap<ProcIdx, Body>,
+    remote: RemoteOutputs<'a>,
+}
+
+fn error(mark: Mark, kind: ConfigErrorKind) -> ConfigError {
+    ConfigError::new(mark, kind)
+}
+
+impl<'a> Compiler<'a> {
+    fn new(device: &'a Device, config: Option<&'a Config>) -> Self {
+        let names = |ios: &'a [Io]| ios.iter().map(|io| (io.name.value.as_str(), io)).collect();
+        Self {
+            device,
+            config,
+            inputs: names(&device.inputs),
+            outputs: names(&device.outputs),
+            procedures: HashMap::new(),
+            bodies: BTreeMap::new(),
+            remote: Vec::new(),
+        }
+    }
+
+    fn input(&self, name: &Name) -> Result<InIdx, ConfigError> {
+        let io = self
+            .inputs
+            .get(name.value.as_str())
+            .ok_or_else(|| error(name.mark, ConfigErrorKind::UnknownInput(name.value.clone())))?;
+        input_idx(io)
+    }
+
+    fn output(&self, name: &Name) -> Result<OutIdx, ConfigError> {
+        let io = self.outputs.get(name.value.as_str()).ok_or_else(|| {
+            error(
+                name.mark,
+                ConfigErrorKind::UnknownOutput(name.value.clone()),
+            )
+        })?;
+        output_idx(io)
+    }
+
+    /// Local output or `device.output` of another device.
+    fn target(&mut self, name: &Name) -> Result<Target, ConfigError> {
+        let Some((device, output)) = name
+            .remote()
+            .filter(|_| !self.outputs.contains_key(name.value.as_str()))
+        else {
+            return self.output(name).map(Target::Local);
+        };
+        let remote = self
+            .config
+            .and_then(|config| config.device(device))
+            .ok_or_else(|| {
+                error(
+                    name.mark,
+                    ConfigErrorKind::UnknownDevice(device.to_string()),
+                )
+            })?;
+        let (address, _) = remote.address.ok_or_else(|| {
+            error(
+                name.mark,
+                ConfigErrorKind::MissingAddress(device.to_string()),
+            )
+        })?;
+        let io = remote
+            .outputs
+            .iter()
+            .find(|io| io.name.value == output)
+            .ok_or_else(|| {
+                error(
+                    name.mark,
+                    ConfigErrorKind::UnknownOutput(name.value.clone()),
+                )
+            })?;
+        let out_idx = output_idx(io)?;
+        // Receiver accepts the command only from a known address.
+        if self.device.address.is_none() {
+            let kind = ConfigErrorKind::MissingAddress(self.device.name().to_string());
+            return Err(error(name.mark, kind));
+        }
+        self.remote.push((remote.name(), out_idx));
+        Ok(Target::Remote(address, out_idx))
+    }
+
+    fn procedure(&self, name: &Name) -> Result<ProcIdx, ConfigError> {
+        self.procedures
+            .get(name.value.as_str())
+            .copied()
+            .ok_or_else(|| {
+                error(
+                    name.mark,
+                    ConfigErrorKind::UnknownProcedure(name.value.clone()),
+                )
+            })
+    }
+
+    /// Reserve the lowest free procedure ID.
+    fn allocate(&mut self, mark: Mark) -> Result<ProcIdx, ConfigError> {
+        let id = (1..MAX_PROCEDURES as ProcIdx)
+            .find(|id| !self.bodies.contains_key(id))
+            .ok_or_else(|| error(mark, ConfigErrorKind::TooManyProcedures))?;
+        self.bodies.insert(id, Vec::new());
+        Ok(id)
+    }
+
+    /// Set a local output with the opcode or a remote one to the state.
+    fn set(
+        &mut self,
+        name: &Name,
+        local: fn(OutIdx) -> Opcode,
+        state: OutputState,
+    ) -> Result<Opcode, ConfigError> {
+        Ok(match self.target(name)? {
+            Target::Local(out_idx) => local(out_idx),
+            Target::Remote(dev, out_idx) => Opcode::SetRemote(dev, out_idx, state),
+        })
+    }
+
+    fn action(&mut self, action: &ActionDef, body: &mut Body) -> Result<(), ConfigError> {
+        for target in &action.targets {
+            let opcode = match action.kind {
+                ActionKind::Toggle => self.set(target, Opcode::Toggle, OutputState::Toggle)?,
+                ActionKind::Activate => self.set(target, Opcode::Activate, OutputState::On)?,
+                ActionKind::Deactivate => self.set(target, Opcode::Deactivate, OutputState::Off)?,
+                ActionKind::Call => Opcode::Call(self.procedure(target)?),
+            };
+            body.push((opcode, target.mark));
+        }
+        Ok(())
+    }
+
+    fn steps(&mut self, steps: &[Step]) -> Result<Body, ConfigError> {
+        let mut body = Vec::new();
+        for step in steps {
+            match step {
+                Step::Action(action) => self.action(action, &mut body)?,
+                Step::Opcode(raw) => body.push((raw_opcode(raw)?, raw.name.mark)),
+            }
+        }
+        Ok(body)
+    }
+
+    /// Binding opcode for actions executed on a trigger.
+    fn bind(&mut self, in_idx: InIdx, def: &TriggerDef) -> Result<Opcode, ConfigError> {
+        if let [action] = def.actions.as_slice() {
+            if let [target] = action.targets.as_slice() {
+                // Remote toggles need a generated procedure.
+                match (action.kind, def.trigger) {
+                    (ActionKind::Toggle, Trigger::ShortClick) => {
+                        if let Target::Local(out_idx) = self.target(target)? {
+                            return Ok(Opcode::BindShortToggle(in_idx, out_idx));
+                        }
+                    }
+                    (ActionKind::Toggle, Trigger::LongClick) => {
+                        if let Target::Local(out_idx) = self.target(target)? {
+                            return Ok(Opcode::BindLongToggle(in_idx, out_idx));
+                        }
+                    }
+                    (ActionKind::Call, trigger) => {
+                        return Ok(bind_call(in_idx, trigger, self.procedure(target)?));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let mut body = Vec::new();
+        for action in &def.actions {
+            self.action(action, &mut body)?;
+        }
+        let id = self.allocate(def.mark)?;
+        self.bodies.insert(id, body);
+        Ok(bind_call(in_idx, def.trigger, id))
+    }
+
+    fn binding(&mut self, binding: &BindingDef, setup: &mut Body) -> Result<(), ConfigError> {
+        let in_idx = self.input(&binding.input)?;
+        if let Some(hold) = binding.hold {
+            check_layer(hold.layer, hold.mark)?;
+            setup.push((Opcode::BindLayerHold(in_idx, hold.layer), hold.mark));
+        }
+        for def in &binding.triggers {
+            let opcode = self.bind(in_idx, def)?;
+            setup.push((opcode, def.mark));
+        }
+        Ok(())
+    }
+
+    /// Zone assignments and bindings, grouped by layers.
+    fn setup(&mut self, mark: Mark) -> Result<Body, ConfigError> {
+        let mut setup = Vec::new();
+        let device = self.device;
+        for (idx, zone) in device.zones.iter().enumerate() {
+            // Zone 0 is the default one.
+            let zone_idx = idx + 1;
+            if zone_idx >= MAX_ZONES {
+                return Err(error(zone.name.mark, ConfigErrorKind::TooManyZones));
+            }
+            for input in &zone.inputs {
+                let opcode = Opcode::ZoneAssign(self.input(input)?, zone_idx as u8);
+                setup.push((opcode, input.mark));
+            }
+        }
+
+        let mut layers: Vec<LayerIdx> = Vec::new();
+        for binding in &device.bindings {
+            if let Some(layer) = binding.layer {
+                check_layer(layer.layer, layer.mark)?;
+            }
+            if !layers.contains(&binding.layer()) {
+                layers.push(binding.layer());
+            }
+        }
+        layers.sort();
+        for &layer in &layers {
+            if layer != 0 {
+                setup.push((Opcode::LayerSet(layer), mark));
+            }
+            for binding in device.bindings.iter().filter(|b| b.layer() == layer) {
+                self.binding(binding, &mut setup)?;
+            }
+        }
+        if layers.iter().any(|&layer| layer != 0) {
+            setup.push((Opcode::LayerDefault, mark));
+        }
+        Ok(setup)
+    }
+
+    /// Compiled program and the remote outputs it sets.
+    fn compile(mut self) -> Result<(DeviceProgram, RemoteOutputs<'a>), ConfigError> {
+        let device = self.device;
+        let mark = device
+            .name
+            .as_ref()
+            .map(|name| name.mark)
+            .unwrap_or_default();
+
+        // Fixed IDs first, then the lowest free ones in the order of definition.
+        for procedure in &device.procedures {
+            if let Some((id, mark)) = procedure.id {
+                if id as usize >= MAX_PROCEDURES {
+                    return Err(error(mark, ConfigErrorKind::TooManyProcedures));
+                }
+                if self.bodies.insert(id, Vec::new()).is_some() {
+                    return Err(error(mark, ConfigErrorKind::DuplicateProcedure(id)));
+                }
+                self.procedures.insert(&procedure.name.value, id);
+            }
+        }
+        self.bodies.insert(0, Vec::new());
+        for procedure in &device.procedures {
+            if procedure.id.is_none() {
+                let id = self.allocate(procedure.name.mark)?;
+                self.procedures.insert(&procedure.name.value, id);
+            }
+        }
+        for procedure in &device.procedures {
+            let body = self.steps(&procedure.steps)?;
+            self.bodies.insert(self.procedure(&procedure.name)?, body);
+        }
+
+        let mut setup = self.setup(mark)?;
+        if let Some(main) = self.bodies.get_mut(&0) {
+            setup.append(main);
+            *main = setup;
+        }
+
+        let mut opcodes = Vec::new();
+        let mut marks = Vec::new();
+        for (id, body) in &self.bodies {
+            opcodes.push(Opcode::Start(*id));
+            marks.push(mark);
+            for &(opcode, opcode_mark) in body {
+                opcodes.push(opcode);
+                marks.push(opcode_mark);
+            }
+            opcodes.push(Opcode::Stop);
+            marks.push(mark);
+        }
+        if let Some(diagnostic) = verifier::verify(&opcodes).into_iter().next() {
+            let mark = marks.get(diagnostic.pc).copied().unwrap_or(mark);
+            return Err(error(mark, ConfigErrorKind::Invalid(diagnostic)));
+        }
+
+        let mut procedures: Vec<(String, ProcIdx)> = self
+            .procedures
+            .iter()
+            .map(|(name, id)| (name.to_string(), *id))
+            .collect();
+        procedures.sort_by_key(|(_, id)| *id);
+        let program = DeviceProgram {
+            device: device.name().to_string(),
+            opcodes,
+            procedures,
+            subscriptions: Vec::new(),
+        };
+        Ok((program, self.remote))
+    }
+}
+
+/// Index of an input. Input 0 is reserved.
+fn input_idx(io: &Io) -> Result<InIdx, ConfigError> {
+    match io.idx {
+        0 => Err(error(io.idx_mark, ConfigErrorKind::ReservedInput)),
+        idx if idx as usize >= MAX_INPUTS => {
+            Err(error(io.idx_mark, ConfigErrorKind::IndexOutOfRange(idx)))
+        }
+        idx => Ok(idx),
+    }
+}
+
+fn output_idx(io: &Io) -> Result<OutIdx, ConfigError> {
+    match io.idx {
+        idx if idx as usize >= MAX_OUTPUTS => {
+            Err(error(io.idx_mark, ConfigErrorKind::IndexOutOfRange(idx)))
+        }
+        idx => Ok(idx),
+    }
+}
+
+fn check_layer(layer: LayerIdx, mark: Mark) -> Result<(), ConfigError> {
+    if layer as usize >= MAX_LAYERS {
+        return Err(error(mark, ConfigErrorKind::Expected("a layer below 128")));
+    }
+    Ok(())
+}
+
+fn bind_call(in_idx: InIdx, trigger: Trigger, proc: ProcIdx) -> Opcode {
+    match trigger {
+        Trigger::ShortClick => Opcode::BindShortCall(in_idx, proc),
+        Trigger::LongClick => Opcode::BindLongCall(in_idx, proc),
+        Trigger::Activated => Opcode::BindActivateCall(in_idx, proc),
+        Trigger::Deactivated => Opcode::BindDeactivateCall(in_idx, proc),
+        Trigger::LongActivated => Opcode::BindLongActivate(in_idx, proc),
+        Trigger::LongDeactivated => Opcode::BindLongDeactivate(in_idx, proc),
+    }
+}
+
+/// Arguments of a raw opcode.
+struct RawArgs<'a> {
+    opcode: &'a RawOpcode,
+    pos: usize,
+}
+
+impl<'a> RawArgs<'a> {
+    fn next(&mut self) -> Result<&'a Name, ConfigError> {
+        let arg = self.opcode.args.get(self.pos).ok_or_else(|| {
+            error(
+                self.opcode.name.mark,
+                ConfigErrorKind::Expected("more arguments"),
+            )
+        })?;
+        self.pos += 1;
+        Ok(arg)
+    }
+
+    fn num<T: FromStr>(&mut self) -> Result<T, ConfigError> {
+        let arg = self.next()?;
+        arg.value
+            .parse()
+            .map_err(|_| error(arg.mark, ConfigErrorKind::Expected("a number")))
+    }
+
+    fn trigger(&mut self) -> Result<Trigger, ConfigError> {
+        let arg = self.next()?;
+        Ok(match arg.value.as_str() {
+            "ShortClick" => Trigger::ShortClick,
+            "LongClick" => Trigger::LongClick,
+            "Activated" => Trigger::Activated,
+            "Deactivated" => Trigger::Deactivated,
+            "LongActivated" => Trigger::LongActivated,
+            "LongDeactivated" => Trigger::LongDeactivated,
+            _ => return Err(error(arg.mark, ConfigErrorKind::Expected("a trigger"))),
+        })
+    }
+
+    fn state(&mut self) -> Result<OutputState, ConfigError> {
+        let arg = self.next()?;
+        Ok(match arg.value.as_str() {
+            "Off" => OutputState::Off,
+            "On" => OutputState::On,
+            "Toggle" => OutputState::Toggle,
+            _ => {
+                return Err(error(
+                    arg.mark,
+                    ConfigErrorKind::Expected("an output state"),
+                ))
+            }
+        })
+    }
+
+    fn retrigger(&mut self) -> Result<Retrigger, ConfigError> {
+        let arg = self.next()?;
+        Ok(match arg.value.as_str() {
+            "Parallel" => Retrigger::Parallel,
+            "Restart" => Retrigger::Restart,
+            "Ignore" => Retrigger::Ignore,
+            "Cancel" => Retrigger::Cancel,
+            _ => {
+                return Err(error(
+                    arg.mark,
+                    ConfigErrorKind::Expected("a retrigger policy"),
+                ))
+            }
+        })
+    }
+}
+
+/// Opcode written by its name, eg. `BindShortToggle: [1, 1]`.
+pub(crate) fn raw_opcode(raw: &RawOpcode) -> Result<Opcode, ConfigError> {
+    let mut a = RawArgs {
+        opcode: raw,
+        pos: 0,
+    };
+    let opcode = match raw.name.value.as_str() {
+        "Noop" => Opcode::Noop,
+        "Call" => Opcode::Call(a.num()?),
+        "CallWith" => Opcode::CallWith(a.num()?, a.num()?, a.num()?),
+        "Toggle" => Opcode::Toggle(a.num()?),
+        "Activate" => Opcode::Activate(a.num()?),
+        "Deactivate" => Opcode::Deactivate(a.num()?),
+        "ActivateFor" => Opcode::ActivateFor(a.num()?, a.num()?),
+        "SetRemote" => Opcode::SetRemote(a.num()?, a.num()?, a.state()?),
+        "Wait" => Opcode::Wait(a.num()?),
+        "WaitForRelease" => Opcode::WaitForRelease(a.num()?),
+        "LayerPush" => Opcode::LayerPush(a.num()?),
+        "LayerPop" => Opcode::LayerPop,
+        "LayerSet" => Opcode::LayerSet(a.num()?),
+        "LayerDefault" => Opcode::LayerDefault,
+        "ZoneAssign" => Opcode::ZoneAssign(a.num()?, a.num()?),
+        "ZoneSelect" => Opcode::ZoneSelect(a.num()?),
+        "BindClearAll" => Opcode::BindClearAll,
+        "BindShortCall" => Opcode::BindShortCall(a.num()?, a.num()?),
+        "BindLongCall" => Opcode::BindLongCall(a.num()?, a.num()?),
+        "BindActivateCall" => Opcode::BindActivateCall(a.num()?, a.num()?),
+        "BindDeactivateCall" => Opcode::BindDeactivateCall(a.num()?, a.num()?),
+        "BindLongActivate" => Opcode::BindLongActivate(a.num()?, a.num()?),
+        "BindLongDeactivate" => Opcode::BindLongDeactivate(a.num()?, a.num()?),
+        "BindCallWith" => {
+            Opcode::BindCallWith(a.num()?, a.trigger()?, a.num()?, a.num()?, a.num()?)
+        }
+        "BindShortToggle" => Opcode::BindShortToggle(a.num()?, a.num()?),
+        "BindLongToggle" => Opcode::BindLongToggle(a.num()?, a.num()?),
+        "BindLayerHold" => Opcode::BindLayerHold(a.num()?, a.num()?),
+        "Load" => Opcode::Load(a.num()?, a.num()?),
+        "ReadInput" => Opcode::ReadInput(a.num()?, a.num()?),
+        "ReadOutput" => Opcode::ReadOutput(a.num()?, a.num()?),
+        "Eq" => Opcode::Eq(a.num()?, a.num()?),
+        "Lt" => Opcode::Lt(a.num()?, a.num()?),
+        "Gt" => Opcode::Gt(a.num()?, a.num()?),
+        "And" => Opcode::And(a.num()?, a.num()?),
+        "Or" => Opcode::Or(a.num()?, a.num()?),
+        "Not" => Opcode::Not(a.num()?),
+        "CallConditionally" => Opcode::CallConditionally(a.num()?, a.num()?, a.num()?),
+        "Set" => Opcode::Set(a.num()?, a.num()?),
+        "Inc" => Opcode::Inc(a.num()?),
+        "Dec" => Opcode::Dec(a.num()?),
+        "Add" => Opcode::Add(a.num()?, a.num()?),
+        "Mod" => Opcode::Mod(a.num()?, a.num()?),
+        "LoadVar" => Opcode::LoadVar(a.num()?, a.num()?),
+        "StoreVar" => Opcode::StoreVar(a.num()?, a.num()?),
+        "VarEq" => Opcode::VarEq(a.num()?, a.num()?, a.num()?),
+        "VarLt" => Opcode::VarLt(a.num()?, a.num()?, a.num()?),
+        "VarGt" => Opcode::VarGt(a.num()?, a.num()?, a.num()?),
+        "Jump" => Opcode::Jump(a.num()?),
+        "JumpTo" => Opcode::JumpTo(a.num()?),
+        "JumpIfZero" => Opcode::JumpIfZero(a.num()?, a.num()?),
+        "JumpIfNotZero" => Opcode::JumpIfNotZero(a.num()?, a.num()?),
+        "Loop" => Opcode::Loop(a.num()?, a.num()?),
+        "SetRetrigger" => Opcode::SetRetrigger(a.num()?, a.retrigger()?),
+        "Cancel" => Opcode::Cancel(a.num()?),
+        "Syscall" => Opcode::Syscall(a.num()?, a.num()?),
+        "SetErrorHandler" => Opcode::SetErrorHandler(a.num()?),
+        // Start and Stop are generated for each procedure.
+        name => {
+            return Err(error(
+                raw.name.mark,
+                ConfigErrorKind::UnknownOpcode(name.to_string()),
+            ));
+        }
+    };
+    if let Some(extra) = raw.args.get(a.pos) {
+        return Err(error(
+            extra.mark,
+            ConfigErrorKind::Expected("fewer arguments"),
+        ));
+    }
+    Ok(opcode)
+}
+
+/// Compile a single device.
+///
+/// Without the configuration, outputs of other devices can't be resolved.
+pub fn compile_device(device: &Device) -> Result<DeviceProgram, ConfigError> {
+    Compiler::new(device, None)
+        .compile()
+        .map(|(program, _)| program)
+}
+
+/// Compile a device resolving outputs of the other devices of the
+/// configuration. Subscriptions are only filled in by `compile`.
+pub fn compile_device_in(config: &Config, device: &Device) -> Result<DeviceProgram, ConfigError> {
+    Compiler::new(device, Some(config))
+        .compile()
+        .map(|(program, _)| program)
+}
+
+/// Compile all devices of the configuration with subscriptions for the remote
+/// outputs.
+pub fn compile(config: &Config) -> Result<Vec<DeviceProgram>, ConfigError> {
+    let mut programs = Vec::new();
+    let mut remote = Vec::new();
+    for device in &config.devices {
+        let (program, outputs) = Compiler::new(device, Some(config)).compile()?;
+        // Devices setting remote outputs have an address.
+        if let Some((address, _)) = device.address {
+            for (target, output) in outputs {
+                remote.push((
+                    target,
+                    Subscription {
+                        device: device.name().to_string(),
+                        address,
+                        output,
+                    },
+                ));
+            }
+        }
+        programs.push(program);
+    }
+    for program in &mut programs {
+        let mut subscriptions: Vec<Subscription> = remote
+            .iter()
+            .filter(|(target, _)| *target == program.device)
+            .map(|(_, subscription)| subscription.clone())
+            .collect();
+        subscriptions.sort();
+        subscriptions.dedup();
+        program.subscriptions = subscriptions;
+    }
+    Ok(programs)
+}
+
+/// Parse and compile configuration source.
+pub fn compile_str(source: &str) -> Result<Vec<DeviceProgram>, ConfigError> {
+    compile(&Config::parse(source)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "
+hall:
+  inputs:
+    - door: 1
+    - stairs: 2
+    - bed: 3
+  zones:
+    upstairs: [stairs, bed]
+  outputs:
+    - ceiling: 10
+    - lamp: 11
+  bindings:
+    - input: door
+      short:
+        toggle: ceiling
+      long:
+        call: all-off
+    - input: stairs
+      layer: 2
+      short:
+        toggle: [ceiling, lamp]
+    - input: bed
+      hold: 2
+      long:
+        activate: lamp
+  procedures:
+    all-off:
+      deactivate: [ceiling, lamp]
+    blink:
+      id: 5
+      opcodes:
+        - Toggle: 11
+        - Wait: 500
+        - Toggle: 11
+fusebox:
+  procedures:
+    main:
+      id: 0
+      opcodes:
+        - LayerDefault
+        - BindShortToggle: [1, 1]
+";
+
+    #[test]
+    fn it_compiles_devices() {
+        let programs = compile_str(SOURCE).unwrap();
+        assert_eq!(programs.len(), 2);
+
+        let hall = &programs[0];
+        assert_eq!(hall.device, "hall");
+        assert_eq!(
+            hall.procedures,
+            vec![("all-off".to_string(), 1), ("blink".to_string(), 5)]
+        );
+        assert_eq!(
+            hall.opcodes,
+            vec![
+                Opcode::Start(0),
+                Opcode::ZoneAssign(2, 1),
+                Opcode::ZoneAssign(3, 1),
+                Opcode::BindShortToggle(1, 10),
+                Opcode::BindLongCall(1, 1),
+                Opcode::BindLayerHold(3, 2),
+                Opcode::BindLongCall(3, 2),
+                Opcode::LayerSet(2),
+                Opcode::BindShortCall(2, 3),
+                Opcode::LayerDefault,
+                Opcode::Stop,
+                Opcode::Start(1),
+                Opcode::Deactivate(10),
+                Opcode::Deactivate(11),
+                Opcode::Stop,
+                Opcode::Start(2),
+                Opcode::Activate(11),
+                Opcode::Stop,
+                Opcode::Start(3),
+                Opcode::Toggle(10),
+                Opcode::Toggle(11),
+                Opcode::Stop,
+                Opcode::Start(5),
+                Opcode::Toggle(11),
+                Opcode::Wait(500),
+                Opcode::Toggle(11),
+                Opcode::Stop,
+            ]
+        );
+
+        let fusebox = &programs[1];
+        assert_eq!(
+            fusebox.opcodes,
+            vec![
+                Opcode::Start(0),
+                Opcode::LayerDefault,
+                Opcode::BindShortToggle(1, 1),
+                Opcode::Stop,
+            ]
+        );
+        assert_eq!(fusebox.procedure("main"), Some(0));
+    }
+
+    #[test]
+    fn it_reports_unknown_names() {
+        // The example config deactivates an input in `all-off`.
+        let err = compile_str(include_str!("../code.yaml")).unwrap_err();
+        assert_eq!(
+            err.kind,
+            ConfigErrorKind::UnknownOutput("kitchen1_l".to_string())
+        );
+        assert_eq!(err.mark.column, 20);
+
+        let err = compile_str("a:\n  procedures:\n    p:\n      call: q\n").unwrap_err();
+        assert_eq!(err.kind, ConfigErrorKind::UnknownProcedure("q".to_string()));
+        assert_eq!(
+            err.mark,
+            Mark {
+                line: 4,
+                column: 13
+            }
+        );
+
+        let err = compile_str("a:\n  procedures:\n    p:\n      opcodes: [Blink]\n").unwrap_err();
+        assert_eq!(
+            err.kind,
+            ConfigErrorKind::UnknownOpcode("Blink".to_string())
+        );
+
+        let source = "a:\n  procedures:\n    p: {id: 3}\n    q: {id: 3}\n";
+        let err = compile_str(source).unwrap_err();
+        assert_eq!(err.kind, ConfigErrorKind::DuplicateProcedure(3));
+        assert_eq!(err.mark.line, 4);
+    }
+
+    #[test]
+    fn it_reports_errors_at_their_source() {
+        let source = "
+a:
+  inputs: {door: 0}
+  bindings:
+    - {input: door, short: {call: p}}
+  procedures:
+    p: {}
+";
+        let err = compile_str(source).unwrap_err();
+        assert_eq!(err.kind, ConfigErrorKind::ReservedInput);
+        assert_eq!((err.mark.line, err.mark.column), (3, 18));
+
+        let source = "a:\n  outputs: {lamp: 200}\n  procedures:\n    p: {toggle: lamp}\n";
+        let err = compile_str(source).unwrap_err();
+        assert_eq!(err.kind, ConfigErrorKind::IndexOutOfRange(200));
+        assert_eq!((err.mark.line, err.mark.column), (2, 19));
+
+        // Verifier problems point at the raw opcode.
+        let source = "
+a:
+  procedures:
+    p:
+      opcodes:
+        - Noop
+        - Call: [9]
+";
+        let err = compile_str(source).unwrap_err();
+        let problem = verifier::Problem::UnknownProcedure(9);
+        assert_eq!(
+            err.kind,
+            ConfigErrorKind::Invalid(verifier::Diagnostic::new(4, problem))
+        );
+        assert_eq!((err.mark.line, err.mark.column), (7, 11));
+    }
+
+    #[test]
+    fn it_resolves_remote_outputs() {
+        let source = "
+livingroom:
+  address: 3
+  inputs: {kitchen: 1, door: 2}
+  bindings:
+    - input: kitchen
+      short:
+        toggle: fusebox.corridor
+      long:
+        deactivate: [fusebox.corridor, fusebox.stairs]
+fusebox:
+  address: 1
+  outputs: {corridor: 5, stairs: 6}
+";
+        let programs = compile_str(source).unwrap();
+        assert_eq!(
+            programs[0].opcodes,
+            vec![
+                Opcode::Start(0),
+                Opcode::BindShortCall(1, 1),
+                Opcode::BindLongCall(1, 2),
+                Opcode::Stop,
+                Opcode::Start(1),
+                Opcode::SetRemote(1, 5, OutputState::Toggle),
+                Opcode::Stop,
+                Opcode::Start(2),
+                Opcode::SetRemote(1, 5, OutputState::Off),
+                Opcode::SetRemote(1, 6, OutputState::Off),
+                Opcode::Stop,
+            ]
+        );
+        assert!(programs[0].subscriptions.is_empty());
+        let subscription = |output| Subscription {
+            device: "livingroom".to_string(),
+            address: 3,
+            output,
+        };
+        assert_eq!(
+            programs[1].subscriptions,
+            vec![subscription(5), subscription(6)]
+        );
+
+        let config = Config::parse(source).unwrap();
+        let err = compile_device(&config.devices[0]).unwrap_err();
+        assert_eq!(
+            err.kind,
+            ConfigErrorKind::UnknownDevice("fusebox".to_string())
+        );
+
+        let source = source.replace("  address: 1\n", "");
+        let err = compile_str(&source).unwrap_err();
+        assert_eq!(
+            err.kind,
+            ConfigErrorKind::MissingAddress("fusebox".to_string())
+        );
+        assert_eq!(err.mark.line, 8);
+
+        // Sender has to be known to the receiver too.
+        let source = source
+            .replace("fusebox:\n", "fusebox:\n  address: 1\n")
+            .replace("  address: 3\n", "");
+        let err = compile_str(&source).unwrap_err();
+        assert_eq!(
+            err.kind,
+            ConfigErrorKind::MissingAddress("livingroom".to_string())
+        );
+    }
+}
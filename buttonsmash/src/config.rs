@@ -0,0 +1,719 @@
+/*
+ * Device configuration read from YAML (see code.yaml). Every name keeps its
+ * position in the source, so the compiler and linter can point at it.
+ */
+
+use core::fmt;
+use core::str::FromStr;
+
+use yaml_rust2::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust2::scanner::Marker;
+
+use crate::consts::{DevAddr, LayerIdx, ProcIdx, Trigger, MAX_DEVICES};
+use crate::verifier::Diagnostic;
+
+/// Position in the YAML source. Both line and column start at 1.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Mark {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl From<Marker> for Mark {
+    fn from(marker: Marker) -> Self {
+        Self {
+            line: marker.line(),
+            column: marker.col() + 1,
+        }
+    }
+}
+
+impl fmt::Display for Mark {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Value {
+    Scalar(String),
+    Seq(Vec<Node>),
+    Map(Vec<(Node, Node)>),
+}
+
+/// YAML node with its position.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Node {
+    pub value: Value,
+    pub mark: Mark,
+}
+
+/// Builds a node tree from parser events.
+#[derive(Default)]
+struct TreeBuilder {
+    /// Unfinished sequences and maps.
+    stack: Vec<Node>,
+    /// Key waiting for its value, for each map on the stack.
+    keys: Vec<Option<Node>>,
+    root: Option<Node>,
+    /// First unsupported node.
+    error: Option<ConfigError>,
+}
+
+impl TreeBuilder {
+    fn push(&mut self, node: Node) {
+        let Some(parent) = self.stack.last_mut() else {
+            self.root.get_or_insert(node);
+            return;
+        };
+        match &mut parent.value {
+            Value::Seq(items) => items.push(node),
+            Value::Map(entries) => {
+                let key = self.keys.last_mut().expect("key slot for a map");
+                match key.take() {
+                    Some(key) => entries.push((key, node)),
+                    None => *key = Some(node),
+                }
+            }
+            Value::Scalar(_) => unreachable!("scalars are never on the stack"),
+        }
+    }
+}
+
+impl MarkedEventReceiver for TreeBuilder {
+    fn on_event(&mut self, event: Event, marker: Marker) {
+        let mark = Mark::from(marker);
+        match event {
+            Event::Scalar(value, ..) => self.push(Node {
+                value: Value::Scalar(value),
+                mark,
+            }),
+            // Aliased node is not kept, so the alias can't be resolved.
+            Event::Alias(_) => {
+                let kind = ConfigErrorKind::Unsupported("anchors and aliases");
+                self.error.get_or_insert(ConfigError::new(mark, kind));
+                self.push(Node {
+                    value: Value::Scalar(String::new()),
+                    mark,
+                });
+            }
+            Event::SequenceStart(..) => {
+                self.stack.push(Node {
+                    value: Value::Seq(Vec::new()),
+                    mark,
+                });
+                self.keys.push(None);
+            }
+            Event::MappingStart(..) => {
+                self.stack.push(Node {
+                    value: Value::Map(Vec::new()),
+                    mark,
+                });
+                self.keys.push(None);
+            }
+            Event::SequenceEnd | Event::MappingEnd => {
+                self.keys.pop();
+                if let Some(node) = self.stack.pop() {
+                    self.push(node);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// What is wrong with the configuration.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ConfigErrorKind {
+    /// Source is not a valid YAML.
+    Yaml(String),
+    /// YAML feature the configuration can't use, eg. aliases.
+    Unsupported(&'static str),
+    /// Node has a different type or value than expected.
+    Expected(&'static str),
+    UnknownKey(String),
+    UnknownInput(String),
+    UnknownOutput(String),
+    UnknownProcedure(String),
+    UnknownOpcode(String),
+    /// Remote output refers to a device not in the configuration.
+    UnknownDevice(String),
+    /// Remote output refers to a device without a CAN `address`.
+    MissingAddress(String),
+    /// Procedure ID is used twice.
+    DuplicateProcedure(ProcIdx),
+    TooManyProcedures,
+    TooManyZones,
+    /// Input 0 is reserved and can't be used.
+    ReservedInput,
+    /// IO index is above the executor limits.
+    IndexOutOfRange(u8),
+    /// Compiled program didn't pass the verifier.
+    Invalid(Diagnostic),
+}
+
+/// Configuration error with the source position.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConfigError {
+    pub mark: Mark,
+    pub kind: ConfigErrorKind,
+}
+
+impl ConfigError {
+    pub fn new(mark: Mark, kind: ConfigErrorKind) -> Self {
+        Self { mark, kind }
+    }
+}
+
+impl fmt::Display for ConfigErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigErrorKind::Yaml(info) => write!(f, "invalid YAML: {}", info),
+            ConfigErrorKind::Unsupported(what) => write!(f, "{} are not supported", what),
+            ConfigErrorKind::Expected(what) => write!(f, "expected {}", what),
+            ConfigErrorKind::UnknownKey(key) => write!(f, "unknown key `{}`", key),
+            ConfigErrorKind::UnknownInput(name) => write!(f, "unknown input `{}`", name),
+            ConfigErrorKind::UnknownOutput(name) => write!(f, "unknown output `{}`", name),
+            ConfigErrorKind::UnknownProcedure(name) => write!(f, "unknown procedure `{}`", name),
+            ConfigErrorKind::UnknownOpcode(name) => write!(f, "unknown opcode `{}`", name),
+            ConfigErrorKind::UnknownDevice(name) => write!(f, "unknown device `{}`", name),
+            ConfigErrorKind::MissingAddress(name) => {
+                write!(f, "device `{}` has no address", name)
+            }
+            ConfigErrorKind::DuplicateProcedure(id) => write!(f, "procedure {} defined twice", id),
+            ConfigErrorKind::TooManyProcedures => write!(f, "too many procedures"),
+            ConfigErrorKind::TooManyZones => write!(f, "too many zones"),
+            ConfigErrorKind::ReservedInput => write!(f, "input 0 is reserved"),
+            ConfigErrorKind::IndexOutOfRange(idx) => write!(f, "IO index {} out of range", idx),
+            ConfigErrorKind::Invalid(diagnostic) => write!(f, "invalid program: {}", diagnostic),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.mark, self.kind)
+    }
+}
+
+/// Parse YAML source into a node tree.
+pub fn parse_yaml(source: &str) -> Result<Node, ConfigError> {
+    let mut builder = TreeBuilder::default();
+    let mut parser = Parser::new_from_str(source);
+    parser.load(&mut builder, false).map_err(|err| {
+        ConfigError::new(
+            Mark::from(*err.marker()),
+            ConfigErrorKind::Yaml(err.info().to_string()),
+        )
+    })?;
+    if let Some(err) = builder.error {
+        return Err(err);
+    }
+    Ok(builder.root.unwrap_or(Node {
+        value: Value::Map(Vec::new()),
+        mark: Mark { line: 1, column: 1 },
+    }))
+}
+
+impl Node {
+    fn expected(&self, what: &'static str) -> ConfigError {
+        ConfigError::new(self.mark, ConfigErrorKind::Expected(what))
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match &self.value {
+            Value::Scalar(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Empty value (`key:` or `~`).
+    pub fn is_null(&self) -> bool {
+        matches!(self.as_str(), Some("" | "~" | "null"))
+    }
+
+    pub fn scalar(&self) -> Result<&str, ConfigError> {
+        self.as_str().ok_or_else(|| self.expected("a value"))
+    }
+
+    pub fn number<T: FromStr>(&self) -> Result<T, ConfigError> {
+        self.as_str()
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| self.expected("a number"))
+    }
+
+    pub fn name(&self) -> Result<Name, ConfigError> {
+        Ok(Name::new(self.scalar()?, self.mark))
+    }
+
+    pub fn seq(&self) -> Result<&[Node], ConfigError> {
+        match &self.value {
+            Value::Seq(items) => Ok(items),
+            _ => Err(self.expected("a list")),
+        }
+    }
+
+    pub fn map(&self) -> Result<&[(Node, Node)], ConfigError> {
+        match &self.value {
+            Value::Map(entries) => Ok(entries),
+            // `key:` without any value is an empty map.
+            _ if self.is_null() => Ok(&[]),
+            _ => Err(self.expected("a mapping")),
+        }
+    }
+
+    /// Single name or a list of names.
+    pub fn names(&self) -> Result<Vec<Name>, ConfigError> {
+        match &self.value {
+            Value::Seq(items) => items.iter().map(Node::name).collect(),
+            _ => Ok(vec![self.name()?]),
+        }
+    }
+
+    /// Entries of a map, or of a list of single-entry maps (`- name: value`).
+    pub fn entries(&self) -> Result<Vec<&(Node, Node)>, ConfigError> {
+        match &self.value {
+            Value::Seq(items) => {
+                let mut entries = Vec::new();
+                for item in items {
+                    entries.extend(item.map()?);
+                }
+                Ok(entries)
+            }
+            _ => Ok(self.map()?.iter().collect()),
+        }
+    }
+}
+
+/// Name used in the configuration with its position.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Name {
+    pub value: String,
+    pub mark: Mark,
+}
+
+impl Name {
+    pub fn new(value: &str, mark: Mark) -> Self {
+        Self {
+            value: value.to_string(),
+            mark,
+        }
+    }
+
+    /// Device and output of a `device.output` name.
+    pub fn remote(&self) -> Option<(&str, &str)> {
+        self.value
+            .split_once('.')
+            .filter(|(device, output)| !device.is_empty() && !output.is_empty())
+    }
+}
+
+/// Named input or output.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Io {
+    pub name: Name,
+    pub idx: u8,
+    /// Position of the index.
+    pub idx_mark: Mark,
+}
+
+/// Group of inputs sharing a layer stack.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Zone {
+    pub name: Name,
+    pub inputs: Vec<Name>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ActionKind {
+    Toggle,
+    Activate,
+    Deactivate,
+    Call,
+}
+
+/// Action on one or more targets, eg. `toggle: [a, b]`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ActionDef {
+    pub kind: ActionKind,
+    pub targets: Vec<Name>,
+    pub mark: Mark,
+}
+
+/// Opcode written directly, eg. `BindShortToggle: [1, 1]`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RawOpcode {
+    pub name: Name,
+    pub args: Vec<Name>,
+}
+
+/// Single step of a procedure.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Step {
+    Action(ActionDef),
+    Opcode(RawOpcode),
+}
+
+/// Actions executed on a trigger.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TriggerDef {
+    pub trigger: Trigger,
+    pub mark: Mark,
+    pub actions: Vec<ActionDef>,
+}
+
+/// Layer number with its position.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct LayerRef {
+    pub layer: LayerIdx,
+    pub mark: Mark,
+}
+
+/// Entry of the `bindings` list.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BindingDef {
+    pub mark: Mark,
+    pub input: Name,
+    /// Layer the bindings apply to, default 0.
+    pub layer: Option<LayerRef>,
+    pub triggers: Vec<TriggerDef>,
+    /// Layer activated while the input is held.
+    pub hold: Option<LayerRef>,
+}
+
+impl BindingDef {
+    pub fn layer(&self) -> LayerIdx {
+        self.layer.map_or(0, |layer| layer.layer)
+    }
+}
+
+/// Named procedure.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProcedureDef {
+    pub name: Name,
+    /// Fixed procedure ID, otherwise it's allocated by the compiler.
+    pub id: Option<(ProcIdx, Mark)>,
+    pub steps: Vec<Step>,
+}
+
+/// IO expander as written in the `expanders` list. Values are interpreted
+/// by the hardware profile.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ExpanderDef {
+    pub mark: Mark,
+    pub addr: Name,
+    pub direction: Name,
+    pub chip: Option<Name>,
+    /// Logical IO index of each expander pin.
+    pub pins: Vec<Name>,
+}
+
+/// Configuration of a single device (controller).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Device {
+    pub name: Option<Name>,
+    /// CAN address used by the other devices to reach this one.
+    pub address: Option<(DevAddr, Mark)>,
+    pub expanders: Vec<ExpanderDef>,
+    pub inputs: Vec<Io>,
+    pub outputs: Vec<Io>,
+    pub zones: Vec<Zone>,
+    pub bindings: Vec<BindingDef>,
+    pub procedures: Vec<ProcedureDef>,
+}
+
+impl Device {
+    pub fn name(&self) -> &str {
+        self.name.as_ref().map_or("", |name| name.value.as_str())
+    }
+}
+
+/// Whole configuration file.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Config {
+    pub devices: Vec<Device>,
+}
+
+/// Trigger names used as keys in bindings.
+pub const TRIGGERS: [(Trigger, &str); 6] = [
+    (Trigger::ShortClick, "short"),
+    (Trigger::LongClick, "long"),
+    (Trigger::Activated, "activate"),
+    (Trigger::Deactivated, "deactivate"),
+    (Trigger::LongActivated, "long_activate"),
+    (Trigger::LongDeactivated, "long_deactivate"),
+];
+
+/// Action names used as keys in triggers and procedures.
+pub const ACTIONS: [(ActionKind, &str); 4] = [
+    (ActionKind::Toggle, "toggle"),
+    (ActionKind::Activate, "activate"),
+    (ActionKind::Deactivate, "deactivate"),
+    (ActionKind::Call, "call"),
+];
+
+fn unknown_key(key: &Node) -> ConfigError {
+    let name = key.as_str().unwrap_or_default().to_string();
+    ConfigError::new(key.mark, ConfigErrorKind::UnknownKey(name))
+}
+
+fn parse_io(node: &Node) -> Result<Vec<Io>, ConfigError> {
+    node.entries()?
+        .into_iter()
+        .map(|(key, value)| {
+            Ok(Io {
+                name: key.name()?,
+                idx: value.number()?,
+                idx_mark: value.mark,
+            })
+        })
+        .collect()
+}
+
+fn parse_zones(node: &Node) -> Result<Vec<Zone>, ConfigError> {
+    node.entries()?
+        .into_iter()
+        .map(|(key, value)| {
+            Ok(Zone {
+                name: key.name()?,
+                inputs: value.names()?,
+            })
+        })
+        .collect()
+}
+
+fn parse_layer(node: &Node) -> Result<LayerRef, ConfigError> {
+    Ok(LayerRef {
+        layer: node.number()?,
+        mark: node.mark,
+    })
+}
+
+fn parse_action(key: &Node, value: &Node) -> Result<ActionDef, ConfigError> {
+    let name = key.scalar()?;
+    let (kind, _) = ACTIONS
+        .iter()
+        .find(|(_, known)| *known == name)
+        .ok_or_else(|| unknown_key(key))?;
+    Ok(ActionDef {
+        kind: *kind,
+        targets: value.names()?,
+        mark: key.mark,
+    })
+}
+
+fn parse_binding(node: &Node) -> Result<BindingDef, ConfigError> {
+    let mut input = None;
+    let mut binding = BindingDef {
+        mark: node.mark,
+        input: Name::new("", node.mark),
+        layer: None,
+        triggers: Vec::new(),
+        hold: None,
+    };
+    for (key, value) in node.map()? {
+        match key.scalar()? {
+            "input" => input = Some(value.name()?),
+            "layer" => binding.layer = Some(parse_layer(value)?),
+            "hold" => binding.hold = Some(parse_layer(value)?),
+            name => {
+                let (trigger, _) = TRIGGERS
+                    .iter()
+                    .find(|(_, known)| *known == name)
+                    .ok_or_else(|| unknown_key(key))?;
+                let actions = value
+                    .map()?
+                    .iter()
+                    .map(|(key, value)| parse_action(key, value))
+                    .collect::<Result<_, _>>()?;
+                binding.triggers.push(TriggerDef {
+                    trigger: *trigger,
+                    mark: key.mark,
+                    actions,
+                });
+            }
+        }
+    }
+    binding.input = input.ok_or_else(|| node.expected("an `input`"))?;
+    Ok(binding)
+}
+
+fn parse_raw_opcode(node: &Node) -> Result<RawOpcode, ConfigError> {
+    if node.as_str().is_some() {
+        return Ok(RawOpcode {
+            name: node.name()?,
+            args: Vec::new(),
+        });
+    }
+    match node.map()? {
+        [(key, args)] => Ok(RawOpcode {
+            name: key.name()?,
+            args: args.names()?,
+        }),
+        _ => Err(node.expected("an opcode")),
+    }
+}
+
+fn parse_procedure(key: &Node, value: &Node) -> Result<ProcedureDef, ConfigError> {
+    let mut procedure = ProcedureDef {
+        name: key.name()?,
+        id: None,
+        steps: Vec::new(),
+    };
+    for (key, value) in value.map()? {
+        match key.scalar()? {
+            "id" => procedure.id = Some((value.number()?, value.mark)),
+            "opcodes" => {
+                for opcode in value.seq()? {
+                    procedure
+                        .steps
+                        .push(Step::Opcode(parse_raw_opcode(opcode)?));
+                }
+            }
+            _ => procedure
+                .steps
+                .push(Step::Action(parse_action(key, value)?)),
+        }
+    }
+    Ok(procedure)
+}
+
+fn parse_expander(node: &Node) -> Result<ExpanderDef, ConfigError> {
+    let mut addr = None;
+    let mut direction = None;
+    let mut chip = None;
+    let mut pins = Vec::new();
+    for (key, value) in node.map()? {
+        match key.scalar()? {
+            "addr" => addr = Some(value.name()?),
+            "direction" => direction = Some(value.name()?),
+            "chip" => chip = Some(value.name()?),
+            "pins" => pins = value.names()?,
+            _ => return Err(unknown_key(key)),
+        }
+    }
+    Ok(ExpanderDef {
+        mark: node.mark,
+        addr: addr.ok_or_else(|| node.expected("an `addr`"))?,
+        direction: direction.ok_or_else(|| node.expected("a `direction`"))?,
+        chip,
+        pins,
+    })
+}
+
+fn parse_device(name: Name, node: &Node) -> Result<Device, ConfigError> {
+    let mut device = Device {
+        name: Some(name),
+        ..Device::default()
+    };
+    for (key, value) in node.map()? {
+        match key.scalar()? {
+            "address" => {
+                let address = value.number()?;
+                if address as usize >= MAX_DEVICES {
+                    return Err(value.expected("an address below 64"));
+                }
+                device.address = Some((address, value.mark));
+            }
+            "expanders" => {
+                device.expanders = value
+                    .seq()?
+                    .iter()
+                    .map(parse_expander)
+                    .collect::<Result<_, _>>()?;
+            }
+            "inputs" => device.inputs = parse_io(value)?,
+            "outputs" => device.outputs = parse_io(value)?,
+            "zones" => device.zones = parse_zones(value)?,
+            "bindings" => {
+                device.bindings = value
+                    .seq()?
+                    .iter()
+                    .map(parse_binding)
+                    .collect::<Result<_, _>>()?;
+            }
+            "procedures" => {
+                device.procedures = value
+                    .map()?
+                    .iter()
+                    .map(|(key, value)| parse_procedure(key, value))
+                    .collect::<Result<_, _>>()?;
+            }
+            _ => return Err(unknown_key(key)),
+        }
+    }
+    Ok(device)
+}
+
+impl Config {
+    /// Parse configuration where each top-level key is a device.
+    pub fn parse(source: &str) -> Result<Self, ConfigError> {
+        let root = parse_yaml(source)?;
+        let devices = root
+            .map()?
+            .iter()
+            .map(|(key, value)| parse_device(key.name()?, value))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { devices })
+    }
+
+    pub fn device(&self, name: &str) -> Option<&Device> {
+        self.devices.iter().find(|device| device.name() == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_keeps_positions() {
+        let root = parse_yaml("a:\n  b: [1, two]\n").unwrap();
+        let (key, value) = &root.map().unwrap()[0];
+        assert_eq!(key.mark, Mark { line: 1, column: 1 });
+        let (key, value) = &value.map().unwrap()[0];
+        assert_eq!(key.as_str(), Some("b"));
+        let names = value.names().unwrap();
+        assert_eq!(
+            names[1],
+            Name::new(
+                "two",
+                Mark {
+                    line: 2,
+                    column: 10
+                }
+            )
+        );
+
+        let err = parse_yaml("a: [1\n").unwrap_err();
+        assert!(matches!(err.kind, ConfigErrorKind::Yaml(_)));
+
+        let err = parse_yaml("lamps: &lamps [a, b]\ntoggle: *lamps\n").unwrap_err();
+        assert_eq!(err.mark, Mark { line: 2, column: 9 });
+        assert_eq!(
+            err.to_string(),
+            "2:9: anchors and aliases are not supported"
+        );
+    }
+
+    #[test]
+    fn it_parses_example_config() {
+        let config = Config::parse(include_str!("../code.yaml")).unwrap();
+        let living = config.device("livingroom").unwrap();
+        assert_eq!(living.inputs.len(), 6);
+        assert_eq!(living.outputs[1].name.value, "island");
+        assert_eq!(living.outputs[1].idx, 1);
+        assert_eq!(living.zones[1].inputs.len(), 2);
+        assert_eq!(living.bindings.len(), 4);
+        assert_eq!(living.bindings[2].layer(), 1);
+        assert_eq!(living.bindings[3].triggers[0].trigger, Trigger::LongClick);
+
+        let fusebox = config.device("fusebox").unwrap();
+        assert_eq!(fusebox.procedures[0].id.map(|(id, _)| id), Some(0));
+        assert_eq!(fusebox.procedures[0].steps.len(), 3);
+
+        let err = Config::parse("dev:\n  bindings:\n    - input: a\n      tap: {}\n").unwrap_err();
+        assert_eq!(err.mark, Mark { line: 4, column: 7 });
+        assert_eq!(err.kind, ConfigErrorKind::UnknownKey("tap".to_string()));
+    }
+}
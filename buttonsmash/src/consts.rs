@@ -7,13 +7,63 @@ pub type InIdx = u8;
 pub type OutIdx = u8;
 pub type LayerIdx = u8;
 pub type ProcIdx = u8;
+/// Group of inputs sharing a layer stack.
+pub type ZoneIdx = u8;
+/// Index of a VM register.
+pub type RegIdx = u8;
+/// Index of a persistent VM variable.
+pub type VarIdx = u8;
+/// ID of a native syscall.
+pub type SyscallId = u8;
+/// CAN address of a device on the bus (6 bits).
+pub type DevAddr = u8;
+/// Argument bytes passed to a procedure.
+pub type Args = [u8; 2];
+/// Output, layer or `Load` value operand replaced by the first procedure
+/// argument.
+pub const ARG0: u8 = 0xFE;
+/// Output, layer or `Load` value operand replaced by the second procedure
+/// argument.
+pub const ARG1: u8 = 0xFF;
+/// Value held in a VM register.
+pub type Word = u16;
+/// Time in milliseconds.
+pub type Millis = u32;
 pub const MAX_PROCEDURES: usize = 128;
+/// Procedure IDs a program can use, sized for any executor.
+pub const PROCEDURE_IDS: usize = ProcIdx::MAX as usize + 1;
+/// Default capacity of the executor program memory (opcodes).
+pub const DEFAULT_PROGRAM_LENGTH: usize = 1024;
 pub const MAX_LAYERS: usize = 128;
+/// Default depth of a layer stack.
 pub const MAX_LAYER_STACK: usize = 5;
+pub const MAX_ZONES: usize = 8;
+/// Maximal depth of nested procedure calls.
+pub const MAX_CALL_DEPTH: usize = 16;
+/// Size of the VM register file.
+pub const REGISTERS: usize = 8;
+/// Variables kept between procedure runs.
+pub const VARIABLES: usize = 32;
+/// Outputs that can be activated for a set time simultaneously.
+pub const MAX_TIMERS: usize = 16;
+/// Native syscalls that can be registered.
+pub const MAX_SYSCALLS: usize = 16;
+/// Procedures that can wait simultaneously.
+pub const MAX_THREADS: usize = 8;
 
 // FIXME: Those required?
 pub const MAX_INPUTS: usize = 128;
 pub const MAX_OUTPUTS: usize = 128;
+/// Devices addressable on the CAN bus.
+pub const MAX_DEVICES: usize = 64;
+
+/// Requested state of an output on another device.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum OutputState {
+    Off,
+    On,
+    Toggle,
+}
 
 // TODO: Low/high active?
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -24,20 +74,66 @@ pub enum Command {
     ActivateOutput(OutIdx),
     /// Deactivate output of given ID - Local or remote
     DeactivateOutput(OutIdx),
+    /// Change output of another device (SET_OUTPUT message).
+    SetRemoteOutput(DevAddr, OutIdx, OutputState),
 
     /// Activate layer (public message)
     ActivateLayer(LayerIdx),
     /// Deactivate layer (public message)
     DeactivateLayer(LayerIdx),
+    /// Report an error (public message)
+    Error(ErrorCode),
     /// No operation
     Noop,
 }
 
+/// Errors reported to the bus.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum ErrorCode {
+    /// Layer activated while the layer stack was full.
+    LayerStackOverflow = 0x01,
+    /// Procedure calls nested too deep.
+    StackOverflow = 0x02,
+    /// Procedure aborted after executing too many opcodes.
+    InstructionBudgetExceeded = 0x03,
+    /// Procedure aborted after executing for too long.
+    TimeBudgetExceeded = 0x04,
+    /// Procedure reached the end of program memory.
+    EndOfProgram = 0x05,
+    /// Too many outputs activated for a set time.
+    TimersFull = 0x06,
+    /// Too many procedures waiting simultaneously.
+    TooManyThreads = 0x07,
+    /// Opcode can't be executed (eg. Start inside a procedure).
+    InvalidOpcode = 0x08,
+    /// Called procedure is not defined.
+    UnknownProcedure = 0x09,
+    /// Command queue was closed. Can't be reported on the bus.
+    QueueClosed = 0x0A,
+    InputOutOfRange = 0x0B,
+    OutputOutOfRange = 0x0C,
+    LayerOutOfRange = 0x0D,
+    ZoneOutOfRange = 0x0E,
+    RegisterOutOfRange = 0x0F,
+    VariableOutOfRange = 0x10,
+    /// No free slot for a new binding.
+    BindingsFull = 0x11,
+    /// Mod opcode with a zero divisor.
+    ModuloByZero = 0x12,
+    /// No native handler registered for the syscall.
+    UnknownSyscall = 0x13,
+    /// Native syscall handler failed.
+    SyscallFailed = 0x14,
+    /// Remote device set an output it isn't subscribed to.
+    RemoteOutputDenied = 0x15,
+}
+
 /// Buttons can be triggered in multiple ways.
 /// TODO: This is after initial detection of short/long click detection. Events can be duplicated for a key:
 /// eg. Activated -> LongActivated -> LongClick -> LongDeactivated -> Deactivated.
 /// Activated -> ShortClick -> Deactivated
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 pub enum Trigger {
     /// Short click activation; longer than debounce period, but shorter than a
     /// long click. Triggered on deactivation.
@@ -76,6 +172,21 @@ pub struct SwitchEvent {
     pub state: SwitchState,
 }
 
+/// Output state reported by the IO layer.
+#[derive(Debug, Copy, Clone)]
+pub struct OutputChange {
+    pub out_idx: OutIdx,
+    pub active: bool,
+}
+
+/// Output change requested by another device (SET_OUTPUT message).
+#[derive(Debug, Copy, Clone)]
+pub struct RemoteOutput {
+    pub device: DevAddr,
+    pub out_idx: OutIdx,
+    pub state: OutputState,
+}
+
 #[derive(Debug)]
 pub enum LayerEvent {
     Activate(u8),
@@ -86,6 +197,10 @@ pub enum LayerEvent {
 pub enum Event {
     /// Button event
     ButtonTrigger(ButtonTrigger),
+    /// Output changed state
+    OutputChanged(OutputChange),
+    /// Another device sets a local output
+    RemoteOutput(RemoteOutput),
     /*
     /// External information about layer change
     LayerEvent(LayerEvent),
@@ -99,4 +214,12 @@ impl Event {
             trigger
         })
     }
+
+    pub fn new_output_changed(out_idx: OutIdx, active: bool) -> Self {
+        Event::OutputChanged(OutputChange { out_idx, active })
+    }
+
+    pub fn new_remote_output(device: DevAddr, out_idx: OutIdx, state: OutputState) -> Self {
+        Event::RemoteOutput(RemoteOutput { device, out_idx, state })
+    }
 }
@@ -0,0 +1,782 @@
+/*
+ * Reconstructs the device configuration from a compiled program, eg. one read
+ * back from a controller whose source YAML got lost.
+ *
+ * The setup part of procedure 0 (zone assignments and bindings) becomes the
+ * `zones` and `bindings` sections and the rest of it the `main` procedure.
+ * Procedures bound by a single binding are inlined into it as actions. Others
+ * keep their IDs, so the remote calls still reach them. Bodies that can't be
+ * written as actions are written as opcodes.
+ *
+ * Names come from an optional name table, unknown ones are numbered: `in3`,
+ * `out5`, `proc2`, `zone1`.
+ */
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::compiler::DeviceProgram;
+use crate::config::{
+    ActionDef, ActionKind, BindingDef, Device, Io, LayerRef, Mark, Name, ProcedureDef, RawOpcode,
+    Step, TriggerDef, Zone, ACTIONS, TRIGGERS,
+};
+use crate::consts::{InIdx, LayerIdx, OutIdx, ProcIdx, Trigger, ZoneIdx, PROCEDURE_IDS};
+use crate::opcodes::Opcode;
+use crate::verifier::{self, Diagnostic, Problem};
+
+/// Names of inputs, outputs, procedures and zones by their indices.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct NameTable {
+    pub inputs: Vec<(String, InIdx)>,
+    pub outputs: Vec<(String, OutIdx)>,
+    pub procedures: Vec<(String, ProcIdx)>,
+    pub zones: Vec<(String, ZoneIdx)>,
+}
+
+impl NameTable {
+    /// Names declared by the device configuration and used by its program.
+    pub fn new(device: &Device, program: &DeviceProgram) -> Self {
+        let ios = |ios: &[Io]| {
+            ios.iter()
+                .map(|io| (io.name.value.clone(), io.idx))
+                .collect()
+        };
+        Self {
+            inputs: ios(&device.inputs),
+            outputs: ios(&device.outputs),
+            procedures: program.procedures.clone(),
+            zones: device
+                .zones
+                .iter()
+                .enumerate()
+                .map(|(idx, zone)| (zone.name.value.clone(), idx as ZoneIdx + 1))
+                .collect(),
+        }
+    }
+}
+
+fn lookup<T: PartialEq>(names: &[(String, T)], idx: T) -> Option<&str> {
+    names
+        .iter()
+        .find(|(_, known)| *known == idx)
+        .map(|(name, _)| name.as_str())
+}
+
+fn name(value: String) -> Name {
+    Name {
+        value,
+        mark: Mark::default(),
+    }
+}
+
+/// Procedures an opcode refers to.
+fn procedures(opcode: &Opcode) -> Vec<ProcIdx> {
+    match *opcode {
+        Opcode::Call(proc)
+        | Opcode::CallWith(proc, _, _)
+        | Opcode::SetRetrigger(proc, _)
+        | Opcode::Cancel(proc)
+        | Opcode::SetErrorHandler(proc)
+        | Opcode::BindShortCall(_, proc)
+        | Opcode::BindLongCall(_, proc)
+        | Opcode::BindActivateCall(_, proc)
+        | Opcode::BindDeactivateCall(_, proc)
+        | Opcode::BindLongActivate(_, proc)
+        | Opcode::BindLongDeactivate(_, proc)
+        | Opcode::BindCallWith(_, _, proc, _, _) => vec![proc],
+        Opcode::CallConditionally(_, if_true, if_false) => vec![if_true, if_false],
+        _ => Vec::new(),
+    }
+}
+
+/// Binding written in the configuration.
+fn binding(opcode: &Opcode) -> Option<(InIdx, Trigger, Bound)> {
+    Some(match *opcode {
+        Opcode::BindShortCall(in_idx, proc) => (in_idx, Trigger::ShortClick, Bound::Call(proc)),
+        Opcode::BindLongCall(in_idx, proc) => (in_idx, Trigger::LongClick, Bound::Call(proc)),
+        Opcode::BindActivateCall(in_idx, proc) => (in_idx, Trigger::Activated, Bound::Call(proc)),
+        Opcode::BindDeactivateCall(in_idx, proc) => {
+            (in_idx, Trigger::Deactivated, Bound::Call(proc))
+        }
+        Opcode::BindLongActivate(in_idx, proc) => {
+            (in_idx, Trigger::LongActivated, Bound::Call(proc))
+        }
+        Opcode::BindLongDeactivate(in_idx, proc) => {
+            (in_idx, Trigger::LongDeactivated, Bound::Call(proc))
+        }
+        Opcode::BindShortToggle(in_idx, out_idx) => {
+            (in_idx, Trigger::ShortClick, Bound::Toggle(out_idx))
+        }
+        Opcode::BindLongToggle(in_idx, out_idx) => {
+            (in_idx, Trigger::LongClick, Bound::Toggle(out_idx))
+        }
+        _ => return None,
+    })
+}
+
+/// What a binding does.
+#[derive(Clone, Copy)]
+enum Bound {
+    Call(ProcIdx),
+    Toggle(OutIdx),
+}
+
+/// Opcode generated by the compiler for the setup.
+fn is_setup(opcode: &Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::LayerSet(_) | Opcode::LayerDefault | Opcode::BindLayerHold(_, _)
+    ) || matches!(opcode, Opcode::ZoneAssign(_, zone) if *zone != 0)
+        || binding(opcode).is_some()
+}
+
+/// Length of the setup at the start of procedure 0. It has to end on the
+/// default layer.
+fn setup_len(body: &[Opcode]) -> usize {
+    let mut layer = 0;
+    let mut len = 0;
+    for (idx, opcode) in body.iter().enumerate() {
+        if !is_setup(opcode) {
+            break;
+        }
+        match *opcode {
+            Opcode::LayerSet(set) => layer = set,
+            Opcode::LayerDefault => layer = 0,
+            _ => {}
+        }
+        if layer == 0 {
+            len = idx + 1;
+        }
+    }
+    len
+}
+
+struct Decompiler<'a> {
+    names: &'a NameTable,
+    /// Procedure bodies with the address of their first opcode.
+    bodies: BTreeMap<ProcIdx, (usize, &'a [Opcode])>,
+    /// Number of references to each procedure.
+    references: [usize; PROCEDURE_IDS],
+    inlined: Vec<ProcIdx>,
+    inputs: Vec<InIdx>,
+    outputs: Vec<OutIdx>,
+}
+
+impl<'a> Decompiler<'a> {
+    fn new(program: &'a [Opcode], names: &'a NameTable) -> Self {
+        let mut bodies = BTreeMap::new();
+        let mut references = [0; PROCEDURE_IDS];
+        let mut start = None;
+        for (pc, opcode) in program.iter().enumerate() {
+            match *opcode {
+                Opcode::Start(proc) => start = Some((proc, pc + 1)),
+                Opcode::Stop => {
+                    if let Some((proc, first)) = start.take() {
+                        bodies.insert(proc, (first, &program[first..pc]));
+                    }
+                }
+                _ => {
+                    for proc in procedures(opcode) {
+                        references[proc as usize] += 1;
+                    }
+                }
+            }
+        }
+        Self {
+            names,
+            bodies,
+            references,
+            inlined: Vec::new(),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+        }
+    }
+
+    fn input(&mut self, in_idx: InIdx) -> Name {
+        if !self.inputs.contains(&in_idx) {
+            self.inputs.push(in_idx);
+        }
+        let known = lookup(&self.names.inputs, in_idx);
+        name(known.map_or_else(|| format!("in{}", in_idx), str::to_string))
+    }
+
+    fn output(&mut self, out_idx: OutIdx) -> Name {
+        if !self.outputs.contains(&out_idx) {
+            self.outputs.push(out_idx);
+        }
+        let known = lookup(&self.names.outputs, out_idx);
+        name(known.map_or_else(|| format!("out{}", out_idx), str::to_string))
+    }
+
+    fn procedure(&self, proc: ProcIdx) -> Name {
+        name(match lookup(&self.names.procedures, proc) {
+            Some(known) => known.to_string(),
+            None if proc == 0 => "main".to_string(),
+            None => format!("proc{}", proc),
+        })
+    }
+
+    fn zone(&self, zone: ZoneIdx) -> Name {
+        let known = lookup(&self.names.zones, zone);
+        name(known.map_or_else(|| format!("zone{}", zone), str::to_string))
+    }
+
+    /// Body as actions, if every action kind is used in a single run.
+    fn actions(&mut self, body: &[Opcode]) -> Option<Vec<ActionDef>> {
+        // Don't record outputs used only by rejected actions.
+        let used = self.outputs.len();
+        let actions = self.try_actions(body);
+        if actions.is_none() {
+            self.outputs.truncate(used);
+        }
+        actions
+    }
+
+    fn try_actions(&mut self, body: &[Opcode]) -> Option<Vec<ActionDef>> {
+        let mut actions: Vec<ActionDef> = Vec::new();
+        for opcode in body {
+            let (kind, target) = match *opcode {
+                Opcode::Toggle(out_idx) => (ActionKind::Toggle, self.output(out_idx)),
+                Opcode::Activate(out_idx) => (ActionKind::Activate, self.output(out_idx)),
+                Opcode::Deactivate(out_idx) => (ActionKind::Deactivate, self.output(out_idx)),
+                Opcode::Call(proc) => (ActionKind::Call, self.procedure(proc)),
+                _ => return None,
+            };
+            if let Some(last) = actions.last_mut().filter(|last| last.kind == kind) {
+                last.targets.push(target);
+            } else if actions.iter().any(|action| action.kind == kind) {
+                return None;
+            } else {
+                actions.push(ActionDef {
+                    kind,
+                    targets: vec![target],
+                    mark: Mark::default(),
+                });
+            }
+        }
+        Some(actions)
+    }
+
+    /// Body as actions or opcodes. `first` is the address of its first opcode.
+    fn steps(&mut self, first: usize, body: &[Opcode]) -> Vec<Step> {
+        if let Some(actions) = self.actions(body) {
+            return actions.into_iter().map(Step::Action).collect();
+        }
+        body.iter()
+            .enumerate()
+            .map(|(idx, opcode)| Step::Opcode(raw_opcode(first + idx, opcode)))
+            .collect()
+    }
+
+    /// Actions of a trigger, inlining a procedure bound only here.
+    fn trigger_actions(&mut self, bound: Bound) -> Vec<ActionDef> {
+        let proc = match bound {
+            Bound::Toggle(out_idx) => {
+                return vec![ActionDef {
+                    kind: ActionKind::Toggle,
+                    targets: vec![self.output(out_idx)],
+                    mark: Mark::default(),
+                }];
+            }
+            Bound::Call(proc) => proc,
+        };
+        let inline = proc != 0
+            && self.references[proc as usize] == 1
+            && lookup(&self.names.procedures, proc).is_none();
+        if inline {
+            let (_, body) = self.bodies[&proc];
+            if let Some(actions) = self.actions(body).filter(|a| !a.is_empty()) {
+                self.inlined.push(proc);
+                return actions;
+            }
+        }
+        vec![ActionDef {
+            kind: ActionKind::Call,
+            targets: vec![self.procedure(proc)],
+            mark: Mark::default(),
+        }]
+    }
+
+    fn setup(&mut self, setup: &[Opcode], device: &mut Device) {
+        let mut zones: Vec<(InIdx, ZoneIdx)> = Vec::new();
+        // Bindings by the input and the layer.
+        let mut bindings: Vec<((InIdx, LayerIdx), BindingDef)> = Vec::new();
+        let mut layer = 0;
+        for opcode in setup {
+            let (in_idx, hold, trigger) = match *opcode {
+                Opcode::LayerSet(set) => {
+                    layer = set;
+                    continue;
+                }
+                Opcode::LayerDefault => {
+                    layer = 0;
+                    continue;
+                }
+                Opcode::ZoneAssign(in_idx, zone) => {
+                    zones.retain(|(known, _)| *known != in_idx);
+                    zones.push((in_idx, zone));
+                    continue;
+                }
+                Opcode::BindLayerHold(in_idx, hold) => (in_idx, Some(hold), None),
+                _ => match binding(opcode) {
+                    Some((in_idx, trigger, bound)) => (in_idx, None, Some((trigger, bound))),
+                    None => continue,
+                },
+            };
+            let pos = match bindings.iter().position(|(key, _)| *key == (in_idx, layer)) {
+                Some(pos) => pos,
+                None => {
+                    let def = BindingDef {
+                        mark: Mark::default(),
+                        input: self.input(in_idx),
+                        layer: (layer != 0).then_some(LayerRef {
+                            layer,
+                            mark: Mark::default(),
+                        }),
+                        triggers: Vec::new(),
+                        hold: None,
+                    };
+                    bindings.push(((in_idx, layer), def));
+                    bindings.len() - 1
+                }
+            };
+            if let Some(hold) = hold {
+                bindings[pos].1.hold = Some(LayerRef {
+                    layer: hold,
+                    mark: Mark::default(),
+                });
+            }
+            if let Some((trigger, bound)) = trigger {
+                let actions = self.trigger_actions(bound);
+                let triggers = &mut bindings[pos].1.triggers;
+                // Binding again replaces the previous one.
+                triggers.retain(|def| def.trigger != trigger);
+                triggers.push(TriggerDef {
+                    trigger,
+                    mark: Mark::default(),
+                    actions,
+                });
+            }
+        }
+
+        // Zones are numbered by their position.
+        let count = zones.iter().map(|(_, zone)| *zone).max().unwrap_or(0);
+        for zone in 1..=count {
+            let mut inputs = Vec::new();
+            for &(in_idx, _) in zones.iter().filter(|(_, known)| *known == zone) {
+                inputs.push(self.input(in_idx));
+            }
+            device.zones.push(Zone {
+                name: self.zone(zone),
+                inputs,
+            });
+        }
+        device.bindings = bindings.into_iter().map(|(_, def)| def).collect();
+    }
+
+    fn decompile(mut self, device_name: &str) -> Device {
+        let mut device = Device {
+            name: Some(name(device_name.to_string())),
+            ..Device::default()
+        };
+        let (first, main) = self.bodies.get(&0).copied().unwrap_or((0, &[]));
+        let setup = setup_len(main);
+        self.setup(&main[..setup], &mut device);
+
+        for (proc, (start, body)) in self.bodies.clone() {
+            let (start, body) = match proc {
+                0 => (first + setup, &main[setup..]),
+                _ => (start, body),
+            };
+            let unused = proc == 0 && body.is_empty() && self.references[0] == 0;
+            if self.inlined.contains(&proc) || unused {
+                continue;
+            }
+            let steps = self.steps(start, body);
+            device.procedures.push(ProcedureDef {
+                name: self.procedure(proc),
+                id: Some((proc, Mark::default())),
+                steps,
+            });
+        }
+
+        device.inputs = self.ios(&self.names.inputs, &self.inputs, "in");
+        device.outputs = self.ios(&self.names.outputs, &self.outputs, "out");
+        device
+    }
+
+    /// Named IOs and the used unnamed ones, by their indices.
+    fn ios(&self, names: &[(String, u8)], used: &[u8], prefix: &str) -> Vec<Io> {
+        let mut ios: Vec<Io> = names
+            .iter()
+            .map(|(value, idx)| Io {
+                name: name(value.clone()),
+                idx: *idx,
+                idx_mark: Mark::default(),
+            })
+            .collect();
+        for &idx in used {
+            if lookup(names, idx).is_none() {
+                ios.push(Io {
+                    name: name(format!("{}{}", prefix, idx)),
+                    idx,
+                    idx_mark: Mark::default(),
+                });
+            }
+        }
+        ios.sort_by_key(|io| io.idx);
+        ios
+    }
+}
+
+/// Offset of an absolute jump from its address, if it fits a relative jump.
+fn jump_offset(pc: usize, target: u16) -> Result<i8, Diagnostic> {
+    let offset = target as isize - pc as isize;
+    i8::try_from(offset).map_err(|_| Diagnostic::new(pc, Problem::JumpTooFar(offset)))
+}
+
+/// Opcode written by its name. Absolute jumps become relative ones, because
+/// the layout of the recompiled program can differ.
+fn raw_opcode(pc: usize, opcode: &Opcode) -> RawOpcode {
+    let opcode = match *opcode {
+        Opcode::JumpTo(target) => {
+            Opcode::Jump(jump_offset(pc, target).expect("jumps are checked by decompile"))
+        }
+        opcode => opcode,
+    };
+    // Debug format matches the names the compiler accepts.
+    let text = format!("{:?}", opcode);
+    let (opcode_name, args) = match text.split_once('(') {
+        Some((opcode_name, args)) => (opcode_name, args.trim_end_matches(')')),
+        None => (text.as_str(), ""),
+    };
+    RawOpcode {
+        name: name(opcode_name.to_string()),
+        args: args
+            .split(", ")
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| name(arg.to_string()))
+            .collect(),
+    }
+}
+
+/// Reconstruct the configuration of a device from its program.
+pub fn decompile(
+    device: &str,
+    program: &[Opcode],
+    names: Option<&NameTable>,
+) -> Result<Device, Diagnostic> {
+    if let Some(diagnostic) = verifier::verify(program).into_iter().next() {
+        return Err(diagnostic);
+    }
+    for (pc, opcode) in program.iter().enumerate() {
+        if let Opcode::JumpTo(target) = *opcode {
+            jump_offset(pc, target)?;
+        }
+    }
+    let empty = NameTable::default();
+    let decompiler = Decompiler::new(program, names.unwrap_or(&empty));
+    Ok(decompiler.decompile(device))
+}
+
+/// Scalar quoted unless it's a plain identifier or a number.
+fn scalar(value: &str) -> String {
+    let plain = value
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-.".contains(c))
+        && !matches!(value, "null" | "true" | "false");
+    if plain {
+        value.to_string()
+    } else {
+        format!("{:?}", value)
+    }
+}
+
+fn number(value: &str) -> String {
+    if value.parse::<i64>().is_ok() {
+        value.to_string()
+    } else {
+        scalar(value)
+    }
+}
+
+/// Single name or a flow list of names.
+fn names(names: &[Name]) -> String {
+    match names {
+        [single] => scalar(&single.value),
+        _ => {
+            let names: Vec<_> = names.iter().map(|name| scalar(&name.value)).collect();
+            format!("[{}]", names.join(", "))
+        }
+    }
+}
+
+fn actions(out: &mut String, indent: &str, actions: &[ActionDef]) {
+    for action in actions {
+        let (_, key) = ACTIONS
+            .iter()
+            .find(|(kind, _)| *kind == action.kind)
+            .expect("all actions are named");
+        let _ = writeln!(out, "{}{}: {}", indent, key, names(&action.targets));
+    }
+}
+
+/// Write a device configuration in the `code.yaml` format.
+pub fn to_yaml(device: &Device) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "{}:", scalar(device.name()));
+    if let Some((address, _)) = device.address {
+        let _ = writeln!(out, "  address: {}", address);
+    }
+    if !device.expanders.is_empty() {
+        out.push_str("  expanders:\n");
+        for expander in &device.expanders {
+            let _ = writeln!(out, "    - addr: {:?}", expander.addr.value);
+            let _ = writeln!(
+                out,
+                "      direction: {}",
+                scalar(&expander.direction.value)
+            );
+            if let Some(chip) = &expander.chip {
+                let _ = writeln!(out, "      chip: {}", scalar(&chip.value));
+            }
+            let pins: Vec<_> = expander.pins.iter().map(|pin| number(&pin.value)).collect();
+            let _ = writeln!(out, "      pins: [{}]", pins.join(", "));
+        }
+    }
+    for (section, ios) in [("inputs", &device.inputs), ("outputs", &device.outputs)] {
+        if !ios.is_empty() {
+            let _ = writeln!(out, "  {}:", section);
+            for io in ios {
+                let _ = writeln!(out, "    - {}: {}", scalar(&io.name.value), io.idx);
+            }
+        }
+    }
+    if !device.zones.is_empty() {
+        out.push_str("  zones:\n");
+        for zone in &device.zones {
+            let inputs: Vec<_> = zone.inputs.iter().map(|name| scalar(&name.value)).collect();
+            let _ = writeln!(
+                out,
+                "    {}: [{}]",
+                scalar(&zone.name.value),
+                inputs.join(", ")
+            );
+        }
+    }
+    if !device.bindings.is_empty() {
+        out.push_str("  bindings:\n");
+        for binding in &device.bindings {
+            let _ = writeln!(out, "    - input: {}", scalar(&binding.input.value));
+            if let Some(layer) = binding.layer {
+                let _ = writeln!(out, "      layer: {}", layer.layer);
+            }
+            if let Some(hold) = binding.hold {
+                let _ = writeln!(out, "      hold: {}", hold.layer);
+            }
+            for def in &binding.triggers {
+                let (_, key) = TRIGGERS
+                    .iter()
+                    .find(|(trigger, _)| *trigger == def.trigger)
+                    .expect("all triggers are named");
+                let _ = writeln!(out, "      {}:", key);
+                actions(&mut out, "        ", &def.actions);
+            }
+        }
+    }
+    if !device.procedures.is_empty() {
+        out.push_str("  procedures:\n");
+        for procedure in &device.procedures {
+            let _ = writeln!(out, "    {}:", scalar(&procedure.name.value));
+            if let Some((id, _)) = procedure.id {
+                let _ = writeln!(out, "      id: {}", id);
+            }
+            let mut opcodes = Vec::new();
+            for step in &procedure.steps {
+                match step {
+                    Step::Action(action) => {
+                        actions(&mut out, "      ", std::slice::from_ref(action))
+                    }
+                    Step::Opcode(raw) => opcodes.push(raw),
+                }
+            }
+            if !opcodes.is_empty() {
+                out.push_str("      opcodes:\n");
+                for raw in opcodes {
+                    let _ = match raw.args.as_slice() {
+                        [] => writeln!(out, "        - {}", scalar(&raw.name.value)),
+                        args => {
+                            let args: Vec<_> = args.iter().map(|arg| number(&arg.value)).collect();
+                            writeln!(
+                                out,
+                                "        - {}: [{}]",
+                                scalar(&raw.name.value),
+                                args.join(", ")
+                            )
+                        }
+                    };
+                }
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{compile_device, compile_str};
+    use crate::config::Config;
+    use crate::consts::OutputState;
+
+    const SOURCE: &str = "
+hall:
+  inputs:
+    - door: 1
+    - stairs: 2
+  outputs:
+    - ceiling: 10
+    - lamp: 11
+  zones:
+    upstairs: [stairs]
+  bindings:
+    - input: door
+      short:
+        toggle: ceiling
+      long:
+        call: all-off
+    - input: stairs
+      hold: 1
+      activate:
+        activate: [ceiling, lamp]
+    - input: stairs
+      layer: 1
+      short:
+        toggle: [lamp]
+        call: all-off
+  procedures:
+    all-off:
+      deactivate: [ceiling, lamp]
+    main:
+      id: 0
+      opcodes:
+        - SetRemote: [1, 5, Toggle]
+";
+
+    #[test]
+    fn it_decompiles_with_names() {
+        let config = Config::parse(SOURCE).unwrap();
+        let program = compile_device(&config.devices[0]).unwrap();
+        let names = NameTable::new(&config.devices[0], &program);
+        let device = decompile("hall", &program.opcodes, Some(&names)).unwrap();
+        let yaml = to_yaml(&device);
+        assert_eq!(
+            yaml,
+            "\
+hall:
+  inputs:
+    - door: 1
+    - stairs: 2
+  outputs:
+    - ceiling: 10
+    - lamp: 11
+  zones:
+    upstairs: [stairs]
+  bindings:
+    - input: door
+      short:
+        toggle: ceiling
+      long:
+        call: all-off
+    - input: stairs
+      hold: 1
+      activate:
+        activate: [ceiling, lamp]
+    - input: stairs
+      layer: 1
+      short:
+        toggle: lamp
+        call: all-off
+  procedures:
+    main:
+      id: 0
+      opcodes:
+        - SetRemote: [1, 5, Toggle]
+    all-off:
+      id: 1
+      deactivate: [ceiling, lamp]
+"
+        );
+
+        // Recompiled program is the same.
+        let recompiled = compile_str(&yaml).unwrap();
+        assert_eq!(recompiled[0].opcodes, program.opcodes);
+    }
+
+    #[test]
+    fn it_decompiles_without_names() {
+        let program = [
+            Opcode::Start(0),
+            Opcode::ZoneAssign(3, 2),
+            Opcode::BindShortToggle(3, 4),
+            Opcode::BindLongCall(3, 5),
+            Opcode::LayerSet(2),
+            Opcode::BindShortCall(3, 5),
+            Opcode::Stop,
+            Opcode::Start(5),
+            Opcode::SetRemote(1, 2, OutputState::On),
+            Opcode::JumpTo(10),
+            Opcode::Stop,
+        ];
+        let device = decompile("dev", &program, None).unwrap();
+        let yaml = to_yaml(&device);
+        assert_eq!(
+            yaml,
+            "\
+dev:
+  inputs:
+    - in3: 3
+  outputs:
+    - out4: 4
+  zones:
+    zone1: []
+    zone2: [in3]
+  bindings:
+    - input: in3
+      short:
+        toggle: out4
+      long:
+        call: proc5
+  procedures:
+    main:
+      id: 0
+      opcodes:
+        - LayerSet: [2]
+        - BindShortCall: [3, 5]
+    proc5:
+      id: 5
+      opcodes:
+        - SetRemote: [1, 2, On]
+        - Jump: [1]
+"
+        );
+        assert!(compile_str(&yaml).is_ok());
+
+        let err = decompile("dev", &program[..3], None).unwrap_err();
+        assert_eq!(err.pc, 0);
+
+        // Absolute jump can't stay absolute, the procedure may move.
+        let mut program = vec![Opcode::Start(0), Opcode::Stop, Opcode::Start(1)];
+        program.push(Opcode::JumpTo(203));
+        program.extend([Opcode::Noop; 200]);
+        program.push(Opcode::Stop);
+        assert_eq!(
+            decompile("dev", &program, None).unwrap_err(),
+            Diagnostic::new(3, Problem::JumpTooFar(200))
+        );
+    }
+}
@@ -0,0 +1,625 @@
+/*
+ * Semantic diff of programs. Both programs are decompiled, so bindings are
+ * compared per input, layer and trigger and procedures by their names, eg.
+ * "kitchen1_r long click: call all-off -> toggle island". Indices of the
+ * named IOs and zones and the device address are compared too. A device
+ * whose compiled program differs in a way none of these describe is still
+ * reported, as "compiled program changed".
+ */
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::compiler::{self, DeviceProgram, Subscription};
+use crate::config::{ActionDef, Config, ConfigError, Device, Io, Step, ACTIONS, TRIGGERS};
+use crate::consts::{DevAddr, InIdx, LayerIdx, OutIdx, ProcIdx, Trigger, ZoneIdx};
+use crate::decompiler::{self, NameTable};
+use crate::opcodes::Opcode;
+use crate::verifier::Diagnostic;
+
+/// Single difference between two programs. `None` is a missing item.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Change {
+    /// Device got another CAN address.
+    Address {
+        old: Option<DevAddr>,
+        new: Option<DevAddr>,
+    },
+    /// Input moved to another index.
+    Input {
+        name: String,
+        old: Option<InIdx>,
+        new: Option<InIdx>,
+    },
+    /// Output moved to another index.
+    Output {
+        name: String,
+        old: Option<OutIdx>,
+        new: Option<OutIdx>,
+    },
+    /// Zone got another number, `ZoneSelect` of the old one won't reach it.
+    ZoneId {
+        name: String,
+        old: ZoneIdx,
+        new: ZoneIdx,
+    },
+    Binding {
+        input: String,
+        layer: LayerIdx,
+        trigger: Trigger,
+        old: Option<String>,
+        new: Option<String>,
+    },
+    /// Layer activated while the input is held.
+    Hold {
+        input: String,
+        layer: LayerIdx,
+        old: Option<LayerIdx>,
+        new: Option<LayerIdx>,
+    },
+    Zone {
+        input: String,
+        old: Option<String>,
+        new: Option<String>,
+    },
+    Procedure {
+        name: String,
+        old: Option<String>,
+        new: Option<String>,
+    },
+    /// Procedure got another ID, remote calls of the old one won't reach it.
+    ProcedureId {
+        name: String,
+        old: ProcIdx,
+        new: ProcIdx,
+    },
+    /// Compiled program differs, but none of the changes above describes it.
+    Program,
+}
+
+fn or<T: ToString>(value: &Option<T>, missing: &str) -> String {
+    value
+        .as_ref()
+        .map_or_else(|| missing.to_string(), ToString::to_string)
+}
+
+fn on_layer(input: &str, layer: LayerIdx) -> String {
+    match layer {
+        0 => input.to_string(),
+        _ => format!("{} layer {}", input, layer),
+    }
+}
+
+fn trigger_name(trigger: Trigger) -> &'static str {
+    match trigger {
+        Trigger::ShortClick => "short click",
+        Trigger::LongClick => "long click",
+        Trigger::Activated => "activate",
+        Trigger::Deactivated => "deactivate",
+        Trigger::LongActivated => "long activate",
+        Trigger::LongDeactivated => "long deactivate",
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Address { old, new } => {
+                write!(f, "address: {} -> {}", or(old, "none"), or(new, "none"))
+            }
+            Change::Input { name, old, new } => write!(
+                f,
+                "input {} index: {} -> {}",
+                name,
+                or(old, "none"),
+                or(new, "none")
+            ),
+            Change::Output { name, old, new } => write!(
+                f,
+                "output {} index: {} -> {}",
+                name,
+                or(old, "none"),
+                or(new, "none")
+            ),
+            Change::ZoneId { name, old, new } => {
+                write!(f, "zone {} number: {} -> {}", name, old, new)
+            }
+            Change::Binding {
+                input,
+                layer,
+                trigger,
+                old,
+                new,
+            } => write!(
+                f,
+                "{} {}: {} -> {}",
+                on_layer(input, *layer),
+                trigger_name(*trigger),
+                or(old, "unbound"),
+                or(new, "unbound")
+            ),
+            Change::Hold {
+                input,
+                layer,
+                old,
+                new,
+            } => {
+                let layer_name = |hold: &Option<LayerIdx>| {
+                    hold.map_or_else(|| "none".to_string(), |hold| format!("layer {}", hold))
+                };
+                write!(
+                    f,
+                    "{} hold: {} -> {}",
+                    on_layer(input, *layer),
+                    layer_name(old),
+                    layer_name(new)
+                )
+            }
+            Change::Zone { input, old, new } => write!(
+                f,
+                "{} zone: {} -> {}",
+                input,
+                or(old, "default"),
+                or(new, "default")
+            ),
+            Change::Procedure { name, old, new } => write!(
+                f,
+                "procedure {}: {} -> {}",
+                name,
+                or(old, "undefined"),
+                or(new, "undefined")
+            ),
+            Change::ProcedureId { name, old, new } => {
+                write!(f, "procedure {} ID: {} -> {}", name, old, new)
+            }
+            Change::Program => write!(f, "compiled program changed"),
+        }
+    }
+}
+
+/// Differences of a single device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DeviceDiff {
+    pub device: String,
+    pub changes: Vec<Change>,
+}
+
+fn describe_action(action: &ActionDef) -> String {
+    let (_, kind) = ACTIONS
+        .iter()
+        .find(|(kind, _)| *kind == action.kind)
+        .expect("all actions are named");
+    match action.targets.as_slice() {
+        [target] => format!("{} {}", kind, target.value),
+        targets => {
+            let targets: Vec<_> = targets.iter().map(|t| t.value.as_str()).collect();
+            format!("{} [{}]", kind, targets.join(", "))
+        }
+    }
+}
+
+fn describe(steps: &[Step]) -> String {
+    if steps.is_empty() {
+        return "empty".to_string();
+    }
+    let steps: Vec<String> = steps
+        .iter()
+        .map(|step| match step {
+            Step::Action(action) => describe_action(action),
+            Step::Opcode(raw) if raw.args.is_empty() => raw.name.value.clone(),
+            Step::Opcode(raw) => {
+                let args: Vec<_> = raw.args.iter().map(|arg| arg.value.as_str()).collect();
+                format!("{}({})", raw.name.value, args.join(", "))
+            }
+        })
+        .collect();
+    steps.join("; ")
+}
+
+/// Position of the trigger, to order bindings.
+fn trigger_order(trigger: Trigger) -> usize {
+    TRIGGERS
+        .iter()
+        .position(|(known, _)| *known == trigger)
+        .unwrap_or_default()
+}
+
+/// What a decompiled device does, keyed for comparison.
+#[derive(Default)]
+struct Summary {
+    address: Option<DevAddr>,
+    inputs: BTreeMap<String, InIdx>,
+    outputs: BTreeMap<String, OutIdx>,
+    zone_ids: BTreeMap<String, ZoneIdx>,
+    bindings: BTreeMap<(String, LayerIdx, usize), (Trigger, String)>,
+    holds: BTreeMap<(String, LayerIdx), LayerIdx>,
+    zones: BTreeMap<String, String>,
+    procedures: BTreeMap<String, (Option<ProcIdx>, String)>,
+}
+
+impl Summary {
+    fn new(device: &Device) -> Self {
+        let ios = |ios: &[Io]| {
+            ios.iter()
+                .map(|io| (io.name.value.clone(), io.idx))
+                .collect()
+        };
+        let mut summary = Summary {
+            address: device.address.map(|(address, _)| address),
+            inputs: ios(&device.inputs),
+            outputs: ios(&device.outputs),
+            ..Summary::default()
+        };
+        for binding in &device.bindings {
+            let input = &binding.input.value;
+            if let Some(hold) = binding.hold {
+                summary
+                    .holds
+                    .insert((input.clone(), binding.layer()), hold.layer);
+            }
+            for def in &binding.triggers {
+                let actions: Vec<_> = def.actions.iter().map(describe_action).collect();
+                let key = (input.clone(), binding.layer(), trigger_order(def.trigger));
+                summary
+                    .bindings
+                    .insert(key, (def.trigger, actions.join("; ")));
+            }
+        }
+        for (idx, zone) in device.zones.iter().enumerate() {
+            // Zone 0 is the default one.
+            summary
+                .zone_ids
+                .insert(zone.name.value.clone(), idx as ZoneIdx + 1);
+            for input in &zone.inputs {
+                summary
+                    .zones
+                    .insert(input.value.clone(), zone.name.value.clone());
+            }
+        }
+        for procedure in &device.procedures {
+            let id = procedure.id.map(|(id, _)| id);
+            summary.procedures.insert(
+                procedure.name.value.clone(),
+                (id, describe(&procedure.steps)),
+            );
+        }
+        summary
+    }
+}
+
+/// Pairs of values for keys present in any of the maps, in the key order.
+fn pairs<'a, K: Ord + Clone, V>(
+    old: &'a BTreeMap<K, V>,
+    new: &'a BTreeMap<K, V>,
+) -> Vec<(K, Option<&'a V>, Option<&'a V>)> {
+    let mut keys: Vec<&K> = old.keys().chain(new.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    keys.into_iter()
+        .map(|key| (key.clone(), old.get(key), new.get(key)))
+        .collect()
+}
+
+/// Differences between two decompiled devices.
+pub fn diff_devices(old: &Device, new: &Device) -> Vec<Change> {
+    let (old, new) = (Summary::new(old), Summary::new(new));
+    let mut changes = Vec::new();
+
+    if old.address != new.address {
+        changes.push(Change::Address {
+            old: old.address,
+            new: new.address,
+        });
+    }
+    for (name, old, new) in pairs(&old.inputs, &new.inputs) {
+        if old != new {
+            changes.push(Change::Input {
+                name,
+                old: old.copied(),
+                new: new.copied(),
+            });
+        }
+    }
+    for (name, old, new) in pairs(&old.outputs, &new.outputs) {
+        if old != new {
+            changes.push(Change::Output {
+                name,
+                old: old.copied(),
+                new: new.copied(),
+            });
+        }
+    }
+    // Added and removed zones show up as moved inputs.
+    for (name, old, new) in pairs(&old.zone_ids, &new.zone_ids) {
+        if let (Some(&old), Some(&new)) = (old, new) {
+            if old != new {
+                changes.push(Change::ZoneId { name, old, new });
+            }
+        }
+    }
+
+    for (input, old, new) in pairs(&old.zones, &new.zones) {
+        if old != new {
+            changes.push(Change::Zone {
+                input,
+                old: old.cloned(),
+                new: new.cloned(),
+            });
+        }
+    }
+    for ((input, layer), old, new) in pairs(&old.holds, &new.holds) {
+        if old != new {
+            changes.push(Change::Hold {
+                input,
+                layer,
+                old: old.copied(),
+                new: new.copied(),
+            });
+        }
+    }
+    for ((input, layer, _), old, new) in pairs(&old.bindings, &new.bindings) {
+        let Some((trigger, _)) = old.or(new) else {
+            continue;
+        };
+        let (old, new) = (old.map(|(_, a)| a), new.map(|(_, a)| a));
+        if old != new {
+            changes.push(Change::Binding {
+                input,
+                layer,
+                trigger: *trigger,
+                old: old.cloned(),
+                new: new.cloned(),
+            });
+        }
+    }
+    for (name, old, new) in pairs(&old.procedures, &new.procedures) {
+        if let (Some((Some(old), _)), Some((Some(new), _))) = (old, new) {
+            if old != new {
+                changes.push(Change::ProcedureId {
+                    name: name.clone(),
+                    old: *old,
+                    new: *new,
+                });
+            }
+        }
+        let (old, new) = (old.map(|(_, body)| body), new.map(|(_, body)| body));
+        if old != new {
+            changes.push(Change::Procedure {
+                name,
+                old: old.cloned(),
+                new: new.cloned(),
+            });
+        }
+    }
+    changes
+}
+
+/// Differences between two programs of a device.
+pub fn diff_programs(
+    old: &[Opcode],
+    old_names: Option<&NameTable>,
+    new: &[Opcode],
+    new_names: Option<&NameTable>,
+) -> Result<Vec<Change>, Diagnostic> {
+    let old_device = decompiler::decompile("", old, old_names)?;
+    let new_device = decompiler::decompile("", new, new_names)?;
+    let mut changes = diff_devices(&old_device, &new_device);
+    if changes.is_empty() && old != new {
+        changes.push(Change::Program);
+    }
+    Ok(changes)
+}
+
+/// Configuration of `diff_configs` which failed to compile.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Side {
+    Old,
+    New,
+}
+
+/// Decompiled devices of a configuration with their programs, by their names.
+fn decompile_config(
+    config: &Config,
+) -> Result<BTreeMap<String, (Device, DeviceProgram)>, ConfigError> {
+    let mut devices = BTreeMap::new();
+    for (device, program) in config.devices.iter().zip(compiler::compile(config)?) {
+        let names = NameTable::new(device, &program);
+        let mut decompiled = decompiler::decompile(device.name(), &program.opcodes, Some(&names))
+            .expect("compiled program passes the verifier");
+        // Address is not a part of the program.
+        decompiled.address = device.address;
+        devices.insert(device.name().to_string(), (decompiled, program));
+    }
+    Ok(devices)
+}
+
+/// What is stored in the bytecode file. Subscriptions are a part of it too.
+fn bytecode(program: Option<&DeviceProgram>) -> Option<(&[Opcode], &[Subscription])> {
+    program.map(|program| (&program.opcodes[..], &program.subscriptions[..]))
+}
+
+/// Differences between two configurations, for each changed device. Added
+/// and removed devices are compared with an empty one.
+pub fn diff_configs(old: &Config, new: &Config) -> Result<Vec<DeviceDiff>, (Side, ConfigError)> {
+    let old = decompile_config(old).map_err(|err| (Side::Old, err))?;
+    let new = decompile_config(new).map_err(|err| (Side::New, err))?;
+    let empty = Device::default();
+    let mut diffs = Vec::new();
+    for (device, old, new) in pairs(&old, &new) {
+        let (old_device, old_program) = old.map_or((&empty, None), |(d, p)| (d, Some(p)));
+        let (new_device, new_program) = new.map_or((&empty, None), |(d, p)| (d, Some(p)));
+        let mut changes = diff_devices(old_device, new_device);
+        if changes.is_empty() && bytecode(old_program) != bytecode(new_program) {
+            changes.push(Change::Program);
+        }
+        if !changes.is_empty() {
+            diffs.push(DeviceDiff { device, changes });
+        }
+    }
+    Ok(diffs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD: &str = "
+livingroom:
+  inputs:
+    - kitchen1_r: 1
+    - kitchen2_r: 3
+  outputs:
+    - main_kitchen: 1
+    - island: 2
+  zones:
+    kitchen: [kitchen1_r]
+  bindings:
+    - input: kitchen1_r
+      short: {toggle: main_kitchen}
+      long: {call: all-off}
+    - input: kitchen2_r
+      hold: 1
+  procedures:
+    all-off:
+      deactivate: [main_kitchen, island]
+";
+
+    const NEW: &str = "
+livingroom:
+  inputs:
+    - kitchen1_r: 1
+    - kitchen2_r: 3
+  outputs:
+    - main_kitchen: 1
+    - island: 2
+  zones:
+    kitchen: [kitchen1_r, kitchen2_r]
+  bindings:
+    - input: kitchen1_r
+      short: {toggle: main_kitchen}
+      long: {toggle: island}
+    - input: kitchen2_r
+      short: {call: all-off}
+    - input: kitchen2_r
+      layer: 1
+      short: {toggle: [main_kitchen, island]}
+  procedures:
+    all-off:
+      deactivate: island
+fusebox:
+  outputs:
+    - corridor: 3
+";
+
+    #[test]
+    fn it_reports_changed_bindings_and_procedures() {
+        let old = Config::parse(OLD).unwrap();
+        let new = Config::parse(NEW).unwrap();
+        let diffs = diff_configs(&old, &new).unwrap();
+        // Added device is compared with an empty one.
+        assert_eq!(diffs[0].device, "fusebox");
+        assert_eq!(
+            diffs[0].changes,
+            vec![Change::Output {
+                name: "corridor".to_string(),
+                old: None,
+                new: Some(3)
+            }]
+        );
+        let lines: Vec<String> = diffs[1].changes.iter().map(ToString::to_string).collect();
+        assert_eq!(diffs[1].device, "livingroom");
+        assert_eq!(
+            lines,
+            vec![
+                "kitchen2_r zone: default -> kitchen",
+                "kitchen2_r hold: layer 1 -> none",
+                "kitchen1_r long click: call all-off -> toggle island",
+                "kitchen2_r short click: unbound -> call all-off",
+                "kitchen2_r layer 1 short click: unbound -> toggle [main_kitchen, island]",
+                "procedure all-off: deactivate [main_kitchen, island] -> deactivate island",
+            ]
+        );
+        assert_eq!(diffs.len(), 2);
+
+        assert!(diff_configs(&old, &old).unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_reports_changed_indices() {
+        let old = Config::parse(OLD).unwrap();
+        let changed = |from: &str, to: &str| {
+            let new = Config::parse(&OLD.replacen(from, to, 1)).unwrap();
+            let diffs = diff_configs(&old, &new).unwrap();
+            assert_eq!(diffs.len(), 1);
+            let lines: Vec<String> = diffs[0].changes.iter().map(ToString::to_string).collect();
+            lines
+        };
+        assert_eq!(
+            changed("island: 2", "island: 7"),
+            ["output island index: 2 -> 7"]
+        );
+        assert_eq!(
+            changed("kitchen2_r: 3", "kitchen2_r: 5"),
+            ["input kitchen2_r index: 3 -> 5"]
+        );
+        assert_eq!(
+            changed("livingroom:\n", "livingroom:\n  address: 4\n"),
+            ["address: none -> 4"]
+        );
+        assert_eq!(
+            changed("zones:\n", "zones:\n    hall: []\n"),
+            ["zone kitchen number: 1 -> 2"]
+        );
+
+        // Programs differing in nothing the summary describes.
+        let program = [Opcode::Start(0), Opcode::LayerDefault, Opcode::Stop];
+        let padded = [
+            Opcode::Noop,
+            Opcode::Start(0),
+            Opcode::LayerDefault,
+            Opcode::Stop,
+        ];
+        let changes = diff_programs(&program, None, &padded, None).unwrap();
+        assert_eq!(changes, [Change::Program]);
+        assert!(diff_programs(&program, None, &program, None)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn it_compares_programs() {
+        let old = [
+            Opcode::Start(0),
+            Opcode::BindShortCall(1, 2),
+            Opcode::Stop,
+            Opcode::Start(2),
+            Opcode::Call(3),
+            Opcode::Stop,
+            Opcode::Start(3),
+            Opcode::Wait(100),
+            Opcode::Stop,
+        ];
+        let mut new = old;
+        new[7] = Opcode::Wait(200);
+        let changes = diff_programs(&old, None, &new, None).unwrap();
+        assert_eq!(
+            changes,
+            vec![Change::Procedure {
+                name: "proc3".to_string(),
+                old: Some("Wait(100)".to_string()),
+                new: Some("Wait(200)".to_string()),
+            }]
+        );
+
+        new[1] = Opcode::BindLongCall(1, 2);
+        let changes = diff_programs(&old, None, &new, None).unwrap();
+        assert_eq!(
+            changes[0].to_string(),
+            "in1 short click: call proc3 -> unbound"
+        );
+        assert_eq!(
+            changes[1].to_string(),
+            "in1 long click: unbound -> call proc3"
+        );
+    }
+}
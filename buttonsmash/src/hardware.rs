@@ -0,0 +1,555 @@
+/*
+ * Hardware profile: which expander pin is which logical input or output.
+ *
+ * Boards use PCF8574 (8 pins) and PCF8575 (16 pins) I2C expanders. Address
+ * pins A2A1A0 select the address within 0x20..=0x27; in the configuration the
+ * address is written as these three bits ("001") or as a full address
+ * ("0x21"). The exported boot table is:
+ *
+ *   magic "BSIO" (4), entry count (u16 little-endian),
+ *   entries: I2C address, expander pin, direction (0 in, 1 out), IO index,
+ *   CRC32 of all preceding bytes (4, little-endian).
+ */
+
+use core::fmt;
+
+use crate::bytecode::crc32;
+use crate::config::{Device, ExpanderDef, Mark, Name};
+use crate::consts::{MAX_INPUTS, MAX_OUTPUTS};
+
+/// Base address of the PCF857x expanders.
+pub const BASE_ADDRESS: u8 = 0x20;
+pub const BOOT_TABLE_MAGIC: &[u8; 4] = b"BSIO";
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Chip {
+    Pcf8574,
+    Pcf8575,
+}
+
+impl Chip {
+    pub fn pins(self) -> usize {
+        match self {
+            Chip::Pcf8574 => 8,
+            Chip::Pcf8575 => 16,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Direction {
+    Input,
+    Output,
+}
+
+/// Capabilities of the controller board.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Board {
+    /// Expanders that fit on the I2C bus.
+    pub expanders: usize,
+    pub inputs: usize,
+    pub outputs: usize,
+}
+
+impl Default for Board {
+    fn default() -> Self {
+        Self {
+            expanders: 8,
+            inputs: MAX_INPUTS,
+            outputs: MAX_OUTPUTS,
+        }
+    }
+}
+
+/// Expander with logical IO indices of its pins.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Expander {
+    pub chip: Chip,
+    /// I2C address.
+    pub addr: u8,
+    pub direction: Direction,
+    pub pins: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum HardwareErrorKind {
+    InvalidAddress(String),
+    DuplicateAddress(u8),
+    UnknownChip(String),
+    InvalidDirection(String),
+    InvalidIndex(String),
+    /// Expander has more pins listed than the chip has.
+    TooManyPins {
+        chip: Chip,
+        pins: usize,
+    },
+    TooManyExpanders(usize),
+    /// IO index is beyond the board capabilities.
+    IndexOutOfRange(u8),
+    /// IO index is assigned to two pins.
+    DuplicateIndex(u8),
+    /// Named IO is not connected to any expander pin.
+    UnmappedInput(String),
+    UnmappedOutput(String),
+}
+
+/// Hardware profile problem with the source position.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HardwareError {
+    pub mark: Mark,
+    pub kind: HardwareErrorKind,
+}
+
+impl fmt::Display for HardwareErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HardwareErrorKind::InvalidAddress(addr) => write!(f, "invalid address `{}`", addr),
+            HardwareErrorKind::DuplicateAddress(addr) => {
+                write!(f, "address {:#04x} used twice", addr)
+            }
+            HardwareErrorKind::UnknownChip(chip) => write!(f, "unknown chip `{}`", chip),
+            HardwareErrorKind::InvalidDirection(dir) => {
+                write!(f, "invalid direction `{}`, expected `in` or `out`", dir)
+            }
+            HardwareErrorKind::InvalidIndex(idx) => write!(f, "invalid IO index `{}`", idx),
+            HardwareErrorKind::TooManyPins { chip, pins } => {
+                write!(f, "{:?} has {} pins, {} listed", chip, chip.pins(), pins)
+            }
+            HardwareErrorKind::TooManyExpanders(max) => {
+                write!(f, "board supports up to {} expanders", max)
+            }
+            HardwareErrorKind::IndexOutOfRange(idx) => write!(f, "IO index {} out of range", idx),
+            HardwareErrorKind::DuplicateIndex(idx) => {
+                write!(f, "IO index {} assigned to two pins", idx)
+            }
+            HardwareErrorKind::UnmappedInput(name) => {
+                write!(f, "input `{}` is not connected to an expander pin", name)
+            }
+            HardwareErrorKind::UnmappedOutput(name) => {
+                write!(f, "output `{}` is not connected to an expander pin", name)
+            }
+        }
+    }
+}
+
+impl fmt::Display for HardwareError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.mark, self.kind)
+    }
+}
+
+/// Single pin mapping of the boot table.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct BootEntry {
+    pub addr: u8,
+    pub pin: u8,
+    pub direction: Direction,
+    /// Logical InIdx or OutIdx.
+    pub idx: u8,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BootTableError {
+    Truncated,
+    BadMagic,
+    BadChecksum,
+    InvalidDirection,
+}
+
+/// Pin mapping read by the firmware at boot.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BootTable {
+    pub entries: Vec<BootEntry>,
+}
+
+impl BootTable {
+    pub fn input(&self, addr: u8, pin: u8) -> Option<u8> {
+        self.find(Direction::Input, addr, pin)
+    }
+
+    pub fn output(&self, addr: u8, pin: u8) -> Option<u8> {
+        self.find(Direction::Output, addr, pin)
+    }
+
+    fn find(&self, direction: Direction, addr: u8, pin: u8) -> Option<u8> {
+        self.entries
+            .iter()
+            .find(|e| e.direction == direction && e.addr == addr && e.pin == pin)
+            .map(|e| e.idx)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = BOOT_TABLE_MAGIC.to_vec();
+        // Profile validation keeps it within MAX_INPUTS + MAX_OUTPUTS.
+        bytes.extend_from_slice(&(self.entries.len() as u16).to_le_bytes());
+        for entry in &self.entries {
+            let direction = match entry.direction {
+                Direction::Input => 0,
+                Direction::Output => 1,
+            };
+            bytes.extend_from_slice(&[entry.addr, entry.pin, direction, entry.idx]);
+        }
+        let crc = crc32(&bytes);
+        bytes.extend_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BootTableError> {
+        if bytes.len() < BOOT_TABLE_MAGIC.len() + 2 + 4 {
+            return Err(BootTableError::Truncated);
+        }
+        if &bytes[..4] != BOOT_TABLE_MAGIC {
+            return Err(BootTableError::BadMagic);
+        }
+        let count = u16::from_le_bytes([bytes[4], bytes[5]]) as usize;
+        let end = 6 + count * 4;
+        if bytes.len() != end + 4 {
+            return Err(BootTableError::Truncated);
+        }
+        let crc = u32::from_le_bytes([bytes[end], bytes[end + 1], bytes[end + 2], bytes[end + 3]]);
+        if crc != crc32(&bytes[..end]) {
+            return Err(BootTableError::BadChecksum);
+        }
+        let entries = bytes[6..end]
+            .chunks(4)
+            .map(|entry| {
+                let direction = match entry[2] {
+                    0 => Direction::Input,
+                    1 => Direction::Output,
+                    _ => return Err(BootTableError::InvalidDirection),
+                };
+                Ok(BootEntry {
+                    addr: entry[0],
+                    pin: entry[1],
+                    direction,
+                    idx: entry[3],
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { entries })
+    }
+}
+
+/// Expanders of a device, validated against the board.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Profile {
+    pub expanders: Vec<Expander>,
+}
+
+/// I2C address from "A2A1A0" bits or a hex address.
+fn parse_address(addr: &str) -> Option<u8> {
+    if let Some(hex) = addr.strip_prefix("0x") {
+        let addr = u8::from_str_radix(hex, 16).ok()?;
+        return (BASE_ADDRESS..BASE_ADDRESS + 8)
+            .contains(&addr)
+            .then_some(addr);
+    }
+    if addr.len() != 3 {
+        return None;
+    }
+    u8::from_str_radix(addr, 2)
+        .ok()
+        .map(|bits| BASE_ADDRESS | bits)
+}
+
+/// Collects errors while building a profile.
+struct Builder {
+    board: Board,
+    errors: Vec<HardwareError>,
+}
+
+impl Builder {
+    fn error(&mut self, mark: Mark, kind: HardwareErrorKind) {
+        self.errors.push(HardwareError { mark, kind });
+    }
+
+    /// Expander and its parsed pin indices with their positions. Invalid
+    /// indices are reported and left out.
+    fn expander(&mut self, def: &ExpanderDef) -> Option<(Expander, Vec<(u8, Mark)>)> {
+        let addr = parse_address(&def.addr.value);
+        if addr.is_none() {
+            let kind = HardwareErrorKind::InvalidAddress(def.addr.value.clone());
+            self.error(def.addr.mark, kind);
+        }
+        let direction = match def.direction.value.as_str() {
+            "in" => Some(Direction::Input),
+            "out" => Some(Direction::Output),
+            other => {
+                let kind = HardwareErrorKind::InvalidDirection(other.to_string());
+                self.error(def.direction.mark, kind);
+                None
+            }
+        };
+        let chip = match &def.chip {
+            Some(name) => match name.value.to_lowercase().as_str() {
+                "pcf8574" => Some(Chip::Pcf8574),
+                "pcf8575" => Some(Chip::Pcf8575),
+                _ => {
+                    self.error(
+                        name.mark,
+                        HardwareErrorKind::UnknownChip(name.value.clone()),
+                    );
+                    None
+                }
+            },
+            // Without a chip, pick the smallest one with enough pins.
+            None if def.pins.len() <= Chip::Pcf8574.pins() => Some(Chip::Pcf8574),
+            None => Some(Chip::Pcf8575),
+        };
+        if let Some(chip) = chip {
+            if def.pins.len() > chip.pins() {
+                let pins = def.pins.len();
+                self.error(def.mark, HardwareErrorKind::TooManyPins { chip, pins });
+            }
+        }
+
+        let mut pins = Vec::new();
+        for pin in &def.pins {
+            match pin.value.parse() {
+                Ok(idx) => pins.push((idx, pin.mark)),
+                Err(_) => {
+                    let kind = HardwareErrorKind::InvalidIndex(pin.value.clone());
+                    self.error(pin.mark, kind);
+                }
+            }
+        }
+        let expander = Expander {
+            chip: chip?,
+            addr: addr?,
+            direction: direction?,
+            pins: pins.iter().map(|(idx, _)| *idx).collect(),
+        };
+        Some((expander, pins))
+    }
+
+    /// Check indices of one direction are in range and not reused.
+    fn check_indices(&mut self, pins: &[(Direction, Vec<(u8, Mark)>)], direction: Direction) {
+        let limit = match direction {
+            Direction::Input => self.board.inputs,
+            Direction::Output => self.board.outputs,
+        };
+        let mut seen = Vec::new();
+        for (_, pins) in pins.iter().filter(|(dir, _)| *dir == direction) {
+            for &(idx, mark) in pins {
+                if idx as usize >= limit {
+                    self.error(mark, HardwareErrorKind::IndexOutOfRange(idx));
+                } else if seen.contains(&idx) {
+                    self.error(mark, HardwareErrorKind::DuplicateIndex(idx));
+                }
+                seen.push(idx);
+            }
+        }
+    }
+}
+
+impl Profile {
+    /// Build the profile from the `expanders` of a device. All problems are
+    /// returned, including named IOs which are not connected to any pin.
+    pub fn from_device(device: &Device, board: Board) -> Result<Self, Vec<HardwareError>> {
+        let mut builder = Builder {
+            board,
+            errors: Vec::new(),
+        };
+        let defs = &device.expanders;
+        if defs.len() > board.expanders {
+            builder.error(
+                defs[board.expanders].mark,
+                HardwareErrorKind::TooManyExpanders(board.expanders),
+            );
+        }
+
+        let mut expanders: Vec<Expander> = Vec::new();
+        let mut pins = Vec::new();
+        for def in defs {
+            let Some((expander, marked)) = builder.expander(def) else {
+                continue;
+            };
+            if expanders.iter().any(|other| other.addr == expander.addr) {
+                builder.error(
+                    def.addr.mark,
+                    HardwareErrorKind::DuplicateAddress(expander.addr),
+                );
+            }
+            pins.push((expander.direction, marked));
+            expanders.push(expander);
+        }
+        builder.check_indices(&pins, Direction::Input);
+        builder.check_indices(&pins, Direction::Output);
+
+        let profile = Self { expanders };
+        let connected = |direction, idx| {
+            profile
+                .expanders
+                .iter()
+                .any(|e| e.direction == direction && e.pins.contains(&idx))
+        };
+        for io in &device.inputs {
+            if !connected(Direction::Input, io.idx) {
+                builder.error(io.name.mark, unmapped(&io.name, Direction::Input));
+            }
+        }
+        for io in &device.outputs {
+            if !connected(Direction::Output, io.idx) {
+                builder.error(io.name.mark, unmapped(&io.name, Direction::Output));
+            }
+        }
+
+        if builder.errors.is_empty() {
+            Ok(profile)
+        } else {
+            builder.errors.sort_by_key(|err| err.mark);
+            Err(builder.errors)
+        }
+    }
+
+    /// Table of all connected pins, ordered by direction and IO index.
+    pub fn boot_table(&self) -> BootTable {
+        let mut entries: Vec<BootEntry> = self
+            .expanders
+            .iter()
+            .flat_map(|expander| {
+                expander
+                    .pins
+                    .iter()
+                    .enumerate()
+                    .map(|(pin, &idx)| BootEntry {
+                        addr: expander.addr,
+                        pin: pin as u8,
+                        direction: expander.direction,
+                        idx,
+                    })
+            })
+            .collect();
+        entries.sort_by_key(|entry| (entry.direction, entry.idx));
+        BootTable { entries }
+    }
+}
+
+fn unmapped(name: &Name, direction: Direction) -> HardwareErrorKind {
+    match direction {
+        Direction::Input => HardwareErrorKind::UnmappedInput(name.value.clone()),
+        Direction::Output => HardwareErrorKind::UnmappedOutput(name.value.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    const SOURCE: &str = "
+hall:
+  expanders:
+    - addr: \"000\"
+      direction: in
+      pins: [1, 2, 3]
+    - addr: \"0x21\"
+      direction: out
+      chip: PCF8575
+      pins: [10, 11]
+  inputs: {door: 1, stairs: 3}
+  outputs: {ceiling: 11}
+";
+
+    #[test]
+    fn it_maps_pins_to_indices() {
+        let config = Config::parse(SOURCE).unwrap();
+        let profile = Profile::from_device(&config.devices[0], Board::default()).unwrap();
+        assert_eq!(profile.expanders[0].chip, Chip::Pcf8574);
+        assert_eq!(profile.expanders[0].addr, 0x20);
+        assert_eq!(profile.expanders[1].chip, Chip::Pcf8575);
+
+        let table = profile.boot_table();
+        assert_eq!(table.entries.len(), 5);
+        assert_eq!(table.input(0x20, 2), Some(3));
+        assert_eq!(table.output(0x21, 1), Some(11));
+        assert_eq!(table.output(0x20, 1), None);
+
+        let bytes = table.to_bytes();
+        assert_eq!(&bytes[..6], b"BSIO\x05\x00");
+        assert_eq!(&bytes[6..10], &[0x20, 0, 0, 1]);
+        assert_eq!(BootTable::from_bytes(&bytes), Ok(table));
+
+        let mut corrupted = bytes.clone();
+        corrupted[7] ^= 1;
+        assert_eq!(
+            BootTable::from_bytes(&corrupted),
+            Err(BootTableError::BadChecksum)
+        );
+        assert_eq!(
+            BootTable::from_bytes(&bytes[..8]),
+            Err(BootTableError::Truncated)
+        );
+    }
+
+    #[test]
+    fn it_validates_against_board() {
+        let source = "
+dev:
+  expanders:
+    - {addr: \"001\", direction: in, pins: [0, 1, 1]}
+    - {addr: \"001\", direction: sideways, pins: []}
+    - {addr: \"0x40\", direction: out, chip: pcf8574, pins: [0, 1, 2, 3, 4, 5, 6, 7, 8]}
+  outputs: {lamp: 9}
+";
+        let config = Config::parse(source).unwrap();
+        let board = Board {
+            expanders: 2,
+            inputs: 1,
+            outputs: 16,
+        };
+        let errors = Profile::from_device(&config.devices[0], board).unwrap_err();
+        let kinds: Vec<_> = errors.iter().map(|err| err.kind.clone()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                HardwareErrorKind::IndexOutOfRange(1),
+                HardwareErrorKind::IndexOutOfRange(1),
+                HardwareErrorKind::InvalidDirection("sideways".to_string()),
+                HardwareErrorKind::TooManyExpanders(2),
+                HardwareErrorKind::TooManyPins {
+                    chip: Chip::Pcf8574,
+                    pins: 9
+                },
+                HardwareErrorKind::InvalidAddress("0x40".to_string()),
+                HardwareErrorKind::UnmappedOutput("lamp".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_reports_errors_at_their_pins() {
+        let source = "
+dev:
+  expanders:
+    - {addr: \"001\", direction: in, pins: [x, 2, 2]}
+";
+        let config = Config::parse(source).unwrap();
+        let errors = Profile::from_device(&config.devices[0], Board::default()).unwrap_err();
+        let found: Vec<_> = errors.iter().map(|err| err.to_string()).collect();
+        assert_eq!(
+            found,
+            vec![
+                "4:43: invalid IO index `x`",
+                "4:49: IO index 2 assigned to two pins",
+            ]
+        );
+    }
+
+    #[test]
+    fn it_reports_unmapped_example_outputs() {
+        // Outputs of the example are on pins 16-31, but named as 0 and 1.
+        let config = Config::parse(include_str!("../code.yaml")).unwrap();
+        let living = config.device("livingroom").unwrap();
+        let errors = Profile::from_device(living, Board::default()).unwrap_err();
+        assert_eq!(errors.len(), living.outputs.len());
+        assert_eq!(
+            errors[0].kind,
+            HardwareErrorKind::UnmappedOutput("main_kitchen".to_string())
+        );
+        assert_eq!(
+            errors[0].to_string(),
+            "31:7: output `main_kitchen` is not connected to an expander pin"
+        );
+    }
+}
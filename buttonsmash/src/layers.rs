@@ -1,21 +1,62 @@
-use crate::consts::{InIdx, LayerIdx, MAX_LAYER_STACK};
+use crate::consts::{InIdx, LayerIdx, ZoneIdx, MAX_INPUTS, MAX_LAYER_STACK, MAX_ZONES};
 
-pub struct Layers {
+/// What to do when a layer is activated while the stack is full.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum OverflowPolicy {
+    /// Keep the stack as is and ignore the new layer.
+    #[default]
+    RejectNew,
+    /// Forget the oldest activation to make room for the new one.
+    DropOldest,
+    /// Replace the most recent activation with the new one.
+    ReplaceTop,
+}
+
+/// Layer stack was full when activating a layer. Stack was handled according
+/// to the `OverflowPolicy`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct LayerStackOverflow;
+
+/// Input was not assigned to a zone.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AssignError {
+    InputOutOfRange,
+    ZoneOutOfRange,
+}
+
+#[derive(Copy, Clone)]
+pub struct Layers<const DEPTH: usize = MAX_LAYER_STACK> {
     /// Currently active layer.
     pub current: LayerIdx,
     /// Mapping between layers and buttons that activated them. Used to
     /// deactivate layers in a correct order.
-    stack: [Option<(InIdx, LayerIdx)>; MAX_LAYER_STACK],
+    stack: [Option<(InIdx, LayerIdx)>; DEPTH],
+    policy: OverflowPolicy,
+}
+
+impl<const DEPTH: usize> Default for Layers<DEPTH> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl Layers {
+impl<const DEPTH: usize> Layers<DEPTH> {
     pub fn new() -> Self {
+        Self::with_policy(OverflowPolicy::default())
+    }
+
+    pub fn with_policy(policy: OverflowPolicy) -> Self {
         Self {
             current: 0,
-            stack: [None; MAX_LAYER_STACK],
+            stack: [None; DEPTH],
+            policy,
         }
     }
 
+    pub fn set_policy(&mut self, policy: OverflowPolicy) {
+        self.policy = policy;
+    }
+
     /// Reset state to layer 0 with no stack.
     pub fn reset(&mut self) {
         self.current = 0;
@@ -24,11 +65,31 @@ impl Layers {
         }
     }
 
-    /// Activate layer and store slot entry.
-    pub fn activate(&mut self, in_idx: InIdx, layer: LayerIdx) {
-        let slot_idx = self.find_slot();
-        self.stack[slot_idx] = Some((in_idx, layer));
-        self.current = layer;
+    /// Activate layer and store slot entry. On a full stack the overflow
+    /// policy is applied and an error is returned, so it can be reported.
+    pub fn activate(&mut self, in_idx: InIdx, layer: LayerIdx) -> Result<(), LayerStackOverflow> {
+        if let Some(slot_idx) = self.find_slot() {
+            self.stack[slot_idx] = Some((in_idx, layer));
+            self.current = layer;
+            return Ok(());
+        }
+
+        if DEPTH == 0 {
+            return Err(LayerStackOverflow);
+        }
+        match self.policy {
+            OverflowPolicy::RejectNew => {}
+            OverflowPolicy::DropOldest => {
+                self.drop_slot(0);
+                self.stack[DEPTH - 1] = Some((in_idx, layer));
+                self.current = layer;
+            }
+            OverflowPolicy::ReplaceTop => {
+                self.stack[DEPTH - 1] = Some((in_idx, layer));
+                self.current = layer;
+            }
+        }
+        Err(LayerStackOverflow)
     }
 
     /// Scan stack for activations using this input key and if one is found -
@@ -48,13 +109,12 @@ impl Layers {
         }
         if let Some(slot_idx) = found {
             self.drop_slot(slot_idx);
-            if slot_idx == 0 {
-                self.current = 0;
-            } else {
-                // Return back to previous layer
-                let previous_layer_idx = self.stack[slot_idx - 1].expect("This must be Some").1;
-                self.current = previous_layer_idx;
-            }
+            // Return back to the layer on top of the stack.
+            self.current = match self.find_slot() {
+                Some(0) => 0,
+                Some(free_idx) => self.stack[free_idx - 1].expect("This must be Some").1,
+                None => self.stack[DEPTH - 1].expect("This must be Some").1,
+            };
             true
         } else {
             false
@@ -62,25 +122,148 @@ impl Layers {
     }
 
     /// Find and return index to a first free slot.
-    fn find_slot(&self) -> usize {
-        for i in 0..MAX_LAYER_STACK {
-            if self.stack[i].is_none() {
-                return i;
-            }
-        }
-        panic!("Layer depth reached.");
+    fn find_slot(&self) -> Option<usize> {
+        self.stack.iter().position(|entry| entry.is_none())
     }
 
     /// Drop slot of given index and shift the rest (if any) to fill the gap.
     fn drop_slot(&mut self, slot_idx: usize) {
         assert!(self.stack[slot_idx].is_some());
         self.stack[slot_idx] = None;
-        for i in slot_idx + 1..MAX_LAYER_STACK {
+        for i in slot_idx + 1..DEPTH {
             if self.stack[i].is_none() {
                 return;
             }
             self.stack[i - 1] = self.stack[i];
+            self.stack[i] = None;
         }
     }
+}
+
+/// Independent layer stacks for groups of inputs (zones). Layer held by a
+/// button in one zone doesn't change the behaviour of buttons in other zones.
+/// Inputs belong to zone 0 unless assigned otherwise.
+pub struct Zones<const DEPTH: usize = MAX_LAYER_STACK> {
+    zones: [Layers<DEPTH>; MAX_ZONES],
+    /// Zone of each input.
+    membership: [ZoneIdx; MAX_INPUTS],
+}
+
+impl<const DEPTH: usize> Default for Zones<DEPTH> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
+impl<const DEPTH: usize> Zones<DEPTH> {
+    pub fn new() -> Self {
+        Self {
+            zones: [Layers::new(); MAX_ZONES],
+            membership: [0; MAX_INPUTS],
+        }
+    }
+
+    /// Set overflow policy of all the zones.
+    pub fn set_policy(&mut self, policy: OverflowPolicy) {
+        for layers in self.zones.iter_mut() {
+            layers.set_policy(policy);
+        }
+    }
+
+    /// Reset all zones to layer 0 with no stack. Membership is kept.
+    pub fn reset(&mut self) {
+        for layers in self.zones.iter_mut() {
+            layers.reset();
+        }
+    }
+
+    /// Move all inputs back to zone 0.
+    pub fn clear_membership(&mut self) {
+        self.membership = [0; MAX_INPUTS];
+    }
+
+    /// Make input a member of a zone.
+    pub fn assign(&mut self, in_idx: InIdx, zone: ZoneIdx) -> Result<(), AssignError> {
+        if zone as usize >= MAX_ZONES {
+            return Err(AssignError::ZoneOutOfRange);
+        }
+        let member = self
+            .membership
+            .get_mut(in_idx as usize)
+            .ok_or(AssignError::InputOutOfRange)?;
+        *member = zone;
+        Ok(())
+    }
+
+    /// Zone the input belongs to.
+    pub fn zone_of(&self, in_idx: InIdx) -> ZoneIdx {
+        self.membership.get(in_idx as usize).copied().unwrap_or(0)
+    }
+
+    /// Layer stack of a zone.
+    pub fn zone(&self, zone: ZoneIdx) -> &Layers<DEPTH> {
+        &self.zones[zone as usize]
+    }
+
+    pub fn zone_mut(&mut self, zone: ZoneIdx) -> &mut Layers<DEPTH> {
+        &mut self.zones[zone as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filled(policy: OverflowPolicy) -> Layers<2> {
+        let mut layers: Layers<2> = Layers::with_policy(policy);
+        assert_eq!(layers.activate(1, 10), Ok(()));
+        assert_eq!(layers.activate(2, 20), Ok(()));
+        assert_eq!(layers.activate(3, 30), Err(LayerStackOverflow));
+        layers
+    }
+
+    #[test]
+    fn it_rejects_new_on_overflow() {
+        let mut layers = filled(OverflowPolicy::RejectNew);
+        assert_eq!(layers.current, 20);
+        assert!(!layers.maybe_deactivate(3));
+        assert!(layers.maybe_deactivate(2));
+        assert_eq!(layers.current, 10);
+    }
+
+    #[test]
+    fn it_drops_oldest_on_overflow() {
+        let mut layers = filled(OverflowPolicy::DropOldest);
+        assert_eq!(layers.current, 30);
+        assert!(!layers.maybe_deactivate(1));
+        assert!(layers.maybe_deactivate(3));
+        assert_eq!(layers.current, 20);
+        assert!(layers.maybe_deactivate(2));
+        assert_eq!(layers.current, 0);
+    }
+
+    #[test]
+    fn it_replaces_top_on_overflow() {
+        let mut layers = filled(OverflowPolicy::ReplaceTop);
+        assert_eq!(layers.current, 30);
+        assert!(!layers.maybe_deactivate(2));
+        assert!(layers.maybe_deactivate(3));
+        assert_eq!(layers.current, 10);
+    }
+
+    #[test]
+    fn it_assigns_inputs_to_zones() {
+        let mut zones: Zones = Zones::new();
+        assert_eq!(zones.assign(5, 2), Ok(()));
+        assert_eq!(zones.zone_of(5), 2);
+        assert_eq!(
+            zones.assign(5, MAX_ZONES as ZoneIdx),
+            Err(AssignError::ZoneOutOfRange)
+        );
+        assert_eq!(
+            zones.assign(MAX_INPUTS as InIdx, 1),
+            Err(AssignError::InputOutOfRange)
+        );
+        assert_eq!(zones.zone_of(5), 2);
+    }
 }
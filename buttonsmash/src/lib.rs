@@ -1,5 +1,18 @@
 pub mod consts;
+pub mod assembler;
 pub mod bindings;
+pub mod bytecode;
+pub mod codegen;
+pub mod compiler;
+pub mod config;
+pub mod decompiler;
+pub mod diff;
+pub mod hardware;
 pub mod layers;
+pub mod lint;
 pub mod opcodes;
 pub mod microvm;
+pub mod syscalls;
+pub mod timers;
+pub mod threads;
+pub mod verifier;
@@ -0,0 +1,735 @@
+/*
+ * Checks of the device configuration which don't stop the compilation but
+ * usually point at a mistake: pins used twice, undefined names, unused IOs,
+ * bindings overwritten by later ones and layers out of range or never
+ * activated. Devices with expanders also get their hardware profile checked.
+ * Finally, devices are compiled, so anything the compiler rejects is an error
+ * here too.
+ */
+
+use core::fmt;
+use std::collections::HashMap;
+
+use crate::compiler::{self, raw_opcode};
+use crate::config::{ActionKind, Config, ConfigErrorKind, Device, Io, Mark, Name, Step};
+use crate::consts::{InIdx, LayerIdx, OutIdx, Trigger, MAX_INPUTS, MAX_LAYERS, MAX_OUTPUTS};
+use crate::hardware::{Board, HardwareErrorKind, Profile};
+use crate::opcodes::Opcode;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LintKind {
+    /// Configuration can't be parsed.
+    Config(ConfigErrorKind),
+    /// Input uses the same pin as the named one.
+    DuplicateInputPin {
+        name: String,
+        other: String,
+        idx: InIdx,
+    },
+    /// Output uses the same pin as the named one.
+    DuplicateOutputPin {
+        name: String,
+        other: String,
+        idx: OutIdx,
+    },
+    /// Input uses the reserved index 0.
+    ReservedInput(String),
+    /// IO index is above the executor limits.
+    IndexOutOfRange {
+        name: String,
+        idx: u8,
+    },
+    UnknownInput(String),
+    UnknownOutput(String),
+    UnknownProcedure(String),
+    UnusedInput(String),
+    UnusedOutput(String),
+    /// Binding on the same (input, layer, trigger) was already defined on
+    /// the given line; the later one wins.
+    ShadowedBinding {
+        input: String,
+        layer: LayerIdx,
+        trigger: Trigger,
+        line: usize,
+    },
+    LayerOutOfRange(LayerIdx),
+    /// Bindings are defined on a layer which is never activated.
+    UnreachableLayer(LayerIdx),
+    /// Expanders don't match the board.
+    Hardware(HardwareErrorKind),
+}
+
+/// Problem found in a configuration file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Lint {
+    pub file: String,
+    pub mark: Mark,
+    pub severity: Severity,
+    pub kind: LintKind,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl fmt::Display for LintKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LintKind::Config(kind) => write!(f, "{}", kind),
+            LintKind::DuplicateInputPin { name, other, idx } => {
+                write!(f, "input `{}` uses pin {} of input `{}`", name, idx, other)
+            }
+            LintKind::DuplicateOutputPin { name, other, idx } => {
+                write!(
+                    f,
+                    "output `{}` uses pin {} of output `{}`",
+                    name, idx, other
+                )
+            }
+            LintKind::ReservedInput(name) => write!(f, "input `{}` uses reserved index 0", name),
+            LintKind::IndexOutOfRange { name, idx } => {
+                write!(f, "index {} of `{}` is out of range", idx, name)
+            }
+            LintKind::UnknownInput(name) => write!(f, "unknown input `{}`", name),
+            LintKind::UnknownOutput(name) => write!(f, "unknown output `{}`", name),
+            LintKind::UnknownProcedure(name) => write!(f, "unknown procedure `{}`", name),
+            LintKind::UnusedInput(name) => write!(f, "input `{}` is never used", name),
+            LintKind::UnusedOutput(name) => write!(f, "output `{}` is never used", name),
+            LintKind::ShadowedBinding {
+                input,
+                layer,
+                trigger,
+                line,
+            } => write!(
+                f,
+                "binding of `{}` on layer {} ({:?}) overwrites the one on line {}",
+                input, layer, trigger, line
+            ),
+            LintKind::LayerOutOfRange(layer) => {
+                write!(f, "layer {} out of range (max {})", layer, MAX_LAYERS - 1)
+            }
+            LintKind::UnreachableLayer(layer) => write!(f, "layer {} is never activated", layer),
+            LintKind::Hardware(kind) => write!(f, "{}", kind),
+        }
+    }
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}: {}",
+            self.file, self.mark, self.severity, self.kind
+        )
+    }
+}
+
+/// Collects lints of a single device.
+struct Linter<'a> {
+    file: &'a str,
+    config: &'a Config,
+    device: &'a Device,
+    lints: Vec<Lint>,
+    used_inputs: Vec<InIdx>,
+    used_outputs: Vec<OutIdx>,
+    /// Layers activated by holds and raw opcodes.
+    activated_layers: Vec<LayerIdx>,
+}
+
+impl<'a> Linter<'a> {
+    fn report(&mut self, mark: Mark, severity: Severity, kind: LintKind) {
+        self.lints.push(Lint {
+            file: self.file.to_string(),
+            mark,
+            severity,
+            kind,
+        });
+    }
+
+    fn duplicate_pins(&mut self) {
+        let mut inputs: HashMap<InIdx, &Io> = HashMap::new();
+        for io in &self.device.inputs {
+            if let Some(other) = inputs.get(&io.idx) {
+                let kind = LintKind::DuplicateInputPin {
+                    name: io.name.value.clone(),
+                    other: other.name.value.clone(),
+                    idx: io.idx,
+                };
+                self.report(io.idx_mark, Severity::Error, kind);
+            } else {
+                inputs.insert(io.idx, io);
+            }
+        }
+        let mut outputs: HashMap<OutIdx, &Io> = HashMap::new();
+        for io in &self.device.outputs {
+            if let Some(other) = outputs.get(&io.idx) {
+                let kind = LintKind::DuplicateOutputPin {
+                    name: io.name.value.clone(),
+                    other: other.name.value.clone(),
+                    idx: io.idx,
+                };
+                self.report(io.idx_mark, Severity::Error, kind);
+            } else {
+                outputs.insert(io.idx, io);
+            }
+        }
+    }
+
+    /// Input 0 is reserved. Indices of expander pins are checked by the
+    /// hardware profile.
+    fn indices(&mut self) {
+        let device = self.device;
+        for io in &device.inputs {
+            if io.idx == 0 {
+                let kind = LintKind::ReservedInput(io.name.value.clone());
+                self.report(io.idx_mark, Severity::Error, kind);
+            }
+        }
+        if !device.expanders.is_empty() {
+            return;
+        }
+        let inputs = device.inputs.iter().map(|io| (io, MAX_INPUTS));
+        let outputs = device.outputs.iter().map(|io| (io, MAX_OUTPUTS));
+        for (io, limit) in inputs.chain(outputs) {
+            if io.idx as usize >= limit {
+                let kind = LintKind::IndexOutOfRange {
+                    name: io.name.value.clone(),
+                    idx: io.idx,
+                };
+                self.report(io.idx_mark, Severity::Error, kind);
+            }
+        }
+    }
+
+    fn input(&mut self, name: &Name) {
+        match find(&self.device.inputs, name) {
+            Some(idx) => self.used_inputs.push(idx),
+            None => {
+                let kind = LintKind::UnknownInput(name.value.clone());
+                self.report(name.mark, Severity::Error, kind);
+            }
+        }
+    }
+
+    fn output(&mut self, name: &Name) {
+        match find(&self.device.outputs, name) {
+            Some(idx) => self.used_outputs.push(idx),
+            None => {
+                if let Some(kind) = self.remote_output(name) {
+                    self.report(name.mark, Severity::Error, kind);
+                }
+            }
+        }
+    }
+
+    /// Problem with a `device.output` name, if any.
+    fn remote_output(&self, name: &Name) -> Option<LintKind> {
+        let unknown = LintKind::UnknownOutput(name.value.clone());
+        let Some((device, output)) = name.remote() else {
+            return Some(unknown);
+        };
+        let Some(remote) = self.config.device(device) else {
+            let kind = ConfigErrorKind::UnknownDevice(device.to_string());
+            return Some(LintKind::Config(kind));
+        };
+        if remote.address.is_none() {
+            let kind = ConfigErrorKind::MissingAddress(device.to_string());
+            return Some(LintKind::Config(kind));
+        }
+        if !remote.outputs.iter().any(|io| io.name.value == output) {
+            return Some(unknown);
+        }
+        if self.device.address.is_none() {
+            let kind = ConfigErrorKind::MissingAddress(self.device.name().to_string());
+            return Some(LintKind::Config(kind));
+        }
+        None
+    }
+
+    fn procedure(&mut self, name: &Name) {
+        let device = self.device;
+        if !device.procedures.iter().any(|p| p.name.value == name.value) {
+            let kind = LintKind::UnknownProcedure(name.value.clone());
+            self.report(name.mark, Severity::Error, kind);
+        }
+    }
+
+    fn action(&mut self, kind: ActionKind, targets: &[Name]) {
+        for target in targets {
+            match kind {
+                ActionKind::Call => self.procedure(target),
+                _ => self.output(target),
+            }
+        }
+    }
+
+    fn layer(&mut self, layer: LayerIdx, mark: Mark) {
+        if layer as usize >= MAX_LAYERS {
+            self.report(mark, Severity::Error, LintKind::LayerOutOfRange(layer));
+        }
+    }
+
+    /// Mark IOs and layers used by a raw opcode.
+    fn raw(&mut self, opcode: &Opcode) {
+        match *opcode {
+            Opcode::Toggle(out_idx)
+            | Opcode::Activate(out_idx)
+            | Opcode::Deactivate(out_idx)
+            | Opcode::ActivateFor(out_idx, _)
+            | Opcode::ReadOutput(_, out_idx) => self.used_outputs.push(out_idx),
+            Opcode::BindShortToggle(in_idx, out_idx) | Opcode::BindLongToggle(in_idx, out_idx) => {
+                self.used_inputs.push(in_idx);
+                self.used_outputs.push(out_idx);
+            }
+            Opcode::BindShortCall(in_idx, _)
+            | Opcode::BindLongCall(in_idx, _)
+            | Opcode::BindActivateCall(in_idx, _)
+            | Opcode::BindDeactivateCall(in_idx, _)
+            | Opcode::BindLongActivate(in_idx, _)
+            | Opcode::BindLongDeactivate(in_idx, _)
+            | Opcode::BindCallWith(in_idx, ..)
+            | Opcode::ReadInput(_, in_idx) => self.used_inputs.push(in_idx),
+            Opcode::BindLayerHold(in_idx, layer) => {
+                self.used_inputs.push(in_idx);
+                self.activated_layers.push(layer);
+            }
+            Opcode::LayerPush(layer) | Opcode::LayerSet(layer) => self.activated_layers.push(layer),
+            _ => {}
+        }
+    }
+
+    fn procedures(&mut self) {
+        let device = self.device;
+        for procedure in &device.procedures {
+            for step in &procedure.steps {
+                match step {
+                    Step::Action(action) => self.action(action.kind, &action.targets),
+                    Step::Opcode(raw) => match raw_opcode(raw) {
+                        Ok(opcode) => self.raw(&opcode),
+                        Err(err) => {
+                            self.report(err.mark, Severity::Error, LintKind::Config(err.kind))
+                        }
+                    },
+                }
+            }
+        }
+    }
+
+    fn bindings(&mut self) {
+        let device = self.device;
+        for zone in &device.zones {
+            for input in &zone.inputs {
+                if find(&device.inputs, input).is_none() {
+                    let kind = LintKind::UnknownInput(input.value.clone());
+                    self.report(input.mark, Severity::Error, kind);
+                }
+            }
+        }
+
+        // Where each (input, layer, trigger) was bound.
+        let mut bound: HashMap<(&str, LayerIdx, Trigger), Mark> = HashMap::new();
+        let mut shadowed = Vec::new();
+        for binding in &device.bindings {
+            self.input(&binding.input);
+            let layer = binding.layer();
+            if let Some(layer) = binding.layer {
+                self.layer(layer.layer, layer.mark);
+            }
+            if let Some(hold) = binding.hold {
+                self.layer(hold.layer, hold.mark);
+                self.activated_layers.push(hold.layer);
+            }
+
+            let holds = binding.hold.map(|hold| (Trigger::Activated, hold.mark));
+            let triggers = binding.triggers.iter().map(|def| (def.trigger, def.mark));
+            for (trigger, mark) in holds.into_iter().chain(triggers) {
+                let key = (binding.input.value.as_str(), layer, trigger);
+                if let Some(previous) = bound.insert(key, mark) {
+                    let kind = LintKind::ShadowedBinding {
+                        input: binding.input.value.clone(),
+                        layer,
+                        trigger,
+                        line: previous.line,
+                    };
+                    shadowed.push((mark, kind));
+                }
+            }
+            for def in &binding.triggers {
+                for action in &def.actions {
+                    self.action(action.kind, &action.targets);
+                }
+            }
+        }
+        for (mark, kind) in shadowed {
+            self.report(mark, Severity::Warning, kind);
+        }
+    }
+
+    /// Layers with bindings which nothing activates.
+    fn unreachable_layers(&mut self) {
+        let device = self.device;
+        let mut reported = Vec::new();
+        for binding in &device.bindings {
+            let Some(layer) = binding.layer else { continue };
+            if layer.layer == 0
+                || layer.layer as usize >= MAX_LAYERS
+                || self.activated_layers.contains(&layer.layer)
+                || reported.contains(&layer.layer)
+            {
+                continue;
+            }
+            reported.push(layer.layer);
+            self.report(
+                layer.mark,
+                Severity::Warning,
+                LintKind::UnreachableLayer(layer.layer),
+            );
+        }
+    }
+
+    fn unused(&mut self) {
+        let device = self.device;
+        for io in &device.inputs {
+            if !self.used_inputs.contains(&io.idx) {
+                let kind = LintKind::UnusedInput(io.name.value.clone());
+                self.report(io.name.mark, Severity::Warning, kind);
+            }
+        }
+        let remote: Vec<&str> = self
+            .config
+            .devices
+            .iter()
+            .flat_map(action_targets)
+            .filter_map(|name| name.remote())
+            .filter(|(remote, _)| *remote == device.name())
+            .map(|(_, output)| output)
+            .collect();
+        for io in &device.outputs {
+            if !self.used_outputs.contains(&io.idx) && !remote.contains(&io.name.value.as_str()) {
+                let kind = LintKind::UnusedOutput(io.name.value.clone());
+                self.report(io.name.mark, Severity::Warning, kind);
+            }
+        }
+    }
+
+    /// Expanders are checked against the default board. Devices without them
+    /// use native pins.
+    fn hardware(&mut self) {
+        if self.device.expanders.is_empty() {
+            return;
+        }
+        if let Err(errors) = Profile::from_device(self.device, Board::default()) {
+            for err in errors {
+                self.report(err.mark, Severity::Error, LintKind::Hardware(err.kind));
+            }
+        }
+    }
+
+    /// First error of the compiler, unless a lint already reported it.
+    fn compile(&mut self) {
+        let Err(err) = compiler::compile_device_in(self.config, self.device) else {
+            return;
+        };
+        let reported = self
+            .lints
+            .iter()
+            .any(|lint| lint.severity == Severity::Error && lint.mark == err.mark);
+        if !reported {
+            self.report(err.mark, Severity::Error, LintKind::Config(err.kind));
+        }
+    }
+}
+
+/// Targets of all actions of a device.
+fn action_targets(device: &Device) -> impl Iterator<Item = &Name> {
+    let bindings = device
+        .bindings
+        .iter()
+        .flat_map(|binding| &binding.triggers)
+        .flat_map(|def| &def.actions);
+    let procedures = device
+        .procedures
+        .iter()
+        .flat_map(|procedure| &procedure.steps)
+        .filter_map(|step| match step {
+            Step::Action(action) => Some(action),
+            Step::Opcode(_) => None,
+        });
+    bindings
+        .chain(procedures)
+        .filter(|action| action.kind != ActionKind::Call)
+        .flat_map(|action| &action.targets)
+}
+
+fn find(ios: &[Io], name: &Name) -> Option<u8> {
+    ios.iter()
+        .find(|io| io.name.value == name.value)
+        .map(|io| io.idx)
+}
+
+/// Lint all devices of a parsed configuration.
+pub fn lint(file: &str, config: &Config) -> Vec<Lint> {
+    let mut lints = Vec::new();
+    for device in &config.devices {
+        let mut linter = Linter {
+            file,
+            config,
+            device,
+            lints: Vec::new(),
+            used_inputs: Vec::new(),
+            used_outputs: Vec::new(),
+            activated_layers: Vec::new(),
+        };
+        linter.duplicate_pins();
+        linter.indices();
+        linter.procedures();
+        linter.bindings();
+        linter.unreachable_layers();
+        linter.unused();
+        linter.hardware();
+        linter.compile();
+        lints.append(&mut linter.lints);
+    }
+    lints.sort_by_key(|lint| lint.mark);
+    lints
+}
+
+/// Parse and lint configuration source. Parse errors are returned as lints.
+pub fn lint_source(file: &str, source: &str) -> Vec<Lint> {
+    match Config::parse(source) {
+        Ok(config) => lint(file, &config),
+        Err(err) => vec![Lint {
+            file: file.to_string(),
+            mark: err.mark,
+            severity: Severity::Error,
+            kind: LintKind::Config(err.kind),
+        }],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_lints_example_config() {
+        let lints = lint_source("code.yaml", include_str!("../code.yaml"));
+        let at = |line, column| Mark { line, column };
+
+        let duplicates: Vec<_> = lints
+            .iter()
+            .filter(|lint| matches!(lint.kind, LintKind::DuplicateOutputPin { .. }))
+            .collect();
+        assert_eq!(duplicates.len(), 6);
+        assert_eq!(duplicates[0].mark, at(33, 20));
+        assert_eq!(
+            duplicates[0].to_string(),
+            "code.yaml:33:20: error: output `dining_main` uses pin 1 of output `island`"
+        );
+
+        let reserved = lints
+            .iter()
+            .find(|lint| matches!(lint.kind, LintKind::ReservedInput(_)))
+            .unwrap();
+        assert_eq!(reserved.mark, at(15, 19));
+
+        let unknown: Vec<_> = lints
+            .iter()
+            .filter(|lint| matches!(lint.kind, LintKind::UnknownOutput(_)))
+            .map(|lint| (lint.mark, lint.kind.clone()))
+            .collect();
+        assert_eq!(
+            unknown,
+            vec![
+                (
+                    at(71, 20),
+                    LintKind::UnknownOutput("kitchen1_l".to_string())
+                ),
+                (
+                    at(71, 32),
+                    LintKind::UnknownOutput("kitchen1_r".to_string())
+                ),
+                (at(71, 44), LintKind::UnknownOutput("...".to_string())),
+            ]
+        );
+
+        let unused: Vec<_> = lints
+            .iter()
+            .filter_map(|lint| match &lint.kind {
+                LintKind::UnusedInput(name) => Some(name.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            unused,
+            vec!["kitchen1_l", "kitchen2_l", "dining1_r", "dining1_l"]
+        );
+
+        let layer = lints
+            .iter()
+            .find(|lint| lint.kind == LintKind::UnreachableLayer(1))
+            .unwrap();
+        assert_eq!(layer.mark, at(57, 14));
+
+        let hardware = lints
+            .iter()
+            .find(|lint| matches!(lint.kind, LintKind::Hardware(_)))
+            .unwrap();
+        assert_eq!(
+            hardware.to_string(),
+            "code.yaml:31:7: error: output `main_kitchen` is not connected to an expander pin"
+        );
+    }
+
+    #[test]
+    fn it_finds_shadowed_bindings_and_layers() {
+        let source = "\
+dev:
+  inputs: {a: 1, b: 1}
+  outputs: {x: 1}
+  bindings:
+    - input: a
+      hold: 200
+      short: {toggle: x}
+    - input: a
+      activate: {toggle: x}
+      short: {call: p}
+";
+        let lints = lint_source("dev.yaml", source);
+        let kinds: Vec<_> = lints
+            .iter()
+            .map(|lint| (lint.mark.line, lint.kind.clone()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (
+                    2,
+                    LintKind::DuplicateInputPin {
+                        name: "b".to_string(),
+                        other: "a".to_string(),
+                        idx: 1
+                    }
+                ),
+                (6, LintKind::LayerOutOfRange(200)),
+                (
+                    9,
+                    LintKind::ShadowedBinding {
+                        input: "a".to_string(),
+                        layer: 0,
+                        trigger: Trigger::Activated,
+                        line: 6
+                    }
+                ),
+                (
+                    10,
+                    LintKind::ShadowedBinding {
+                        input: "a".to_string(),
+                        layer: 0,
+                        trigger: Trigger::ShortClick,
+                        line: 7
+                    }
+                ),
+                (10, LintKind::UnknownProcedure("p".to_string())),
+            ]
+        );
+
+        let lints = lint_source("dev.yaml", "dev:\n  inputs: [\n");
+        assert_eq!(lints.len(), 1);
+        assert_eq!(lints[0].severity, Severity::Error);
+    }
+
+    #[test]
+    fn it_reports_what_the_compiler_rejects() {
+        let source = "\
+dev:
+  inputs: {door: 0, bell: 200}
+  outputs: {lamp: 1}
+  bindings:
+    - input: door
+      short: {toggle: lamp}
+    - input: bell
+      short: {toggle: lamp}
+  procedures:
+    p:
+      opcodes: [{Call: [9]}]
+";
+        let lints = lint_source("dev.yaml", source);
+        let kinds: Vec<_> = lints
+            .iter()
+            .map(|lint| (lint.mark.line, lint.mark.column, lint.kind.clone()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (2, 18, LintKind::ReservedInput("door".to_string())),
+                (
+                    2,
+                    27,
+                    LintKind::IndexOutOfRange {
+                        name: "bell".to_string(),
+                        idx: 200
+                    }
+                ),
+            ]
+        );
+
+        // Verifier problems are reported once the IOs are fixed.
+        let source = source
+            .replace("door: 0", "door: 1")
+            .replace("bell: 200", "bell: 2");
+        let lints = lint_source("dev.yaml", &source);
+        assert_eq!(lints.len(), 1);
+        assert_eq!(
+            lints[0].to_string(),
+            "dev.yaml:11:18: error: invalid program: 5: procedure 9 is not defined"
+        );
+    }
+
+    #[test]
+    fn it_checks_remote_outputs() {
+        let source = "\
+hall:
+  address: 2
+  inputs: {a: 1}
+  bindings:
+    - input: a
+      short: {toggle: [fusebox.corridor, fusebox.attic, garage.door]}
+      long: {toggle: shed.lamp}
+attic:
+  inputs: {b: 1}
+  bindings:
+    - input: b
+      short: {toggle: fusebox.corridor}
+fusebox:
+  address: 1
+  outputs: {corridor: 1}
+shed:
+  outputs: {lamp: 1}
+";
+        let kinds: Vec<_> = lint_source("house.yaml", source)
+            .into_iter()
+            .map(|lint| lint.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                LintKind::UnknownOutput("fusebox.attic".to_string()),
+                LintKind::Config(ConfigErrorKind::UnknownDevice("garage".to_string())),
+                LintKind::Config(ConfigErrorKind::MissingAddress("shed".to_string())),
+                LintKind::Config(ConfigErrorKind::MissingAddress("attic".to_string())),
+            ]
+        );
+    }
+}
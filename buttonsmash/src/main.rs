@@ -67,7 +67,663 @@
  *
  */
 
-#[tokio::main]
-async fn main() {
-    println!("Main starts!");
+/*
+ * Command-line tool for device configurations and programs.
+ *
+ * Inputs are configurations (YAML) or compiled programs (bytecode), which are
+ * recognized by the magic bytes. With `--json` the result is printed as a
+ * single JSON object on stdout, logs of the VM go to stderr.
+ *
+ * Exit codes: 0 - success, 1 - invalid configuration or program (including
+ * lint errors), 2 - wrong usage or an unreadable file.
+ */
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand};
+use serde_json::{json, Value};
+use tokio::runtime;
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
+
+use buttonsmash::assembler;
+use buttonsmash::bytecode;
+use buttonsmash::compiler::{self, DeviceProgram};
+use buttonsmash::config::{Config, ConfigError, Device, ACTIONS, TRIGGERS};
+use buttonsmash::consts::{Command, Event};
+use buttonsmash::decompiler::{self, NameTable};
+use buttonsmash::diff::{self, DeviceDiff, Side};
+use buttonsmash::hardware::{Board, Profile};
+use buttonsmash::lint::{self, Severity};
+use buttonsmash::microvm::Executor;
+use buttonsmash::opcodes::Opcode;
+
+/// Bindings capacity of the simulated executor.
+const BINDINGS: usize = 128;
+
+#[derive(Parser)]
+#[command(
+    name = "buttonsmash",
+    about = "Compile, inspect and simulate device programs"
+)]
+struct Cli {
+    /// Print the result as JSON.
+    #[arg(long, global = true)]
+    json: bool,
+    #[command(subcommand)]
+    command: Cmd,
+}
+
+#[derive(Subcommand)]
+enum Cmd {
+    /// Lint a configuration.
+    Check {
+        config: PathBuf,
+        /// Fail on warnings too.
+        #[arg(long)]
+        deny_warnings: bool,
+    },
+    /// Compile a configuration into a bytecode file for each device, and a
+    /// `<device>.io.bin` boot table for devices with expanders.
+    Compile {
+        config: PathBuf,
+        /// Compile only this device. Its subscriptions are not resolved, they
+        /// depend on the other devices.
+        #[arg(short, long)]
+        device: Option<String>,
+        /// Directory for the `<device>.bin` files.
+        #[arg(short, long, default_value = ".")]
+        out_dir: PathBuf,
+    },
+    /// Disassemble a program.
+    Disasm {
+        #[command(flatten)]
+        input: Input,
+    },
+    /// Reconstruct bindings and procedures of a program.
+    DumpBindings {
+        #[command(flatten)]
+        input: Input,
+    },
+    /// Show changed bindings and procedures between two configurations or
+    /// programs.
+    Diff {
+        old: PathBuf,
+        new: PathBuf,
+        /// Compare only this device.
+        #[arg(short, long)]
+        device: Option<String>,
+        /// Configuration with the names for bytecode files.
+        #[arg(long)]
+        names: Option<PathBuf>,
+    },
+    /// Run a program with input events, eg. `short:door`, `activate:3` or
+    /// `wait:500` (ms, simulated without sleeping), and show the emitted
+    /// commands.
+    Simulate {
+        #[command(flatten)]
+        input: Input,
+        events: Vec<String>,
+    },
+}
+
+#[derive(Args)]
+struct Input {
+    /// Configuration or bytecode file.
+    path: PathBuf,
+    /// Device of the configuration, required when it has more of them.
+    #[arg(short, long)]
+    device: Option<String>,
+    /// Configuration with the names for a bytecode file.
+    #[arg(long)]
+    names: Option<PathBuf>,
+}
+
+/// Reason of a non-zero exit code.
+enum Failure {
+    /// Configuration or program is invalid.
+    Invalid(Value),
+    Usage(String),
+}
+
+impl Failure {
+    fn invalid(message: impl ToString) -> Self {
+        Failure::Invalid(json!({ "message": message.to_string() }))
+    }
+
+    fn config(file: &Path, err: &ConfigError) -> Self {
+        Failure::Invalid(json!({
+            "file": file.display().to_string(),
+            "line": err.mark.line,
+            "column": err.mark.column,
+            "message": err.kind.to_string(),
+        }))
+    }
+}
+
+/// Result printed as text or JSON.
+struct Report {
+    json: Value,
+    text: String,
+    /// Exit with an error after printing, eg. when lints have errors.
+    failed: bool,
+}
+
+impl Report {
+    fn ok(json: Value, text: String) -> Self {
+        Self {
+            json,
+            text,
+            failed: false,
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, Failure> {
+    fs::read(path).map_err(|err| Failure::Usage(format!("{}: {}", path.display(), err)))
+}
+
+fn read_config(path: &Path) -> Result<Config, Failure> {
+    let source = String::from_utf8(read(path)?)
+        .map_err(|_| Failure::Usage(format!("{}: not a UTF-8 file", path.display())))?;
+    Config::parse(&source).map_err(|err| Failure::config(path, &err))
+}
+
+/// Device selected by its name or the only one.
+fn select<'a>(config: &'a Config, device: Option<&str>) -> Result<&'a Device, Failure> {
+    match (device, config.devices.as_slice()) {
+        (Some(name), _) => config
+            .device(name)
+            .ok_or_else(|| Failure::Usage(format!("no device `{}`", name))),
+        (None, [device]) => Ok(device),
+        (None, _) => Err(Failure::Usage(
+            "configuration has more devices, select one with --device".to_string(),
+        )),
+    }
+}
+
+/// Compiled program of a device with its names.
+fn compile_device(
+    path: &Path,
+    config: &Config,
+    device: &Device,
+) -> Result<(DeviceProgram, NameTable), Failure> {
+    let program =
+        compiler::compile_device_in(config, device).map_err(|err| Failure::config(path, &err))?;
+    let names = NameTable::new(device, &program);
+    Ok((program, names))
+}
+
+/// Program to inspect or run.
+struct Loaded {
+    device: String,
+    opcodes: Vec<Opcode>,
+    names: Option<NameTable>,
+}
+
+fn load(input: &Input) -> Result<Loaded, Failure> {
+    let data = read(&input.path)?;
+    if data.starts_with(&bytecode::MAGIC) {
+        let opcodes = bytecode::decode(&data).map_err(Failure::invalid)?;
+        let device = input
+            .path
+            .file_stem()
+            .map_or_else(String::new, |stem| stem.to_string_lossy().to_string());
+        let mut loaded = Loaded {
+            device,
+            opcodes,
+            names: None,
+        };
+        if let Some(path) = &input.names {
+            let config = read_config(path)?;
+            let name = input.device.as_deref().unwrap_or(&loaded.device);
+            let device = select(&config, Some(name))?;
+            let (_, names) = compile_device(path, &config, device)?;
+            loaded.device = device.name().to_string();
+            loaded.names = Some(names);
+        }
+        return Ok(loaded);
+    }
+
+    let config = read_config(&input.path)?;
+    let device = select(&config, input.device.as_deref())?;
+    let (program, names) = compile_device(&input.path, &config, device)?;
+    Ok(Loaded {
+        device: program.device,
+        opcodes: program.opcodes,
+        names: Some(names),
+    })
+}
+
+fn check(config: &Path, deny_warnings: bool) -> Result<Report, Failure> {
+    let source = String::from_utf8(read(config)?)
+        .map_err(|_| Failure::Usage(format!("{}: not a UTF-8 file", config.display())))?;
+    let lints = lint::lint_source(&config.display().to_string(), &source);
+    let failed = lints
+        .iter()
+        .any(|lint| lint.severity == Severity::Error || deny_warnings);
+    let json = json!({
+        "ok": !failed,
+        "lints": lints.iter().map(|lint| json!({
+            "file": lint.file,
+            "line": lint.mark.line,
+            "column": lint.mark.column,
+            "severity": lint.severity.to_string(),
+            "message": lint.kind.to_string(),
+        })).collect::<Vec<_>>(),
+    });
+    let text = lints.iter().map(|lint| format!("{}\n", lint)).collect();
+    Ok(Report { json, text, failed })
+}
+
+fn compile(config_path: &Path, device: Option<&str>, out_dir: &Path) -> Result<Report, Failure> {
+    let config = read_config(config_path)?;
+    let programs = match device {
+        Some(name) => {
+            let device = select(&config, Some(name))?;
+            compiler::compile_device_in(&config, device).map(|program| vec![program])
+        }
+        None => compiler::compile(&config),
+    }
+    .map_err(|err| Failure::config(config_path, &err))?;
+
+    let mut devices = Vec::new();
+    let mut text = String::new();
+    for program in programs {
+        let device = config
+            .device(&program.device)
+            .expect("programs are compiled from the devices");
+        let io = match device.expanders.is_empty() {
+            true => None,
+            false => Some(boot_table(config_path, device, out_dir)?),
+        };
+        let subscriptions: Vec<_> = program
+            .subscriptions
+            .iter()
+            .map(|sub| (sub.address, sub.output))
+            .collect();
+        let bytes = bytecode::encode_with(&program.opcodes, &subscriptions)
+            .map_err(|err| Failure::invalid(format!("{}: {:?}", program.device, err)))?;
+        let path = out_dir.join(format!("{}.bin", program.device));
+        fs::write(&path, &bytes)
+            .map_err(|err| Failure::Usage(format!("{}: {}", path.display(), err)))?;
+        text += &format!(
+            "{}: {} opcodes, {} bytes\n",
+            path.display(),
+            program.opcodes.len(),
+            bytes.len()
+        );
+        if let Some(io) = &io {
+            text += &format!("{}: boot table\n", io.display());
+        }
+        devices.push(json!({
+            "device": program.device,
+            "path": path.display().to_string(),
+            "opcodes": program.opcodes.len(),
+            "bytes": bytes.len(),
+            "io": io.map(|io| io.display().to_string()),
+            "procedures": program.procedures.iter()
+                .map(|(name, id)| json!({ "name": name, "id": id }))
+                .collect::<Vec<_>>(),
+            "subscriptions": program.subscriptions.iter()
+                .map(|sub| json!({
+                    "device": sub.device,
+                    "address": sub.address,
+                    "output": sub.output,
+                }))
+                .collect::<Vec<_>>(),
+        }));
+    }
+    Ok(Report::ok(json!({ "ok": true, "devices": devices }), text))
+}
+
+/// Validate the expanders of the device and write its boot table.
+fn boot_table(config_path: &Path, device: &Device, out_dir: &Path) -> Result<PathBuf, Failure> {
+    let profile = Profile::from_device(device, Board::default()).map_err(|errors| {
+        let err = &errors[0];
+        Failure::Invalid(json!({
+            "file": config_path.display().to_string(),
+            "line": err.mark.line,
+            "column": err.mark.column,
+            "message": err.kind.to_string(),
+        }))
+    })?;
+    let path = out_dir.join(format!("{}.io.bin", device.name()));
+    fs::write(&path, profile.boot_table().to_bytes())
+        .map_err(|err| Failure::Usage(format!("{}: {}", path.display(), err)))?;
+    Ok(path)
+}
+
+fn disasm(input: &Input) -> Result<Report, Failure> {
+    let loaded = load(input)?;
+    let text = assembler::disassemble(&loaded.opcodes);
+    let json = json!({ "ok": true, "device": loaded.device, "assembly": text });
+    Ok(Report::ok(json, text))
+}
+
+fn dump_bindings(input: &Input) -> Result<Report, Failure> {
+    let loaded = load(input)?;
+    let device = decompiler::decompile(&loaded.device, &loaded.opcodes, loaded.names.as_ref())
+        .map_err(Failure::invalid)?;
+
+    let bindings: Vec<Value> = device
+        .bindings
+        .iter()
+        .map(|binding| {
+            let mut triggers = serde_json::Map::new();
+            for def in &binding.triggers {
+                let (_, name) = TRIGGERS
+                    .iter()
+                    .find(|(trigger, _)| *trigger == def.trigger)
+                    .expect("all triggers are named");
+                let actions: Vec<Value> = def
+                    .actions
+                    .iter()
+                    .map(|action| {
+                        let targets: Vec<&str> =
+                            action.targets.iter().map(|t| t.value.as_str()).collect();
+                        let (_, kind) = ACTIONS
+                            .iter()
+                            .find(|(kind, _)| *kind == action.kind)
+                            .expect("all actions are named");
+                        json!({ "action": kind, "targets": targets })
+                    })
+                    .collect();
+                triggers.insert(name.to_string(), json!(actions));
+            }
+            json!({
+                "input": binding.input.value,
+                "layer": binding.layer(),
+                "hold": binding.hold.map(|hold| hold.layer),
+                "triggers": triggers,
+            })
+        })
+        .collect();
+    let procedures: Vec<Value> = device
+        .procedures
+        .iter()
+        .map(|procedure| {
+            json!({ "name": procedure.name.value, "id": procedure.id.map(|(id, _)| id) })
+        })
+        .collect();
+    let json = json!({
+        "ok": true,
+        "device": loaded.device,
+        "bindings": bindings,
+        "procedures": procedures,
+    });
+    Ok(Report::ok(json, decompiler::to_yaml(&device)))
+}
+
+fn is_bytecode(path: &Path) -> Result<bool, Failure> {
+    Ok(read(path)?.starts_with(&bytecode::MAGIC))
+}
+
+fn diff(
+    old: &Path,
+    new: &Path,
+    device: Option<&str>,
+    names: Option<&Path>,
+) -> Result<Report, Failure> {
+    let diffs = if device.is_none() && !is_bytecode(old)? && !is_bytecode(new)? {
+        let (old_config, new_config) = (read_config(old)?, read_config(new)?);
+        diff::diff_configs(&old_config, &new_config).map_err(|(side, err)| match side {
+            Side::Old => Failure::config(old, &err),
+            Side::New => Failure::config(new, &err),
+        })?
+    } else {
+        let input = |path: &Path| Input {
+            path: path.to_path_buf(),
+            device: device.map(str::to_string),
+            names: names.map(Path::to_path_buf),
+        };
+        let (old, new) = (load(&input(old))?, load(&input(new))?);
+        let changes = diff::diff_programs(
+            &old.opcodes,
+            old.names.as_ref(),
+            &new.opcodes,
+            new.names.as_ref(),
+        )
+        .map_err(Failure::invalid)?;
+        match changes.is_empty() {
+            true => Vec::new(),
+            false => vec![DeviceDiff {
+                device: new.device,
+                changes,
+            }],
+        }
+    };
+
+    let mut text = String::new();
+    for diff in &diffs {
+        text += &format!("{}:\n", diff.device);
+        for change in &diff.changes {
+            text += &format!("  {}\n", change);
+        }
+    }
+    let devices: Vec<Value> = diffs
+        .iter()
+        .map(|diff| {
+            let changes: Vec<String> = diff.changes.iter().map(ToString::to_string).collect();
+            json!({ "device": diff.device, "changes": changes })
+        })
+        .collect();
+    Ok(Report::ok(json!({ "ok": true, "devices": devices }), text))
+}
+
+/// Simulation step parsed from the command line.
+enum Step {
+    Event(Event),
+    Wait(Duration),
+}
+
+fn parse_step(step: &str, names: Option<&NameTable>) -> Result<Step, Failure> {
+    let invalid = || Failure::Usage(format!("invalid event `{}`", step));
+    let (kind, arg) = step.split_once(':').ok_or_else(invalid)?;
+    if kind == "wait" {
+        let ms = arg.parse().map_err(|_| invalid())?;
+        return Ok(Step::Wait(Duration::from_millis(ms)));
+    }
+    let (trigger, _) = TRIGGERS
+        .iter()
+        .find(|(_, name)| *name == kind)
+        .ok_or_else(invalid)?;
+    let in_idx = match arg.parse() {
+        Ok(in_idx) => in_idx,
+        Err(_) => names
+            .and_then(|names| names.inputs.iter().find(|(name, _)| name == arg))
+            .map(|(_, in_idx)| *in_idx)
+            .ok_or_else(|| Failure::Usage(format!("unknown input `{}`", arg)))?,
+    };
+    Ok(Step::Event(Event::new_button_trigger(in_idx, *trigger)))
+}
+
+fn drain(commands: &mut mpsc::Receiver<Command>) -> Vec<String> {
+    let mut drained = Vec::new();
+    while let Ok(command) = commands.try_recv() {
+        drained.push(format!("{:?}", command));
+    }
+    drained
+}
+
+/// Commands emitted by the setup and by each step.
+async fn replay(opcodes: &[Opcode], steps: Vec<Step>) -> Result<Vec<Vec<String>>, Failure> {
+    let (queue, mut commands) = mpsc::channel(1024);
+    let mut executor: Executor<BINDINGS> = Executor::new(queue);
+    executor
+        .load_static(opcodes)
+        .await
+        .map_err(|err| Failure::invalid(format!("{:?}", err)))?;
+
+    let mut results = vec![drain(&mut commands)];
+    for step in steps {
+        match step {
+            Step::Event(event) => executor.parse_event(&event).await,
+            Step::Wait(duration) => {
+                let deadline = Instant::now() + duration;
+                while let Some(wake) = executor.next_deadline().filter(|wake| *wake <= deadline) {
+                    time::advance(wake.saturating_duration_since(Instant::now())).await;
+                    executor.process_timers().await;
+                }
+                time::advance(deadline.saturating_duration_since(Instant::now())).await;
+            }
+        }
+        results.push(drain(&mut commands));
+    }
+    Ok(results)
+}
+
+/// Run the program with the events on a paused clock, waits take no real time.
+fn simulate(input: &Input, events: &[String]) -> Result<Report, Failure> {
+    let loaded = load(input)?;
+    let steps = events
+        .iter()
+        .map(|event| parse_step(event, loaded.names.as_ref()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_time()
+        .start_paused(true)
+        .build()
+        .map_err(|err| Failure::Usage(format!("runtime: {}", err)))?;
+    let commands = runtime.block_on(replay(&loaded.opcodes, steps))?;
+    let results: Vec<_> = ["setup"]
+        .into_iter()
+        .chain(events.iter().map(String::as_str))
+        .zip(commands)
+        .collect();
+
+    let mut text = String::new();
+    for (event, commands) in &results {
+        match commands.is_empty() {
+            true => text += &format!("{}:\n", event),
+            false => text += &format!("{}: {}\n", event, commands.join(", ")),
+        }
+    }
+    let steps: Vec<Value> = results
+        .iter()
+        .map(|(event, commands)| json!({ "event": event, "commands": commands }))
+        .collect();
+    let json = json!({ "ok": true, "device": loaded.device, "steps": steps });
+    Ok(Report::ok(json, text))
+}
+
+fn run(cli: &Cli) -> Result<Report, Failure> {
+    match &cli.command {
+        Cmd::Check {
+            config,
+            deny_warnings,
+        } => check(config, *deny_warnings),
+        Cmd::Compile {
+            config,
+            device,
+            out_dir,
+        } => compile(config, device.as_deref(), out_dir),
+        Cmd::Disasm { input } => disasm(input),
+        Cmd::DumpBindings { input } => dump_bindings(input),
+        Cmd::Diff {
+            old,
+            new,
+            device,
+            names,
+        } => diff(old, new, device.as_deref(), names.as_deref()),
+        Cmd::Simulate { input, events } => simulate(input, events),
+    }
+}
+
+/// Print to stdout. Closed pipe (eg. `| head`) is not an error.
+fn output(text: &str) {
+    let _ = io::stdout().lock().write_all(text.as_bytes());
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(&cli) {
+        Ok(report) => {
+            if cli.json {
+                output(&format!("{}\n", report.json));
+            } else {
+                output(&report.text);
+            }
+            ExitCode::from(report.failed as u8)
+        }
+        Err(Failure::Invalid(error)) => {
+            if cli.json {
+                output(&format!("{}\n", json!({ "ok": false, "error": error })));
+            } else {
+                let message = error["message"].as_str().unwrap_or_default();
+                match (error["file"].as_str(), error["line"].as_u64()) {
+                    (Some(file), Some(line)) => {
+                        eprintln!("{}:{}:{}: error: {}", file, line, error["column"], message)
+                    }
+                    _ => eprintln!("error: {}", message),
+                }
+            }
+            ExitCode::from(1)
+        }
+        Err(Failure::Usage(message)) => {
+            if cli.json {
+                let error = json!({ "ok": false, "error": { "message": message } });
+                output(&format!("{}\n", error));
+            } else {
+                eprintln!("error: {}", message);
+            }
+            ExitCode::from(2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use buttonsmash::consts::Trigger;
+
+    #[test]
+    fn it_parses_simulation_steps() {
+        let names = NameTable {
+            inputs: vec![("door".to_string(), 4)],
+            ..NameTable::default()
+        };
+        let Ok(Step::Event(Event::ButtonTrigger(trigger))) = parse_step("long:door", Some(&names))
+        else {
+            panic!("expected a button event");
+        };
+        assert_eq!((trigger.in_idx, trigger.trigger), (4, Trigger::LongClick));
+        assert!(matches!(
+            parse_step("wait:20", None),
+            Ok(Step::Wait(duration)) if duration == Duration::from_millis(20)
+        ));
+        assert!(parse_step("short:door", None).is_err());
+        assert!(parse_step("tap:1", None).is_err());
+    }
+
+    #[test]
+    fn it_simulates_example_device() {
+        let input = Input {
+            path: PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/code.yaml")),
+            device: Some("fusebox".to_string()),
+            names: None,
+        };
+        let events = ["short:1", "wait:3600000", "long:1"].map(String::from);
+        let started = std::time::Instant::now();
+        let Ok(report) = simulate(&input, &events) else {
+            panic!("simulation failed");
+        };
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(
+            report.text,
+            "setup:\nshort:1: ToggleOutput(1)\nwait:3600000:\nlong:1:\n"
+        );
+        assert_eq!(report.json["steps"][1]["commands"][0], "ToggleOutput(1)");
+
+        // Configuration has more devices.
+        let input = Input {
+            device: None,
+            ..input
+        };
+        assert!(matches!(simulate(&input, &[]), Err(Failure::Usage(_))));
+    }
 }
@@ -1,115 +1,488 @@
+use core::time::Duration;
+
 use tokio::sync::mpsc;
+use tokio::time::{sleep_until, timeout_at, Instant};
 
 use crate::bindings::*;
 use crate::consts::*;
-use crate::layers::Layers;
+use crate::layers::{AssignError, OverflowPolicy, Zones};
 use crate::opcodes::Opcode;
+use crate::syscalls::{SyscallFailed, SyscallHandler, SyscallOutOfRange, Syscalls};
+use crate::threads::{Retrigger, Thread, ThreadHandle, Threads};
+use crate::timers::OutputTimers;
+use crate::verifier::{self, Diagnostic};
+
+/// Errors that abort execution of a procedure.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum VmError {
+    /// Procedure calls nested deeper than `MAX_CALL_DEPTH`.
+    StackOverflow,
+    /// Procedure executed more opcodes than allowed by the `Budget`.
+    InstructionBudgetExceeded,
+    /// Procedure executed longer than allowed by the `Budget`.
+    TimeBudgetExceeded,
+    /// Execution reached the end of the program memory.
+    EndOfProgram,
+    /// No free timer to schedule output deactivation.
+    TimersFull,
+    /// No free thread slot for a waiting procedure.
+    TooManyThreads,
+    /// Opcode can't be executed (eg. Start inside a procedure).
+    InvalidOpcode,
+    /// Called procedure is not defined.
+    UnknownProcedure,
+    /// Command queue was closed by the receiver.
+    QueueClosed,
+    InputOutOfRange,
+    OutputOutOfRange,
+    LayerOutOfRange,
+    ZoneOutOfRange,
+    RegisterOutOfRange,
+    VariableOutOfRange,
+    /// No free slot for a new binding.
+    BindingsFull,
+    /// Mod opcode with a zero divisor.
+    ModuloByZero,
+    /// No native handler registered for the syscall.
+    UnknownSyscall,
+    /// Native syscall handler failed.
+    SyscallFailed,
+}
+
+impl From<VmError> for ErrorCode {
+    fn from(err: VmError) -> Self {
+        match err {
+            VmError::StackOverflow => ErrorCode::StackOverflow,
+            VmError::InstructionBudgetExceeded => ErrorCode::InstructionBudgetExceeded,
+            VmError::TimeBudgetExceeded => ErrorCode::TimeBudgetExceeded,
+            VmError::EndOfProgram => ErrorCode::EndOfProgram,
+            VmError::TimersFull => ErrorCode::TimersFull,
+            VmError::TooManyThreads => ErrorCode::TooManyThreads,
+            VmError::InvalidOpcode => ErrorCode::InvalidOpcode,
+            VmError::UnknownProcedure => ErrorCode::UnknownProcedure,
+            VmError::QueueClosed => ErrorCode::QueueClosed,
+            VmError::InputOutOfRange => ErrorCode::InputOutOfRange,
+            VmError::OutputOutOfRange => ErrorCode::OutputOutOfRange,
+            VmError::LayerOutOfRange => ErrorCode::LayerOutOfRange,
+            VmError::ZoneOutOfRange => ErrorCode::ZoneOutOfRange,
+            VmError::RegisterOutOfRange => ErrorCode::RegisterOutOfRange,
+            VmError::VariableOutOfRange => ErrorCode::VariableOutOfRange,
+            VmError::BindingsFull => ErrorCode::BindingsFull,
+            VmError::ModuloByZero => ErrorCode::ModuloByZero,
+            VmError::UnknownSyscall => ErrorCode::UnknownSyscall,
+            VmError::SyscallFailed => ErrorCode::SyscallFailed,
+        }
+    }
+}
+
+impl From<BindError> for VmError {
+    fn from(err: BindError) -> Self {
+        match err {
+            BindError::ReservedInput => VmError::InputOutOfRange,
+            BindError::Full => VmError::BindingsFull,
+        }
+    }
+}
+
+impl From<AssignError> for VmError {
+    fn from(err: AssignError) -> Self {
+        match err {
+            AssignError::InputOutOfRange => VmError::InputOutOfRange,
+            AssignError::ZoneOutOfRange => VmError::ZoneOutOfRange,
+        }
+    }
+}
+
+impl From<SyscallFailed> for VmError {
+    fn from(_: SyscallFailed) -> Self {
+        VmError::SyscallFailed
+    }
+}
+
+/// Limits of a single procedure run between waits. Procedure exceeding them
+/// is aborted, so a bad program can't stall button handling.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Budget {
+    /// Maximal number of executed opcodes, including called procedures.
+    pub instructions: u32,
+    /// Maximal time spent executing.
+    pub time: Duration,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Self {
+            instructions: 10_000,
+            time: Duration::from_millis(500),
+        }
+    }
+}
+
+/// Program was not loaded.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LoadError {
+    /// Verifier rejected the program. First found problem is returned.
+    Invalid(Diagnostic),
+    /// Program is longer than the executor program memory.
+    TooLong { length: usize, capacity: usize },
+    /// Procedure ID is out of the executor procedure table.
+    TooManyProcedures(ProcIdx),
+    /// Subscribed device or output is out of range.
+    InvalidSubscription(DevAddr, OutIdx),
+}
+
+/// What the interpreter loop does after executing an opcode.
+enum Flow {
+    /// Continue with the next opcode.
+    Next,
+    /// Enter a procedure and continue after the call when it returns.
+    Call(ProcIdx, Args),
+    /// Return from the current procedure.
+    Return,
+    /// Continue at a given address.
+    Jump(usize),
+    /// Suspend the thread for a given time and continue with the next opcode.
+    Wait(Duration),
+    /// Suspend the thread until its input is released or the time passes.
+    WaitForRelease(Duration),
+}
+
+/// Address of a relative jump. Jumping before the program start results in an
+/// invalid address, which ends execution.
+fn relative(pc: usize, offset: i8) -> usize {
+    pc.wrapping_add_signed(offset as isize)
+}
 
-/// Executes actions using a program.
-pub struct Executor<const BINDINGS: usize> {
-    /// Current selected Layer
-    // current_layer: LayerIdx,
-    layers: Layers,
+/// Executes actions using a program. Capacity of the program memory and
+/// procedure table can be sized for the target.
+pub struct Executor<
+    const BINDINGS: usize,
+    const LAYER_STACK: usize = MAX_LAYER_STACK,
+    const PROGRAM: usize = DEFAULT_PROGRAM_LENGTH,
+    const PROCEDURES: usize = MAX_PROCEDURES,
+> {
+    /// Layer stacks of input zones.
+    zones: Zones<LAYER_STACK>,
     bindings: BindingList<BINDINGS>,
-    opcodes: [Opcode; 1024],
-    procedures: [usize; MAX_PROCEDURES],
+    opcodes: [Opcode; PROGRAM],
+    procedures: [usize; PROCEDURES],
+    budget: Budget,
+    /// End of the time budget of the running thread. Emitting a command
+    /// doesn't wait for the queue past it.
+    deadline: Option<Instant>,
+
+    /// Procedures suspended in a wait.
+    threads: Threads,
+    /// What to do when a running procedure is triggered again.
+    retrigger: [Retrigger; PROCEDURES],
+    /// Variables shared by all procedures.
+    variables: [Word; VARIABLES],
+    /// Procedure started when another procedure fails.
+    error_handler: Option<ProcIdx>,
+    /// Last known state of local inputs.
+    inputs: [bool; MAX_INPUTS],
+    /// Last known state of local outputs.
+    outputs: [bool; MAX_OUTPUTS],
+    /// Devices allowed to set each output, a bit per device address.
+    subscribers: [u64; MAX_OUTPUTS],
+    /// Outputs to deactivate after a set time.
+    timers: OutputTimers,
+    /// Native handlers registered by the firmware. Kept when loading programs.
+    syscalls: Syscalls,
 
     command_queue: mpsc::Sender<Command>,
 }
 
-impl<const BN: usize> Executor<BN> {
+impl<const BN: usize, const LS: usize, const PL: usize, const PN: usize> Executor<BN, LS, PL, PN> {
     pub fn new(queue: mpsc::Sender<Command>) -> Self {
         Self {
-            layers: Layers::new(),
+            zones: Zones::new(),
             bindings: BindingList::new(),
-            opcodes: [Opcode::Noop; 1024],
-            procedures: [0; MAX_PROCEDURES],
+            opcodes: [Opcode::Noop; PL],
+            procedures: [0; PN],
+            budget: Budget::default(),
+            deadline: None,
+
+            threads: Threads::new(),
+            retrigger: [Retrigger::default(); PN],
+            variables: [0; VARIABLES],
+            error_handler: None,
+            inputs: [false; MAX_INPUTS],
+            outputs: [false; MAX_OUTPUTS],
+            subscribers: [0; MAX_OUTPUTS],
+            timers: OutputTimers::new(),
+            syscalls: Syscalls::new(),
 
             command_queue: queue,
         }
     }
 
-    pub async fn load_static(&mut self, program: &[Opcode]) {
-        for (idx, opcode) in program.iter().enumerate() {
-            self.opcodes[idx] = *opcode;
+    /// Set limits of a single procedure run.
+    pub fn set_budget(&mut self, budget: Budget) {
+        self.budget = budget;
+    }
+
+    /// Select what happens when a layer is activated on a full layer stack.
+    pub fn set_layer_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.zones.set_policy(policy);
+    }
+
+    /// Register native handler of the `Syscall` opcode.
+    pub fn register_syscall(
+        &mut self,
+        id: SyscallId,
+        handler: impl FnMut(u8) -> Result<Word, SyscallFailed> + Send + 'static,
+    ) -> Result<(), SyscallOutOfRange> {
+        let handler: SyscallHandler = Box::new(handler);
+        self.syscalls.register(id, handler)
+    }
+
+    /// Allow remote devices to set the outputs, as (device address, output)
+    /// pairs. Call after `load_static`, which clears them.
+    pub fn set_subscriptions(
+        &mut self,
+        subscriptions: impl IntoIterator<Item = (DevAddr, OutIdx)>,
+    ) -> Result<(), LoadError> {
+        let mut subscribers = [0u64; MAX_OUTPUTS];
+        for (device, out_idx) in subscriptions {
+            if device as usize >= MAX_DEVICES || out_idx as usize >= MAX_OUTPUTS {
+                return Err(LoadError::InvalidSubscription(device, out_idx));
+            }
+            subscribers[out_idx as usize] |= 1 << device;
+        }
+        self.subscribers = subscribers;
+        Ok(())
+    }
+
+    /// Verify and load the program, then execute setup procedure 0. Program
+    /// must fit into the executor program memory and procedure table.
+    /// Remote devices can't set outputs until `set_subscriptions`.
+    pub async fn load_static(&mut self, program: &[Opcode]) -> Result<(), LoadError> {
+        if program.len() > PL {
+            return Err(LoadError::TooLong {
+                length: program.len(),
+                capacity: PL,
+            });
+        }
+        for opcode in program {
+            if let Opcode::Start(proc) = opcode {
+                if *proc as usize >= PN {
+                    return Err(LoadError::TooManyProcedures(*proc));
+                }
+            }
+        }
+        let mut first_problem = None;
+        verifier::check(program, |diagnostic| {
+            first_problem.get_or_insert(diagnostic);
+        });
+        if let Some(diagnostic) = first_problem {
+            return Err(LoadError::Invalid(diagnostic));
         }
+
+        self.opcodes = [Opcode::Noop; PL];
+        self.opcodes[..program.len()].copy_from_slice(program);
         self.index_code();
-        self.execute(0).await;
+        self.threads.clear();
+        self.retrigger = [Retrigger::default(); PN];
+        self.variables = [0; VARIABLES];
+        self.error_handler = None;
+        self.subscribers = [0; MAX_OUTPUTS];
+        self.timers.clear();
+        self.zones.clear_membership();
+        if let Err(err) = self.execute(0).await {
+            self.fail(0, err).await;
+        }
         // Finish on default layer
-        self.layers.reset();
+        self.zones.reset();
+        Ok(())
     }
 
-    pub async fn emit(&self, command: Command) {
-        println!("Emiting {:?}", command);
-        // TODO: Maybe some timeout in case it breaks and we don't want to hang?
-        self.command_queue.send(command).await.unwrap();
+    pub async fn emit(&mut self, command: Command) -> Result<(), VmError> {
+        let deadline = self.deadline;
+        let send = self.command_queue.send(command);
+        let sent = match deadline {
+            Some(deadline) => timeout_at(deadline, send)
+                .await
+                .map_err(|_| VmError::TimeBudgetExceeded)?,
+            None => send.await,
+        };
+        sent.map_err(|_| VmError::QueueClosed)?;
+        self.track_output(command);
+        Ok(())
     }
 
-    /// Helper: Bind input/trigger to a call to a given procedure.
-    fn bind_proc(&mut self, idx: InIdx, trigger: Trigger, proc_idx: ProcIdx) {
+    /// Report aborted procedure and start the error handler with the error
+    /// code and the failed procedure as arguments.
+    async fn fail(&mut self, proc: ProcIdx, err: VmError) {
+        let code = ErrorCode::from(err);
+        if self.emit(Command::Error(code)).await.is_err() {
+            return;
+        }
+        let Some(handler) = self.error_handler else {
+            return;
+        };
+        // Failing handler is only reported, so it can't loop.
+        if handler == proc {
+            return;
+        }
+        if let Err(err) = self.spawn(handler, 0, [code as u8, proc]).await {
+            let _ = self.emit(Command::Error(err.into())).await;
+        }
+    }
+
+    /// Keep the output state in sync with emitted commands until the IO layer
+    /// reports the change.
+    fn track_output(&mut self, command: Command) {
+        let (out_idx, state) = match command {
+            Command::ToggleOutput(out_idx) => (out_idx, None),
+            Command::ActivateOutput(out_idx) => (out_idx, Some(true)),
+            Command::DeactivateOutput(out_idx) => (out_idx, Some(false)),
+            _ => return,
+        };
+        if let Some(output) = self.outputs.get_mut(out_idx as usize) {
+            *output = state.unwrap_or(!*output);
+        }
+    }
+
+    /// Activate layer in a zone and report a stack overflow. Overflow doesn't
+    /// abort the procedure.
+    async fn activate_layer(
+        &mut self,
+        zone: ZoneIdx,
+        in_idx: InIdx,
+        layer: LayerIdx,
+    ) -> Result<(), VmError> {
+        if self.zones.zone_mut(zone).activate(in_idx, layer).is_err() {
+            self.emit(Command::Error(ErrorCode::LayerStackOverflow)).await?;
+        }
+        Ok(())
+    }
+
+    /// Helper: Bind input/trigger to an action on the current layer of a zone.
+    fn bind(
+        &mut self,
+        zone: ZoneIdx,
+        idx: InIdx,
+        trigger: Trigger,
+        action: Action,
+    ) -> Result<(), VmError> {
         self.bindings.bind(Binding {
             idx,
             trigger,
-            layer: self.layers.current,
-            action: Action::Proc(proc_idx),
-        });
+            layer: self.zones.zone(zone).current,
+            action,
+        })?;
+        Ok(())
+    }
+
+    /// Helper: Bind input/trigger to a call to a given procedure.
+    fn bind_proc(
+        &mut self,
+        zone: ZoneIdx,
+        idx: InIdx,
+        trigger: Trigger,
+        proc_idx: ProcIdx,
+    ) -> Result<(), VmError> {
+        self.bind(zone, idx, trigger, Action::Proc(proc_idx))
     }
 
     /// Helper: Bind input/trigger to single command.
-    fn bind_single(&mut self, idx: InIdx, trigger: Trigger, command: Command) {
-        self.bindings.bind(Binding {
-            idx,
-            trigger,
-            layer: self.layers.current,
-            action: Action::Single(command),
-        });
+    fn bind_single(
+        &mut self,
+        zone: ZoneIdx,
+        idx: InIdx,
+        trigger: Trigger,
+        command: Command,
+    ) -> Result<(), VmError> {
+        self.bind(zone, idx, trigger, Action::Single(command))
+    }
+
+    /// Value of a local variable.
+    fn variable(&mut self, var: VarIdx) -> Result<&mut Word, VmError> {
+        self.variables
+            .get_mut(var as usize)
+            .ok_or(VmError::VariableOutOfRange)
     }
 
-    async fn execute_opcode(&mut self, opcode: Opcode) -> bool {
+    /// Execute opcode at the thread's pc.
+    async fn execute_opcode(
+        &mut self,
+        thread: &mut Thread,
+        opcode: Opcode,
+    ) -> Result<Flow, VmError> {
+        let pc = thread.pc;
+        let zone = thread.zone;
+        let registers = &mut thread.registers;
         match opcode {
             Opcode::Noop => { /* Noop */ }
             Opcode::Stop => {
-                return true;
+                return Ok(Flow::Return);
             }
             Opcode::Start(_) => {
-                panic!("Invalid opcode: Start");
+                return Err(VmError::InvalidOpcode);
             }
             Opcode::Call(proc_id) => {
-                // TODO: Own stack?
-                Box::pin(self.execute(proc_id)).await;
+                return Ok(Flow::Call(proc_id, [0; 2]));
+            }
+            Opcode::CallWith(proc_id, first, second) => {
+                return Ok(Flow::Call(proc_id, [first, second]));
             }
 
             Opcode::Toggle(out_idx) => {
-                self.emit(Command::ToggleOutput(out_idx)).await;
+                self.emit(Command::ToggleOutput(output(out_idx)?)).await?;
             }
             Opcode::Activate(out_idx) => {
-                self.emit(Command::ActivateOutput(out_idx)).await;
+                self.emit(Command::ActivateOutput(output(out_idx)?)).await?;
             }
             Opcode::Deactivate(out_idx) => {
-                self.emit(Command::DeactivateOutput(out_idx)).await;
+                self.emit(Command::DeactivateOutput(output(out_idx)?)).await?;
+            }
+            Opcode::SetRemote(dev, out_idx, state) => {
+                let command = Command::SetRemoteOutput(dev, output(out_idx)?, state);
+                self.emit(command).await?;
+            }
+            Opcode::ActivateFor(out_idx, ms) => {
+                let out_idx = output(out_idx)?;
+                let deadline = Instant::now() + Duration::from_millis(ms as u64);
+                self.timers
+                    .schedule(out_idx, deadline)
+                    .map_err(|_| VmError::TimersFull)?;
+                self.emit(Command::ActivateOutput(out_idx)).await?;
+            }
+            Opcode::Wait(ms) => {
+                return Ok(Flow::Wait(Duration::from_millis(ms as u64)));
+            }
+            Opcode::WaitForRelease(ms) => {
+                return Ok(Flow::WaitForRelease(Duration::from_millis(ms as u64)));
             }
 
             // Enable a layer (TODO: push layer onto a layer stack?)
             Opcode::LayerPush(layer) => {
-                assert!(layer as usize <= MAX_LAYERS);
                 // Use a `virtual` input idx of 0 when forcing a layer activation.
-                self.layers.activate(0, layer);
+                self.activate_layer(zone, 0, layer_idx(layer)?).await?;
             }
             Opcode::LayerPop => {
                 // Deactivate last virtual 0 input.
-                self.layers.maybe_deactivate(0);
+                self.zones.zone_mut(zone).maybe_deactivate(0);
             }
             Opcode::LayerSet(layer) => {
-                self.layers.reset();
-                self.layers.activate(0, layer);
+                let layer = layer_idx(layer)?;
+                self.zones.zone_mut(zone).reset();
+                self.activate_layer(zone, 0, layer).await?;
             }
 
             // Clear the layer stack - back to default layer.
             Opcode::LayerDefault => {
-                self.layers.reset();
+                self.zones.zone_mut(zone).reset();
+            }
+
+            Opcode::ZoneAssign(in_idx, zone) => {
+                self.zones.assign(in_idx, zone)?;
+            }
+            Opcode::ZoneSelect(zone) => {
+                thread.zone = zone_idx(zone)?;
             }
 
-            // WaitForRelease - maybe?
             // Procedure 0 is executed after loading and it can map the actions initially
 
             // Clear all the bindings.
@@ -118,22 +491,26 @@ impl<const BN: usize> Executor<BN> {
             }
 
             Opcode::BindShortCall(in_idx, proc_idx) => {
-                self.bind_proc(in_idx, Trigger::ShortClick, proc_idx);
+                self.bind_proc(zone, in_idx, Trigger::ShortClick, proc_idx)?;
             }
             Opcode::BindLongCall(in_idx, proc_idx) => {
-                self.bind_proc(in_idx, Trigger::LongClick, proc_idx);
+                self.bind_proc(zone, in_idx, Trigger::LongClick, proc_idx)?;
             }
             Opcode::BindActivateCall(in_idx, proc_idx) => {
-                self.bind_proc(in_idx, Trigger::Activated, proc_idx);
+                self.bind_proc(zone, in_idx, Trigger::Activated, proc_idx)?;
             }
             Opcode::BindDeactivateCall(in_idx, proc_idx) => {
-                self.bind_proc(in_idx, Trigger::Deactivated, proc_idx);
+                self.bind_proc(zone, in_idx, Trigger::Deactivated, proc_idx)?;
             }
             Opcode::BindLongActivate(in_idx, proc_idx) => {
-                self.bind_proc(in_idx, Trigger::LongActivated, proc_idx);
+                self.bind_proc(zone, in_idx, Trigger::LongActivated, proc_idx)?;
             }
             Opcode::BindLongDeactivate(in_idx, proc_idx) => {
-                self.bind_proc(in_idx, Trigger::LongDeactivated, proc_idx);
+                self.bind_proc(zone, in_idx, Trigger::LongDeactivated, proc_idx)?;
+            }
+            Opcode::BindCallWith(in_idx, trigger, proc_idx, first, second) => {
+                let action = Action::ProcWith(proc_idx, [first, second]);
+                self.bind(zone, in_idx, trigger, action)?;
             }
 
             /*
@@ -141,61 +518,389 @@ impl<const BN: usize> Executor<BN> {
              */
             // Trivial configuration shortcuts.
             Opcode::BindShortToggle(in_idx, out_idx) => {
-                self.bind_single(in_idx, Trigger::ShortClick, Command::ToggleOutput(out_idx));
+                let command = Command::ToggleOutput(output(out_idx)?);
+                self.bind_single(zone, in_idx, Trigger::ShortClick, command)?;
             }
 
             Opcode::BindLongToggle(in_idx, out_idx) => {
-                self.bind_single(in_idx, Trigger::LongClick, Command::ToggleOutput(out_idx));
+                let command = Command::ToggleOutput(output(out_idx)?);
+                self.bind_single(zone, in_idx, Trigger::LongClick, command)?;
             }
 
-            Opcode::BindLayerHold(in_idx, layer_idx) => {
+            Opcode::BindLayerHold(in_idx, layer) => {
                 // When this is in use + ShortClick is defined for the same key,
                 // then the shortclick should be defined on new layer.
                 self.bind_single(
+                    zone,
                     in_idx,
                     Trigger::Activated,
-                    Command::ActivateLayer(layer_idx),
-                );
+                    Command::ActivateLayer(layer_idx(layer)?),
+                )?;
 
                 // NOTE: Layer deactivation is handled automatically and should
                 // not be bound.
-            } // Hypothetical?
-              // Read input value (local) into register
-              /*
-                  Opcode::ReadInput(in_idx) => {
-              },
-                  /// Read input value (local) into register
-                  Opcode::ReadOutput(OutIdx) => {
-              },
-                  /// Call first if register is True, second one if False.
-                  Opcode::CallConditionally(proc_idx, proc_idx) => {
-              },
-                   */
-        }
-        false
-    }
-
-    pub async fn execute(&mut self, proc: ProcIdx) {
-        let mut pc = self.procedures[proc as usize];
-        assert_eq!(self.opcodes[pc], Opcode::Start(proc));
+            }
+
+            /*
+             * Registers
+             */
+            Opcode::Load(reg, value) => {
+                *register(registers, reg)? = value as Word;
+            }
+            Opcode::ReadInput(reg, in_idx) => {
+                let active = self.inputs.get(in_idx as usize);
+                *register(registers, reg)? = *active.ok_or(VmError::InputOutOfRange)? as Word;
+            }
+            Opcode::ReadOutput(reg, out_idx) => {
+                let active = self.outputs.get(out_idx as usize);
+                *register(registers, reg)? = *active.ok_or(VmError::OutputOutOfRange)? as Word;
+            }
+            Opcode::Eq(first, second) => compute(registers, first, second, |a, b| a == b)?,
+            Opcode::Lt(first, second) => compute(registers, first, second, |a, b| a < b)?,
+            Opcode::Gt(first, second) => compute(registers, first, second, |a, b| a > b)?,
+            Opcode::And(first, second) => {
+                compute(registers, first, second, |a, b| a != 0 && b != 0)?
+            }
+            Opcode::Or(first, second) => {
+                compute(registers, first, second, |a, b| a != 0 || b != 0)?
+            }
+            Opcode::Not(reg) => {
+                let value = register(registers, reg)?;
+                *value = (*value == 0) as Word;
+            }
+
+            /*
+             * Variables
+             */
+            Opcode::Set(var, value) => {
+                *self.variable(var)? = value;
+            }
+            Opcode::Inc(var) => {
+                let variable = self.variable(var)?;
+                *variable = variable.wrapping_add(1);
+            }
+            Opcode::Dec(var) => {
+                let variable = self.variable(var)?;
+                *variable = variable.wrapping_sub(1);
+            }
+            Opcode::Add(var, value) => {
+                let variable = self.variable(var)?;
+                *variable = variable.wrapping_add(value);
+            }
+            Opcode::Mod(var, value) => {
+                let variable = self.variable(var)?;
+                *variable = variable.checked_rem(value).ok_or(VmError::ModuloByZero)?;
+            }
+            Opcode::LoadVar(reg, var) => {
+                *register(registers, reg)? = *self.variable(var)?;
+            }
+            Opcode::StoreVar(var, reg) => {
+                *self.variable(var)? = *register(registers, reg)?;
+            }
+            Opcode::VarEq(reg, var, value) => {
+                *register(registers, reg)? = (*self.variable(var)? == value) as Word;
+            }
+            Opcode::VarLt(reg, var, value) => {
+                *register(registers, reg)? = (*self.variable(var)? < value) as Word;
+            }
+            Opcode::VarGt(reg, var, value) => {
+                *register(registers, reg)? = (*self.variable(var)? > value) as Word;
+            }
+
+            /*
+             * Branching. Relative offsets are counted from the jump opcode.
+             */
+            Opcode::Jump(offset) => {
+                return Ok(Flow::Jump(relative(pc, offset)));
+            }
+            Opcode::JumpTo(target) => {
+                return Ok(Flow::Jump(target as usize));
+            }
+            Opcode::JumpIfZero(reg, offset) => {
+                if *register(registers, reg)? == 0 {
+                    return Ok(Flow::Jump(relative(pc, offset)));
+                }
+            }
+            Opcode::JumpIfNotZero(reg, offset) => {
+                if *register(registers, reg)? != 0 {
+                    return Ok(Flow::Jump(relative(pc, offset)));
+                }
+            }
+            Opcode::Loop(reg, offset) => {
+                let counter = register(registers, reg)?;
+                *counter = counter.saturating_sub(1);
+                if *counter != 0 {
+                    return Ok(Flow::Jump(relative(pc, offset)));
+                }
+            }
+
+            Opcode::CallConditionally(reg, if_true, if_false) => {
+                if *register(registers, reg)? != 0 {
+                    return Ok(Flow::Call(if_true, [0; 2]));
+                } else {
+                    return Ok(Flow::Call(if_false, [0; 2]));
+                }
+            }
+
+            /*
+             * Threads
+             */
+            Opcode::SetRetrigger(proc_idx, policy) => {
+                self.proc_start(proc_idx)?;
+                self.retrigger[proc_idx as usize] = policy;
+            }
+            Opcode::Cancel(proc_idx) => {
+                self.threads.cancel_proc(proc_idx);
+            }
+
+            Opcode::Syscall(id, arg) => {
+                let handler = self.syscalls.get(id).ok_or(VmError::UnknownSyscall)?;
+                *register(registers, 0)? = handler(arg)?;
+            }
+
+            /*
+             * Errors
+             */
+            Opcode::SetErrorHandler(proc_idx) => {
+                self.proc_start(proc_idx)?;
+                self.error_handler = Some(proc_idx);
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    /// Address of the procedure start.
+    fn proc_start(&self, proc: ProcIdx) -> Result<usize, VmError> {
+        let pc = *self
+            .procedures
+            .get(proc as usize)
+            .ok_or(VmError::UnknownProcedure)?;
+        if self.opcodes[pc] != Opcode::Start(proc) {
+            return Err(VmError::UnknownProcedure);
+        }
+        Ok(pc)
+    }
+
+    /// Run thread until its procedure returns or it starts waiting. Returns
+    /// the time to resume a waiting thread. Nested calls use thread's own
+    /// call stack instead of recursion. Execution is aborted when exceeding
+    /// the budget, also while blocked on emitting a command.
+    async fn run_thread(&mut self, thread: &mut Thread) -> Result<Option<Instant>, VmError> {
+        let deadline = Instant::now() + self.budget.time;
+        let outer = self.deadline.replace(deadline);
+        let result = self.run_steps(thread, deadline).await;
+        self.deadline = outer;
+        result
+    }
+
+    async fn run_steps(
+        &mut self,
+        thread: &mut Thread,
+        deadline: Instant,
+    ) -> Result<Option<Instant>, VmError> {
+        let mut steps: u32 = 0;
+        thread.wake_at = None;
+        thread.wait_release = false;
+        loop {
+            steps += 1;
+            if steps > self.budget.instructions {
+                return Err(VmError::InstructionBudgetExceeded);
+            }
+
+            let opcode = *self.opcodes.get(thread.pc).ok_or(VmError::EndOfProgram)?;
+            // Reaching the Stop in time finishes the procedure.
+            if opcode != Opcode::Stop && Instant::now() > deadline {
+                return Err(VmError::TimeBudgetExceeded);
+            }
+            let opcode = opcode.with_args(thread.args);
+            match self.execute_opcode(thread, opcode).await? {
+                Flow::Next => thread.pc += 1,
+                Flow::Call(proc_id, args) => {
+                    thread.stack.push(thread.pc + 1, thread.args)?;
+                    thread.pc = self.proc_start(proc_id)? + 1;
+                    thread.args = args;
+                }
+                Flow::Return => match thread.stack.pop() {
+                    Some((return_pc, args)) => {
+                        thread.pc = return_pc;
+                        thread.args = args;
+                    }
+                    None => return Ok(None),
+                },
+                Flow::Jump(target) => thread.pc = target,
+                Flow::Wait(duration) => {
+                    thread.pc += 1;
+                    let wake_at = Instant::now() + duration;
+                    thread.wake_at = Some(wake_at);
+                    return Ok(Some(wake_at));
+                }
+                Flow::WaitForRelease(timeout) => {
+                    thread.pc += 1;
+                    let held = self.inputs.get(thread.input as usize);
+                    if held != Some(&true) {
+                        continue;
+                    }
+                    let wake_at = Instant::now() + timeout;
+                    thread.wake_at = Some(wake_at);
+                    thread.wait_release = true;
+                    return Ok(Some(wake_at));
+                }
+            }
+        }
+    }
+
+    /// Execute procedure until it returns, waiting in place when procedure
+    /// waits. Output timers and other threads are handled meanwhile.
+    pub async fn execute(&mut self, proc: ProcIdx) -> Result<(), VmError> {
+        let mut thread = Thread::new(proc, self.proc_start(proc)?, 0, 0, [0; 2]);
+        while let Some(wake_at) = self.run_thread(&mut thread).await? {
+            self.wait_until(wake_at).await;
+        }
+        Ok(())
+    }
+
+    /// Start procedure triggered by an input as a thread, respecting its
+    /// retrigger policy. Runs it until it finishes or waits, so it doesn't
+    /// block handling of other events. Returns handle of a waiting thread.
+    pub async fn spawn(
+        &mut self,
+        proc: ProcIdx,
+        in_idx: InIdx,
+        args: Args,
+    ) -> Result<Option<ThreadHandle>, VmError> {
+        let start = self.proc_start(proc)?;
+        let running = self.threads.find_proc(proc).is_some();
+        match self.retrigger[proc as usize] {
+            Retrigger::Parallel => {}
+            Retrigger::Restart => self.threads.cancel_proc(proc),
+            Retrigger::Ignore if running => return Ok(None),
+            Retrigger::Ignore => {}
+            Retrigger::Cancel if running => {
+                self.threads.cancel_proc(proc);
+                return Ok(None);
+            }
+            Retrigger::Cancel => {}
+        }
+
+        // Procedure operates on layers of the input's zone.
+        let zone = self.zones.zone_of(in_idx);
+        let mut thread = Thread::new(proc, start, zone, in_idx, args);
+        match self.run_thread(&mut thread).await? {
+            None => Ok(None),
+            Some(_) => self.threads.insert(thread).map(Some),
+        }
+    }
+
+    /// Cancel a waiting procedure. Returns false if it has already finished.
+    pub fn cancel(&mut self, handle: ThreadHandle) -> bool {
+        self.threads.cancel(handle)
+    }
+
+    /// Is procedure still running (waiting)?
+    pub fn is_running(&self, handle: ThreadHandle) -> bool {
+        self.threads.is_running(handle)
+    }
+
+    /// Start procedure and handle its failure.
+    async fn trigger(&mut self, proc: ProcIdx, in_idx: InIdx, args: Args) {
+        if let Err(err) = self.spawn(proc, in_idx, args).await {
+            self.fail(proc, err).await;
+        }
+    }
+
+    /// Sleep until the deadline while handling output timers and threads.
+    async fn wait_until(&mut self, deadline: Instant) {
+        loop {
+            let wake = match self.next_deadline() {
+                Some(timer) if timer < deadline => timer,
+                _ => deadline,
+            };
+            sleep_until(wake).await;
+            self.process_timers().await;
+            if wake == deadline {
+                return;
+            }
+        }
+    }
+
+    /// Earliest time at which `process_timers` has something to do.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        match (self.timers.next_deadline(), self.threads.next_wake()) {
+            (Some(timer), Some(wake)) => Some(timer.min(wake)),
+            (timer, wake) => timer.or(wake),
+        }
+    }
+
+    /// Deactivate outputs which activation time has passed and resume threads
+    /// which finished waiting.
+    pub async fn process_timers(&mut self) {
+        let now = Instant::now();
+        while let Some(out_idx) = self.timers.pop_expired(now) {
+            // Nothing to report to when the queue is closed.
+            let _ = self.emit(Command::DeactivateOutput(out_idx)).await;
+        }
+
+        // Each thread is resumed at most once, even if it waits for 0ms.
+        for slot in 0..MAX_THREADS {
+            if self.threads.is_due(slot, now) {
+                self.resume(slot).await;
+            }
+        }
+    }
+
+    /// Resume threads waiting for release of the input.
+    async fn release(&mut self, in_idx: InIdx) {
+        for slot in 0..MAX_THREADS {
+            if self.threads.is_released(slot, in_idx) {
+                self.resume(slot).await;
+            }
+        }
+    }
+
+    /// Run thread in the slot until it finishes or waits again.
+    async fn resume(&mut self, slot: usize) {
+        let Some(mut thread) = self.threads.take(slot) else {
+            return;
+        };
+        match self.run_thread(&mut thread).await {
+            Ok(None) => {}
+            Ok(Some(_)) => self.threads.restore(slot, thread),
+            Err(err) => self.fail(thread.proc, err).await,
+        }
+    }
+
+    /// Handle incoming events, output timers and threads until the event
+    /// channel is closed.
+    pub async fn serve(&mut self, events: &mut mpsc::Receiver<Event>) {
         loop {
-            pc += 1;
-            let opcode = self.opcodes[pc];
-            if self.execute_opcode(opcode).await {
-                break;
+            let event = match self.next_deadline() {
+                Some(deadline) => tokio::select! {
+                    event = events.recv() => event,
+                    _ = sleep_until(deadline) => {
+                        self.process_timers().await;
+                        continue;
+                    }
+                },
+                None => events.recv().await,
+            };
+            match event {
+                Some(event) => self.parse_event(&event).await,
+                None => return,
             }
         }
     }
 
     /// Index procedures starts
     fn index_code(&mut self) {
-        for i in 0..MAX_PROCEDURES {
+        for i in 0..PN {
             self.procedures[i] = 0;
         }
 
         for (idx, opcode) in self.opcodes.iter().enumerate() {
             if let Opcode::Start(proc_idx) = opcode {
-                self.procedures[*proc_idx as usize] = idx;
+                if let Some(start) = self.procedures.get_mut(*proc_idx as usize) {
+                    *start = idx;
+                }
             }
         }
     }
@@ -204,7 +909,20 @@ impl<const BN: usize> Executor<BN> {
     pub async fn parse_event(&mut self, event: &Event) {
         match event {
             Event::ButtonTrigger(data) => {
-                if data.trigger == Trigger::Deactivated && self.layers.maybe_deactivate(data.in_idx)
+                if let Some(input) = self.inputs.get_mut(data.in_idx as usize) {
+                    match data.trigger {
+                        Trigger::Activated => *input = true,
+                        Trigger::Deactivated => *input = false,
+                        _ => {}
+                    }
+                }
+                if data.trigger == Trigger::Deactivated {
+                    self.release(data.in_idx).await;
+                }
+
+                let zone = self.zones.zone_of(data.in_idx);
+                if data.trigger == Trigger::Deactivated
+                    && self.zones.zone_mut(zone).maybe_deactivate(data.in_idx)
                 {
                     // Deactivated layer that was previously activated using
                     // this key. TODO: Warning! Event order might be important.
@@ -214,37 +932,104 @@ impl<const BN: usize> Executor<BN> {
 
                 let binding = self.bindings.filter(
                     data.in_idx,
-                    Some(self.layers.current),
+                    Some(self.zones.zone(zone).current),
                     Some(data.trigger),
                 );
                 if let Some(binding) = binding {
-                    println!("Found matching event {:?}", binding.action);
-
                     match binding.action {
                         Action::Noop => {}
-                        Action::Single(cmd) => match cmd {
-                            Command::ActivateLayer(layer) => {
-                                self.layers.activate(data.in_idx, layer);
-                                // self.current_layer = layer
-                            }
-                            Command::DeactivateLayer(layer) => {
-                                todo!("deactivation is based on stack list");
-                                // self.current_layer = 0
-                            }
-                            _ => self.emit(cmd).await,
-                        },
+                        Action::Single(cmd) => {
+                            let result = match cmd {
+                                Command::ActivateLayer(layer) => {
+                                    self.activate_layer(zone, data.in_idx, layer).await
+                                }
+                                Command::DeactivateLayer(_layer) => {
+                                    // Deactivation is based on stack list:
+                                    // drop layer activated by this input.
+                                    self.zones.zone_mut(zone).maybe_deactivate(data.in_idx);
+                                    Ok(())
+                                }
+                                _ => self.emit(cmd).await,
+                            };
+                            // Fails only when the command queue is closed,
+                            // there is nobody to report to.
+                            let _ = result;
+                        }
                         Action::Proc(proc_idx) => {
-                            self.execute(proc_idx).await;
+                            self.trigger(proc_idx, data.in_idx, [0; 2]).await;
+                        }
+                        Action::ProcWith(proc_idx, args) => {
+                            self.trigger(proc_idx, data.in_idx, args).await;
                         }
                     }
-                } else {
-                    println!("Not found binding {:?}!", data);
                 }
             }
+            Event::OutputChanged(change) => {
+                if let Some(output) = self.outputs.get_mut(change.out_idx as usize) {
+                    *output = change.active;
+                }
+            }
+            Event::RemoteOutput(remote) => {
+                let allowed = (remote.device as usize) < MAX_DEVICES
+                    && self
+                        .subscribers
+                        .get(remote.out_idx as usize)
+                        .is_some_and(|devices| devices & (1 << remote.device) != 0);
+                let cmd = match (allowed, remote.state) {
+                    (false, _) => Command::Error(ErrorCode::RemoteOutputDenied),
+                    (true, OutputState::On) => Command::ActivateOutput(remote.out_idx),
+                    (true, OutputState::Off) => Command::DeactivateOutput(remote.out_idx),
+                    (true, OutputState::Toggle) => Command::ToggleOutput(remote.out_idx),
+                };
+                let _ = self.emit(cmd).await;
+            }
         }
     }
 }
 
+/// Register of a thread.
+fn register(registers: &mut [Word; REGISTERS], reg: RegIdx) -> Result<&mut Word, VmError> {
+    registers
+        .get_mut(reg as usize)
+        .ok_or(VmError::RegisterOutOfRange)
+}
+
+/// Store boolean result of a two-register operation in the first register.
+fn compute(
+    registers: &mut [Word; REGISTERS],
+    first: RegIdx,
+    second: RegIdx,
+    op: impl Fn(Word, Word) -> bool,
+) -> Result<(), VmError> {
+    let second = *register(registers, second)?;
+    let first = register(registers, first)?;
+    *first = op(*first, second) as Word;
+    Ok(())
+}
+
+/// Local output, possibly passed as an argument.
+fn output(out_idx: OutIdx) -> Result<OutIdx, VmError> {
+    if out_idx as usize >= MAX_OUTPUTS {
+        return Err(VmError::OutputOutOfRange);
+    }
+    Ok(out_idx)
+}
+
+/// Layer, possibly passed as an argument.
+fn layer_idx(layer: LayerIdx) -> Result<LayerIdx, VmError> {
+    if layer as usize >= MAX_LAYERS {
+        return Err(VmError::LayerOutOfRange);
+    }
+    Ok(layer)
+}
+
+fn zone_idx(zone: ZoneIdx) -> Result<ZoneIdx, VmError> {
+    if zone as usize >= MAX_ZONES {
+        return Err(VmError::ZoneOutOfRange);
+    }
+    Ok(zone)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -274,7 +1059,7 @@ mod tests {
 
         let (event_src, event_handler) = mpsc::channel(32);
         let mut executor: Executor<30> = Executor::new(event_src);
-        executor.load_static(&PROGRAM).await;
+        executor.load_static(&PROGRAM).await.unwrap();
 
         (executor, event_handler)
     }
@@ -355,4 +1140,1012 @@ mod tests {
 
         // TODO: Multiple layers test.
     }
+
+    #[tokio::test]
+    async fn it_calls_nested_procedures() {
+        const PROGRAM: [Opcode; 13] = [
+            Opcode::Start(0),
+            Opcode::BindShortCall(1, 1),
+            Opcode::Stop,
+            Opcode::Start(1),
+            Opcode::Call(2),
+            Opcode::Activate(11),
+            Opcode::Stop,
+            Opcode::Start(2),
+            Opcode::Activate(12),
+            Opcode::Call(3),
+            Opcode::Stop,
+            Opcode::Start(3),
+            Opcode::Stop,
+        ];
+
+        let (event_src, mut event_handler) = mpsc::channel(32);
+        let mut executor: Executor<30> = Executor::new(event_src);
+        executor.load_static(&PROGRAM).await.unwrap();
+
+        executor
+            .parse_event(&Event::new_button_trigger(1, Trigger::ShortClick))
+            .await;
+        assert_eq!(event_handler.recv().await.unwrap(), Command::ActivateOutput(12));
+        assert_eq!(event_handler.recv().await.unwrap(), Command::ActivateOutput(11));
+        assert!(event_handler.is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_passes_procedure_arguments() {
+        const PROGRAM: [Opcode; 12] = [
+            Opcode::Start(0),
+            Opcode::BindCallWith(1, Trigger::ShortClick, 1, 7, 8),
+            Opcode::BindCallWith(2, Trigger::LongClick, 2, 5, 6),
+            Opcode::Stop,
+            Opcode::Start(1),
+            Opcode::CallWith(2, ARG1, 3),
+            Opcode::Toggle(ARG0),
+            Opcode::Stop,
+            Opcode::Start(2),
+            Opcode::Toggle(ARG0),
+            Opcode::Activate(ARG1),
+            Opcode::Stop,
+        ];
+
+        let (event_src, mut events) = mpsc::channel(32);
+        let mut executor: Executor<30> = Executor::new(event_src);
+        executor.load_static(&PROGRAM).await.unwrap();
+
+        executor
+            .parse_event(&Event::new_button_trigger(2, Trigger::LongClick))
+            .await;
+        assert_eq!(events.recv().await.unwrap(), Command::ToggleOutput(5));
+        assert_eq!(events.recv().await.unwrap(), Command::ActivateOutput(6));
+
+        // Caller arguments are restored after the call returns.
+        executor
+            .parse_event(&Event::new_button_trigger(1, Trigger::ShortClick))
+            .await;
+        assert_eq!(events.recv().await.unwrap(), Command::ToggleOutput(8));
+        assert_eq!(events.recv().await.unwrap(), Command::ActivateOutput(3));
+        assert_eq!(events.recv().await.unwrap(), Command::ToggleOutput(7));
+        assert!(events.is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_limits_call_depth() {
+        // Chain of procedures calling the next one, one too many.
+        let mut program = vec![Opcode::Start(0), Opcode::BindShortCall(1, 1), Opcode::Stop];
+        let last = MAX_CALL_DEPTH as ProcIdx + 2;
+        for proc in 1..last {
+            program.extend([Opcode::Start(proc), Opcode::Call(proc + 1), Opcode::Stop]);
+        }
+        program.extend([Opcode::Start(last), Opcode::Activate(1), Opcode::Stop]);
+
+        let (event_src, mut event_handler) = mpsc::channel(32);
+        let mut executor: Executor<30> = Executor::new(event_src);
+        executor.load_static(&program).await.unwrap();
+
+        assert_eq!(executor.execute(1).await, Err(VmError::StackOverflow));
+        assert_eq!(executor.execute(2).await, Ok(()));
+        assert_eq!(event_handler.recv().await.unwrap(), Command::ActivateOutput(1));
+
+        executor
+            .parse_event(&Event::new_button_trigger(1, Trigger::ShortClick))
+            .await;
+        assert_eq!(
+            event_handler.recv().await.unwrap(),
+            Command::Error(ErrorCode::StackOverflow)
+        );
+        assert!(event_handler.is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_sets_outputs_for_subscribed_devices() {
+        const PROGRAM: [Opcode; 2] = [Opcode::Start(0), Opcode::Stop];
+
+        let (event_src, mut events) = mpsc::channel(32);
+        let mut executor: Executor<30> = Executor::new(event_src);
+        executor.load_static(&PROGRAM).await.unwrap();
+        assert_eq!(
+            executor.set_subscriptions([(3, 5), (MAX_DEVICES as DevAddr, 5)]),
+            Err(LoadError::InvalidSubscription(MAX_DEVICES as DevAddr, 5))
+        );
+        executor.set_subscriptions([(3, 5), (4, 6)]).unwrap();
+
+        executor.parse_event(&Event::new_remote_output(3, 5, OutputState::On)).await;
+        assert_eq!(events.recv().await.unwrap(), Command::ActivateOutput(5));
+        executor.parse_event(&Event::new_remote_output(4, 6, OutputState::Toggle)).await;
+        assert_eq!(events.recv().await.unwrap(), Command::ToggleOutput(6));
+        executor.parse_event(&Event::new_remote_output(4, 5, OutputState::Off)).await;
+        assert_eq!(
+            events.recv().await.unwrap(),
+            Command::Error(ErrorCode::RemoteOutputDenied)
+        );
+
+        // Reloading the program drops the subscriptions.
+        executor.load_static(&PROGRAM).await.unwrap();
+        executor.parse_event(&Event::new_remote_output(3, 5, OutputState::On)).await;
+        assert_eq!(
+            events.recv().await.unwrap(),
+            Command::Error(ErrorCode::RemoteOutputDenied)
+        );
+        assert!(events.is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_calls_conditionally() {
+        const PROGRAM: [Opcode; 21] = [
+            Opcode::Start(0),
+            Opcode::BindShortCall(1, 1),
+            Opcode::BindShortCall(2, 4),
+            Opcode::Stop,
+            // If any kitchen light is on, turn all off, else turn main on.
+            Opcode::Start(1),
+            Opcode::ReadOutput(0, 10),
+            Opcode::ReadOutput(1, 11),
+            Opcode::Or(0, 1),
+            Opcode::CallConditionally(0, 2, 3),
+            Opcode::Stop,
+            Opcode::Start(2),
+            Opcode::Deactivate(10),
+            Opcode::Deactivate(11),
+            Opcode::Stop,
+            Opcode::Start(3),
+            Opcode::Activate(10),
+            Opcode::Stop,
+            // Is input 1 held?
+            Opcode::Start(4),
+            Opcode::ReadInput(0, 1),
+            Opcode::CallConditionally(0, 3, 2),
+            Opcode::Stop,
+        ];
+
+        let (event_src, mut event_handler) = mpsc::channel(32);
+        let mut executor: Executor<30> = Executor::new(event_src);
+        executor.load_static(&PROGRAM).await.unwrap();
+
+        // All off -> main on.
+        executor
+            .parse_event(&Event::new_button_trigger(1, Trigger::ShortClick))
+            .await;
+        assert_eq!(event_handler.recv().await.unwrap(), Command::ActivateOutput(10));
+
+        // Island reported on by IO -> all off.
+        executor.parse_event(&Event::new_output_changed(11, true)).await;
+        executor
+            .parse_event(&Event::new_button_trigger(1, Trigger::ShortClick))
+            .await;
+        assert_eq!(event_handler.recv().await.unwrap(), Command::DeactivateOutput(10));
+        assert_eq!(event_handler.recv().await.unwrap(), Command::DeactivateOutput(11));
+
+        executor
+            .parse_event(&Event::new_button_trigger(1, Trigger::Activated))
+            .await;
+        executor
+            .parse_event(&Event::new_button_trigger(2, Trigger::ShortClick))
+            .await;
+        assert_eq!(event_handler.recv().await.unwrap(), Command::ActivateOutput(10));
+        executor
+            .parse_event(&Event::new_button_trigger(1, Trigger::Deactivated))
+            .await;
+        executor
+            .parse_event(&Event::new_button_trigger(2, Trigger::ShortClick))
+            .await;
+        assert_eq!(event_handler.recv().await.unwrap(), Command::DeactivateOutput(10));
+        assert_eq!(event_handler.recv().await.unwrap(), Command::DeactivateOutput(11));
+        assert!(event_handler.is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_computes_on_registers() {
+        const PROGRAM: [Opcode; 23] = [
+            Opcode::Start(0),
+            Opcode::Stop,
+            Opcode::Start(1),
+            Opcode::Load(0, 3),
+            Opcode::Load(1, 5),
+            Opcode::Load(2, 3),
+            Opcode::Lt(2, 1),
+            Opcode::CallConditionally(2, 2, 3),
+            Opcode::Load(2, 3),
+            Opcode::Gt(2, 1),
+            Opcode::CallConditionally(2, 2, 3),
+            Opcode::Eq(0, 1),
+            Opcode::Not(0),
+            Opcode::Load(1, 0),
+            Opcode::And(0, 1),
+            Opcode::CallConditionally(0, 2, 3),
+            Opcode::Stop,
+            Opcode::Start(2),
+            Opcode::Activate(1),
+            Opcode::Stop,
+            Opcode::Start(3),
+            Opcode::Deactivate(1),
+            Opcode::Stop,
+        ];
+
+        let (event_src, mut event_handler) = mpsc::channel(32);
+        let mut executor: Executor<30> = Executor::new(event_src);
+        executor.load_static(&PROGRAM).await.unwrap();
+        executor.execute(1).await.unwrap();
+        // 3 < 5, !(3 > 5), !(!(3 == 5) && 0)
+        assert_eq!(event_handler.recv().await.unwrap(), Command::ActivateOutput(1));
+        assert_eq!(event_handler.recv().await.unwrap(), Command::DeactivateOutput(1));
+        assert_eq!(event_handler.recv().await.unwrap(), Command::DeactivateOutput(1));
+        assert!(event_handler.is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_cycles_scenes_with_variables() {
+        // Press 1: main, press 2: main + island, press 3: off.
+        const PROGRAM: [Opcode; 19] = [
+            Opcode::Start(0),
+            Opcode::BindShortCall(1, 1),
+            Opcode::Stop,
+            Opcode::Start(1),
+            Opcode::Inc(0),
+            Opcode::Mod(0, 3),
+            Opcode::VarEq(0, 0, 1),
+            Opcode::JumpIfNotZero(0, 6),
+            Opcode::VarEq(0, 0, 2),
+            Opcode::JumpIfNotZero(0, 7),
+            Opcode::Deactivate(1),
+            Opcode::Deactivate(2),
+            Opcode::Jump(6),
+            Opcode::Activate(1),
+            Opcode::Deactivate(2),
+            Opcode::Jump(3),
+            Opcode::Activate(1),
+            Opcode::Activate(2),
+            Opcode::Stop,
+        ];
+
+        let (event_src, mut events) = mpsc::channel(32);
+        let mut executor: Executor<30> = Executor::new(event_src);
+        executor.load_static(&PROGRAM).await.unwrap();
+
+        let expected = [
+            [Command::ActivateOutput(1), Command::DeactivateOutput(2)],
+            [Command::ActivateOutput(1), Command::ActivateOutput(2)],
+            [Command::DeactivateOutput(1), Command::DeactivateOutput(2)],
+            [Command::ActivateOutput(1), Command::DeactivateOutput(2)],
+        ];
+        for commands in expected {
+            executor
+                .parse_event(&Event::new_button_trigger(1, Trigger::ShortClick))
+                .await;
+            assert_eq!(events.recv().await.unwrap(), commands[0]);
+            assert_eq!(events.recv().await.unwrap(), commands[1]);
+        }
+
+        // Loading a program clears the variables.
+        executor.load_static(&PROGRAM).await.unwrap();
+        executor
+            .parse_event(&Event::new_button_trigger(1, Trigger::ShortClick))
+            .await;
+        assert_eq!(events.recv().await.unwrap(), Command::ActivateOutput(1));
+    }
+
+    #[tokio::test]
+    async fn it_jumps_and_loops() {
+        const PROGRAM: [Opcode; 17] = [
+            Opcode::Start(0),
+            Opcode::Stop,
+            // Pulse output 5 times.
+            Opcode::Start(1),
+            Opcode::Load(0, 5),
+            Opcode::Activate(1),
+            Opcode::Deactivate(1),
+            Opcode::Loop(0, -2),
+            // Early return if input 1 is not active.
+            Opcode::ReadInput(1, 1),
+            Opcode::JumpIfZero(1, 8),
+            Opcode::JumpIfNotZero(1, 2),
+            Opcode::Toggle(3),
+            Opcode::Jump(2),
+            Opcode::Toggle(4),
+            Opcode::Toggle(5),
+            Opcode::JumpTo(16),
+            Opcode::Toggle(6),
+            Opcode::Stop,
+        ];
+
+        let (event_src, mut event_handler) = mpsc::channel(32);
+        let mut executor: Executor<30> = Executor::new(event_src);
+        executor.load_static(&PROGRAM).await.unwrap();
+
+        executor.execute(1).await.unwrap();
+        for _ in 0..5 {
+            assert_eq!(event_handler.recv().await.unwrap(), Command::ActivateOutput(1));
+            assert_eq!(event_handler.recv().await.unwrap(), Command::DeactivateOutput(1));
+        }
+        assert!(event_handler.is_empty());
+
+        executor
+            .parse_event(&Event::new_button_trigger(1, Trigger::Activated))
+            .await;
+        executor.execute(1).await.unwrap();
+        for _ in 0..5 {
+            event_handler.recv().await.unwrap();
+            event_handler.recv().await.unwrap();
+        }
+        assert_eq!(event_handler.recv().await.unwrap(), Command::ToggleOutput(5));
+        assert!(event_handler.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_deactivates_timed_outputs() {
+        const PROGRAM: [Opcode; 6] = [
+            Opcode::Start(0),
+            Opcode::BindShortCall(1, 1),
+            Opcode::Stop,
+            Opcode::Start(1),
+            Opcode::ActivateFor(5, 1000),
+            Opcode::Stop,
+        ];
+
+        let (event_src, mut event_handler) = mpsc::channel(32);
+        let mut executor: Executor<30> = Executor::new(event_src);
+        executor.load_static(&PROGRAM).await.unwrap();
+        let click = Event::new_button_trigger(1, Trigger::ShortClick);
+
+        executor.parse_event(&click).await;
+        assert_eq!(event_handler.recv().await.unwrap(), Command::ActivateOutput(5));
+        let first_deadline = executor.next_deadline().unwrap();
+
+        // Re-triggering extends the timer.
+        tokio::time::advance(Duration::from_millis(500)).await;
+        executor.parse_event(&click).await;
+        assert_eq!(event_handler.recv().await.unwrap(), Command::ActivateOutput(5));
+        assert_eq!(
+            executor.next_deadline().unwrap(),
+            first_deadline + Duration::from_millis(500)
+        );
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        executor.process_timers().await;
+        assert!(event_handler.is_empty());
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        executor.process_timers().await;
+        assert_eq!(event_handler.recv().await.unwrap(), Command::DeactivateOutput(5));
+        assert_eq!(executor.next_deadline(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_waits_in_procedure() {
+        const PROGRAM: [Opcode; 9] = [
+            Opcode::Start(0),
+            Opcode::BindShortCall(1, 1),
+            Opcode::Stop,
+            Opcode::Start(1),
+            Opcode::Activate(1),
+            Opcode::ActivateFor(2, 300),
+            Opcode::Wait(1000),
+            Opcode::Deactivate(1),
+            Opcode::Stop,
+        ];
+
+        let (event_src, mut event_handler) = mpsc::channel(32);
+        let mut executor: Executor<30> = Executor::new(event_src);
+        executor.load_static(&PROGRAM).await.unwrap();
+
+        // Waiting procedure doesn't block handling of other events.
+        let started = Instant::now();
+        executor
+            .parse_event(&Event::new_button_trigger(1, Trigger::ShortClick))
+            .await;
+        assert_eq!(started.elapsed(), Duration::ZERO);
+        assert_eq!(event_handler.recv().await.unwrap(), Command::ActivateOutput(1));
+        assert_eq!(event_handler.recv().await.unwrap(), Command::ActivateOutput(2));
+        assert!(event_handler.is_empty());
+
+        // Waiting is not limited by the time budget.
+        while let Some(deadline) = executor.next_deadline() {
+            tokio::time::sleep_until(deadline).await;
+            executor.process_timers().await;
+        }
+        assert_eq!(started.elapsed(), Duration::from_millis(1000));
+        assert_eq!(event_handler.recv().await.unwrap(), Command::DeactivateOutput(2));
+        assert_eq!(event_handler.recv().await.unwrap(), Command::DeactivateOutput(1));
+        assert!(event_handler.is_empty());
+    }
+
+    /// Procedure 1 blinks output 1 and is bound to input 1 with a given
+    /// retrigger policy.
+    async fn get_blinking(policy: Retrigger) -> (Executor<30>, mpsc::Receiver<Command>) {
+        let program = [
+            Opcode::Start(0),
+            Opcode::BindShortCall(1, 1),
+            Opcode::BindShortToggle(2, 5),
+            Opcode::SetRetrigger(1, policy),
+            Opcode::Stop,
+            Opcode::Start(1),
+            Opcode::Activate(1),
+            Opcode::Wait(500),
+            Opcode::Deactivate(1),
+            Opcode::Stop,
+        ];
+
+        let (event_src, event_handler) = mpsc::channel(32);
+        let mut executor: Executor<30> = Executor::new(event_src);
+        executor.load_static(&program).await.unwrap();
+        (executor, event_handler)
+    }
+
+    async fn click(executor: &mut Executor<30>, in_idx: InIdx) {
+        executor
+            .parse_event(&Event::new_button_trigger(in_idx, Trigger::ShortClick))
+            .await;
+    }
+
+    async fn drain(events: &mut mpsc::Receiver<Command>) -> Vec<Command> {
+        let mut commands = Vec::new();
+        while let Ok(command) = events.try_recv() {
+            commands.push(command);
+        }
+        commands
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_runs_other_bindings_while_waiting() {
+        let (mut executor, mut events) = get_blinking(Retrigger::Parallel).await;
+
+        click(&mut executor, 1).await;
+        click(&mut executor, 2).await;
+        assert_eq!(
+            drain(&mut events).await,
+            [Command::ActivateOutput(1), Command::ToggleOutput(5)]
+        );
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        executor.process_timers().await;
+        assert_eq!(drain(&mut events).await, [Command::DeactivateOutput(1)]);
+        assert_eq!(executor.next_deadline(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_retriggers_running_procedure() {
+        // Parallel: both instances finish.
+        let (mut executor, mut events) = get_blinking(Retrigger::Parallel).await;
+        click(&mut executor, 1).await;
+        tokio::time::advance(Duration::from_millis(100)).await;
+        click(&mut executor, 1).await;
+        tokio::time::advance(Duration::from_millis(400)).await;
+        executor.process_timers().await;
+        tokio::time::advance(Duration::from_millis(100)).await;
+        executor.process_timers().await;
+        assert_eq!(
+            drain(&mut events).await,
+            [
+                Command::ActivateOutput(1),
+                Command::ActivateOutput(1),
+                Command::DeactivateOutput(1),
+                Command::DeactivateOutput(1),
+            ]
+        );
+
+        // Restart: first instance is dropped, the second one finishes.
+        let (mut executor, mut events) = get_blinking(Retrigger::Restart).await;
+        click(&mut executor, 1).await;
+        tokio::time::advance(Duration::from_millis(100)).await;
+        click(&mut executor, 1).await;
+        tokio::time::advance(Duration::from_millis(400)).await;
+        executor.process_timers().await;
+        assert_eq!(
+            drain(&mut events).await,
+            [Command::ActivateOutput(1), Command::ActivateOutput(1)]
+        );
+        tokio::time::advance(Duration::from_millis(100)).await;
+        executor.process_timers().await;
+        assert_eq!(drain(&mut events).await, [Command::DeactivateOutput(1)]);
+
+        // Ignore: second trigger does nothing.
+        let (mut executor, mut events) = get_blinking(Retrigger::Ignore).await;
+        click(&mut executor, 1).await;
+        click(&mut executor, 1).await;
+        tokio::time::advance(Duration::from_millis(500)).await;
+        executor.process_timers().await;
+        assert_eq!(
+            drain(&mut events).await,
+            [Command::ActivateOutput(1), Command::DeactivateOutput(1)]
+        );
+
+        // Cancel: second trigger stops the procedure.
+        let (mut executor, mut events) = get_blinking(Retrigger::Cancel).await;
+        click(&mut executor, 1).await;
+        click(&mut executor, 1).await;
+        assert_eq!(executor.next_deadline(), None);
+        click(&mut executor, 1).await;
+        assert_eq!(
+            drain(&mut events).await,
+            [Command::ActivateOutput(1), Command::ActivateOutput(1)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_waits_for_release() {
+        const PROGRAM: [Opcode; 8] = [
+            Opcode::Start(0),
+            Opcode::BindActivateCall(1, 1),
+            Opcode::Stop,
+            Opcode::Start(1),
+            Opcode::Activate(3),
+            Opcode::WaitForRelease(5000),
+            Opcode::Deactivate(3),
+            Opcode::Stop,
+        ];
+
+        let (event_src, mut events) = mpsc::channel(32);
+        let mut executor: Executor<30> = Executor::new(event_src);
+        executor.load_static(&PROGRAM).await.unwrap();
+        let press = Event::new_button_trigger(1, Trigger::Activated);
+        let release = Event::new_button_trigger(1, Trigger::Deactivated);
+
+        // Runs while held.
+        executor.parse_event(&press).await;
+        tokio::time::advance(Duration::from_millis(1200)).await;
+        executor.process_timers().await;
+        assert_eq!(drain(&mut events).await, [Command::ActivateOutput(3)]);
+        executor
+            .parse_event(&Event::new_button_trigger(2, Trigger::Deactivated))
+            .await;
+        assert!(events.is_empty());
+        executor.parse_event(&release).await;
+        assert_eq!(drain(&mut events).await, [Command::DeactivateOutput(3)]);
+        assert_eq!(executor.next_deadline(), None);
+
+        // Stops after the timeout when the release is lost.
+        executor.parse_event(&press).await;
+        tokio::time::advance(Duration::from_millis(5000)).await;
+        executor.process_timers().await;
+        assert_eq!(
+            drain(&mut events).await,
+            [Command::ActivateOutput(3), Command::DeactivateOutput(3)]
+        );
+
+        // Continues immediately when the input is not held.
+        executor.parse_event(&release).await;
+        executor.spawn(1, 1, [0; 2]).await.unwrap();
+        assert_eq!(
+            drain(&mut events).await,
+            [Command::ActivateOutput(3), Command::DeactivateOutput(3)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_cancels_threads() {
+        let (mut executor, mut events) = get_blinking(Retrigger::Parallel).await;
+
+        let handle = executor.spawn(1, 0, [0; 2]).await.unwrap().unwrap();
+        assert!(executor.is_running(handle));
+        assert!(executor.cancel(handle));
+        assert!(!executor.is_running(handle));
+        assert!(!executor.cancel(handle));
+
+        // Handle of a cancelled thread doesn't match a new one in the same slot.
+        let other = executor.spawn(1, 0, [0; 2]).await.unwrap().unwrap();
+        assert!(!executor.is_running(handle));
+        assert!(executor.is_running(other));
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        executor.process_timers().await;
+        assert!(!executor.is_running(other));
+        assert_eq!(
+            drain(&mut events).await,
+            [
+                Command::ActivateOutput(1),
+                Command::ActivateOutput(1),
+                Command::DeactivateOutput(1),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_limits_waiting_threads() {
+        let (mut executor, mut events) = get_blinking(Retrigger::Parallel).await;
+        for _ in 0..MAX_THREADS {
+            click(&mut executor, 1).await;
+        }
+        // Procedure runs until the wait, then it's dropped.
+        assert_eq!(executor.spawn(1, 0, [0; 2]).await, Err(VmError::TooManyThreads));
+
+        click(&mut executor, 1).await;
+        let commands = drain(&mut events).await;
+        assert_eq!(commands.len(), MAX_THREADS + 3);
+        assert_eq!(
+            commands.last(),
+            Some(&Command::Error(ErrorCode::TooManyThreads))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_serves_events_and_timers() {
+        const PROGRAM: [Opcode; 3] = [
+            Opcode::Start(0),
+            Opcode::BindShortCall(1, 1),
+            Opcode::Stop,
+        ];
+        const TIMED: [Opcode; 3] = [
+            Opcode::Start(1),
+            Opcode::ActivateFor(7, 2000),
+            Opcode::Stop,
+        ];
+
+        let (event_src, mut event_handler) = mpsc::channel(32);
+        let mut executor: Executor<30> = Executor::new(event_src);
+        executor.load_static(&[PROGRAM, TIMED].concat()).await.unwrap();
+
+        let (events, mut event_queue) = mpsc::channel(8);
+        let server = tokio::spawn(async move {
+            executor.serve(&mut event_queue).await;
+        });
+        events
+            .send(Event::new_button_trigger(1, Trigger::ShortClick))
+            .await
+            .unwrap();
+        assert_eq!(event_handler.recv().await.unwrap(), Command::ActivateOutput(7));
+        let started = Instant::now();
+        assert_eq!(event_handler.recv().await.unwrap(), Command::DeactivateOutput(7));
+        assert_eq!(started.elapsed(), Duration::from_millis(2000));
+
+        drop(events);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_aborts_procedure_over_instruction_budget() {
+        const PROGRAM: [Opcode; 9] = [
+            Opcode::Start(0),
+            Opcode::BindShortCall(1, 1),
+            Opcode::Stop,
+            Opcode::Start(1),
+            Opcode::Activate(1),
+            Opcode::Activate(2),
+            Opcode::Activate(3),
+            Opcode::Activate(4),
+            Opcode::Stop,
+        ];
+
+        let (event_src, mut event_handler) = mpsc::channel(32);
+        let mut executor: Executor<30> = Executor::new(event_src);
+        executor.load_static(&PROGRAM).await.unwrap();
+        executor.set_budget(Budget {
+            instructions: 3,
+            ..Budget::default()
+        });
+
+        executor
+            .parse_event(&Event::new_button_trigger(1, Trigger::ShortClick))
+            .await;
+        for out_idx in 1..=3 {
+            assert_eq!(event_handler.recv().await.unwrap(), Command::ActivateOutput(out_idx));
+        }
+        assert_eq!(
+            event_handler.recv().await.unwrap(),
+            Command::Error(ErrorCode::InstructionBudgetExceeded)
+        );
+        assert!(event_handler.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_aborts_procedure_over_time_budget() {
+        const PROGRAM: [Opcode; 8] = [
+            Opcode::Start(0),
+            Opcode::BindShortCall(1, 1),
+            Opcode::Stop,
+            Opcode::Start(1),
+            Opcode::Activate(1),
+            Opcode::Activate(2),
+            Opcode::Activate(3),
+            Opcode::Stop,
+        ];
+
+        // Slow consumer makes emitting block past the budget.
+        let (event_src, mut event_handler) = mpsc::channel(1);
+        let consumer = tokio::spawn(async move {
+            let mut received = Vec::new();
+            while let Some(cmd) = event_handler.recv().await {
+                received.push(cmd);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            received
+        });
+
+        let mut executor: Executor<30> = Executor::new(event_src);
+        executor.load_static(&PROGRAM).await.unwrap();
+        executor.set_budget(Budget {
+            time: Duration::from_millis(50),
+            ..Budget::default()
+        });
+        executor
+            .parse_event(&Event::new_button_trigger(1, Trigger::ShortClick))
+            .await;
+        drop(executor);
+
+        assert_eq!(
+            consumer.await.unwrap(),
+            vec![
+                Command::ActivateOutput(1),
+                Command::ActivateOutput(2),
+                Command::Error(ErrorCode::TimeBudgetExceeded),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn it_calls_native_syscalls() {
+        const PROGRAM: [Opcode; 12] = [
+            Opcode::Start(0),
+            Opcode::BindShortCall(1, 1),
+            Opcode::BindLongCall(1, 2),
+            Opcode::Stop,
+            Opcode::Start(1),
+            Opcode::Syscall(3, 42),
+            Opcode::JumpIfZero(0, 2),
+            Opcode::Call(2),
+            Opcode::Stop,
+            Opcode::Start(2),
+            Opcode::Syscall(4, 0),
+            Opcode::Stop,
+        ];
+
+        let (event_src, mut events) = mpsc::channel(32);
+        let mut executor: Executor<30> = Executor::new(event_src);
+        let calls = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = calls.clone();
+        executor
+            .register_syscall(3, move |arg| {
+                recorded.lock().unwrap().push(arg);
+                Ok(1)
+            })
+            .unwrap();
+        assert_eq!(
+            executor.register_syscall(MAX_SYSCALLS as SyscallId, |_| Ok(0)),
+            Err(SyscallOutOfRange)
+        );
+        executor.load_static(&PROGRAM).await.unwrap();
+
+        // Syscall 3 returns true, so procedure 2 is called.
+        executor
+            .parse_event(&Event::new_button_trigger(1, Trigger::ShortClick))
+            .await;
+        assert_eq!(*calls.lock().unwrap(), [42]);
+        assert_eq!(events.recv().await.unwrap(), Command::Error(ErrorCode::UnknownSyscall));
+
+        executor.register_syscall(4, |_| Err(SyscallFailed)).unwrap();
+        executor
+            .parse_event(&Event::new_button_trigger(1, Trigger::LongClick))
+            .await;
+        assert_eq!(events.recv().await.unwrap(), Command::Error(ErrorCode::SyscallFailed));
+        assert!(events.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_calls_error_handler() {
+        const PROGRAM: [Opcode; 22] = [
+            Opcode::Start(0),
+            Opcode::SetErrorHandler(9),
+            Opcode::BindShortCall(1, 1),
+            Opcode::BindCallWith(2, Trigger::ShortClick, 9, 200, 0),
+            Opcode::Stop,
+            Opcode::Start(1),
+            Opcode::CallWith(2, 200, 0),
+            Opcode::Activate(1),
+            Opcode::Stop,
+            Opcode::Start(2),
+            Opcode::Toggle(ARG0),
+            Opcode::Stop,
+            // Blink the status LED, light the fault LED on output errors
+            // and report other errors natively.
+            Opcode::Start(9),
+            Opcode::ActivateFor(20, 100),
+            Opcode::Load(0, ARG0),
+            Opcode::Load(1, ErrorCode::OutputOutOfRange as u8),
+            Opcode::Eq(0, 1),
+            Opcode::JumpIfZero(0, 3),
+            Opcode::Activate(21),
+            Opcode::Jump(2),
+            Opcode::Syscall(7, 0),
+            Opcode::Stop,
+        ];
+
+        let (event_src, mut events) = mpsc::channel(32);
+        let mut executor: Executor<30> = Executor::new(event_src);
+        executor.load_static(&PROGRAM).await.unwrap();
+
+        // Procedure is aborted, handler gets error code and procedure.
+        executor
+            .parse_event(&Event::new_button_trigger(1, Trigger::ShortClick))
+            .await;
+        assert_eq!(
+            drain(&mut events).await,
+            [
+                Command::Error(ErrorCode::OutputOutOfRange),
+                Command::ActivateOutput(20),
+                Command::ActivateOutput(21),
+            ]
+        );
+
+        // Other code takes the native path, which isn't registered. Failing
+        // handler is not called again.
+        executor
+            .parse_event(&Event::new_button_trigger(2, Trigger::ShortClick))
+            .await;
+        assert_eq!(
+            drain(&mut events).await,
+            [
+                Command::ActivateOutput(20),
+                Command::Error(ErrorCode::UnknownSyscall),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn it_survives_closed_queue() {
+        const PROGRAM: [Opcode; 6] = [
+            Opcode::Start(0),
+            Opcode::BindShortCall(1, 1),
+            Opcode::Stop,
+            Opcode::Start(1),
+            Opcode::Activate(1),
+            Opcode::Stop,
+        ];
+
+        let (event_src, events) = mpsc::channel(32);
+        let mut executor: Executor<30> = Executor::new(event_src);
+        executor.load_static(&PROGRAM).await.unwrap();
+        drop(events);
+
+        assert_eq!(
+            executor.emit(Command::ActivateOutput(1)).await,
+            Err(VmError::QueueClosed)
+        );
+        executor
+            .parse_event(&Event::new_button_trigger(1, Trigger::ShortClick))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn it_rejects_invalid_program() {
+        const PROGRAM: [Opcode; 3] = [Opcode::Start(0), Opcode::Call(1), Opcode::Stop];
+
+        let (event_src, _event_handler) = mpsc::channel(32);
+        let mut executor: Executor<30> = Executor::new(event_src);
+        assert_eq!(
+            executor.load_static(&PROGRAM).await,
+            Err(LoadError::Invalid(Diagnostic::new(
+                1,
+                verifier::Problem::UnknownProcedure(1)
+            )))
+        );
+    }
+
+    #[tokio::test]
+    async fn it_checks_program_capacity() {
+        const PROGRAM: [Opcode; 6] = [
+            Opcode::Start(0),
+            Opcode::BindShortCall(1, 3),
+            Opcode::Stop,
+            Opcode::Start(3),
+            Opcode::Toggle(1),
+            Opcode::Stop,
+        ];
+
+        let (event_src, mut events) = mpsc::channel(32);
+        let mut executor: Executor<4, 2, 6, 4> = Executor::new(event_src.clone());
+        executor.load_static(&PROGRAM).await.unwrap();
+        executor
+            .parse_event(&Event::new_button_trigger(1, Trigger::ShortClick))
+            .await;
+        assert_eq!(events.recv().await.unwrap(), Command::ToggleOutput(1));
+
+        let mut short: Executor<4, 2, 5, 4> = Executor::new(event_src.clone());
+        assert_eq!(
+            short.load_static(&PROGRAM).await,
+            Err(LoadError::TooLong {
+                length: 6,
+                capacity: 5
+            })
+        );
+
+        let mut few_procedures: Executor<4, 2, 6, 3> = Executor::new(event_src.clone());
+        assert_eq!(
+            few_procedures.load_static(&PROGRAM).await,
+            Err(LoadError::TooManyProcedures(3))
+        );
+
+        // Procedure table can be larger than the default one.
+        let mut program = PROGRAM;
+        program[1] = Opcode::BindShortCall(1, 200);
+        program[3] = Opcode::Start(200);
+        let mut many_procedures: Executor<4, 2, 6, 256> = Executor::new(event_src.clone());
+        many_procedures.load_static(&program).await.unwrap();
+        many_procedures
+            .parse_event(&Event::new_button_trigger(1, Trigger::ShortClick))
+            .await;
+        assert_eq!(events.recv().await.unwrap(), Command::ToggleOutput(1));
+        assert_eq!(
+            executor.load_static(&program).await,
+            Err(LoadError::TooManyProcedures(200))
+        );
+    }
+
+    #[tokio::test]
+    async fn it_reports_layer_stack_overflow() {
+        const PROGRAM: [Opcode; 10] = [
+            Opcode::Start(0),
+            Opcode::BindShortCall(1, 1),
+            Opcode::LayerPush(2),
+            Opcode::BindShortToggle(4, 20),
+            Opcode::Stop,
+            Opcode::Start(1),
+            Opcode::LayerPush(1),
+            Opcode::LayerPush(2),
+            Opcode::LayerPush(3),
+            Opcode::Stop,
+        ];
+
+        let (event_src, mut event_handler) = mpsc::channel(32);
+        let mut executor: Executor<30, 2> = Executor::new(event_src);
+        executor.set_layer_overflow_policy(OverflowPolicy::RejectNew);
+        executor.load_static(&PROGRAM).await.unwrap();
+
+        executor
+            .parse_event(&Event::new_button_trigger(1, Trigger::ShortClick))
+            .await;
+        // Layer 3 was rejected, layer 2 still active.
+        executor
+            .parse_event(&Event::new_button_trigger(4, Trigger::ShortClick))
+            .await;
+
+        assert_eq!(
+            event_handler.recv().await.unwrap(),
+            Command::Error(ErrorCode::LayerStackOverflow)
+        );
+        assert_eq!(event_handler.recv().await.unwrap(), Command::ToggleOutput(20));
+        assert!(event_handler.is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_keeps_layers_per_zone() {
+        const PROGRAM: [Opcode; 11] = [
+            Opcode::Start(0),
+            // Kitchen: inputs 1 and 5. Dining: input 2 in default zone.
+            Opcode::ZoneAssign(1, 1),
+            Opcode::ZoneAssign(5, 1),
+            Opcode::BindShortToggle(1, 10),
+            Opcode::BindShortToggle(2, 20),
+            Opcode::BindLayerHold(5, 1),
+            Opcode::ZoneSelect(1),
+            Opcode::LayerPush(1),
+            Opcode::BindShortToggle(1, 11),
+            // Bound on layer 1 of a kitchen zone. Dining never gets there.
+            Opcode::BindShortToggle(2, 21),
+            Opcode::Stop,
+        ];
+
+        let (event_src, mut event_handler) = mpsc::channel(32);
+        let mut executor: Executor<30> = Executor::new(event_src);
+        executor.load_static(&PROGRAM).await.unwrap();
+
+        // Kitchen layer held.
+        executor
+            .parse_event(&Event::new_button_trigger(5, Trigger::Activated))
+            .await;
+        executor
+            .parse_event(&Event::new_button_trigger(1, Trigger::ShortClick))
+            .await;
+        executor
+            .parse_event(&Event::new_button_trigger(2, Trigger::ShortClick))
+            .await;
+        executor
+            .parse_event(&Event::new_button_trigger(5, Trigger::Deactivated))
+            .await;
+        executor
+            .parse_event(&Event::new_button_trigger(1, Trigger::ShortClick))
+            .await;
+
+        assert_eq!(event_handler.recv().await.unwrap(), Command::ToggleOutput(11));
+        assert_eq!(event_handler.recv().await.unwrap(), Command::ToggleOutput(20));
+        assert_eq!(event_handler.recv().await.unwrap(), Command::ToggleOutput(10));
+        assert!(event_handler.is_empty());
+    }
 }
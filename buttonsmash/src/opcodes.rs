@@ -1,4 +1,8 @@
-use crate::consts::{OutIdx, InIdx, ProcIdx, LayerIdx};
+use crate::consts::{
+    Args, DevAddr, InIdx, LayerIdx, Millis, OutIdx, OutputState, ProcIdx, RegIdx, SyscallId,
+    Trigger, VarIdx, Word, ZoneIdx, ARG0, ARG1,
+};
+use crate::threads::Retrigger;
 
 /// Opcodes of the internal micro vm.
 #[derive(Eq, PartialEq, Copy, Clone, Debug)]
@@ -11,6 +15,10 @@ pub enum Opcode {
     Stop,
     /// Call a procedure
     Call(u8),
+    /// Call a procedure with two argument bytes. Output and layer operands and
+    /// `Load` values of the procedure equal to `ARG0`/`ARG1` are replaced by
+    /// the arguments.
+    CallWith(ProcIdx, u8, u8),
 
     /// Direct output control: Toggle IO
     Toggle(OutIdx),
@@ -18,6 +26,17 @@ pub enum Opcode {
     Activate(OutIdx),
     /// Direct output control: Deactivate IO (no matter state)
     Deactivate(OutIdx),
+    /// Activate IO and deactivate it after a time (ms). Activating again
+    /// extends the time.
+    ActivateFor(OutIdx, Millis),
+    /// Change output of another device on the bus.
+    SetRemote(DevAddr, OutIdx, OutputState),
+
+    /// Pause the procedure for a time (ms).
+    Wait(Millis),
+    /// Pause the procedure until the input which triggered it is released, but
+    /// at most for a time (ms). Continues immediately if it's already released.
+    WaitForRelease(Millis),
 
     /// Enable a layer (later: push layer onto a layer stack)
     LayerPush(LayerIdx),
@@ -27,6 +46,12 @@ pub enum Opcode {
     /// Clear the layer stack - back to default layer.
     LayerDefault,
 
+    /// Put input into a zone. Each zone has its own layer stack.
+    ZoneAssign(InIdx, ZoneIdx),
+    /// Select zone which layer opcodes (and so the layer of new bindings)
+    /// operate on. Procedure called by input starts in the input's zone.
+    ZoneSelect(ZoneIdx),
+
     /// Clear all bindings.
     BindClearAll,
     /// Map Input short click to a procedure (on current layer)
@@ -41,6 +66,8 @@ pub enum Opcode {
     BindLongActivate(InIdx, ProcIdx),
     /// Map deactivation after over short click time to a procedure (on a current layer)
     BindLongDeactivate(InIdx, ProcIdx),
+    /// Map input trigger to a procedure called with arguments (on a current layer)
+    BindCallWith(InIdx, Trigger, ProcIdx, u8, u8),
 
 
     /*
@@ -55,18 +82,131 @@ pub enum Opcode {
     /// Bind layer to activate/deactivate triggers.
     BindLayerHold(InIdx, LayerIdx),
 
-
-    // Hypothetical?
     /*
+     * Registers. Boolean results are stored as 1 (true) or 0 (false), any
+     * non-zero value is true.
+     */
+    /// Load a constant into register. `ARG0`/`ARG1` load the procedure
+    /// arguments.
+    Load(RegIdx, u8),
     /// Read input value (local) into register
-    ReadInput(InIdx),
-    /// Read input value (local) into register
-    ReadOutput(OutIdx),
-    /// Call first if register is True, second one if False.
-    CallConditionally(ProcIdx, ProcIdx),
+    ReadInput(RegIdx, InIdx),
+    /// Read output value (local) into register
+    ReadOutput(RegIdx, OutIdx),
+    /// First register = first == second
+    Eq(RegIdx, RegIdx),
+    /// First register = first < second
+    Lt(RegIdx, RegIdx),
+    /// First register = first > second
+    Gt(RegIdx, RegIdx),
+    /// First register = first && second
+    And(RegIdx, RegIdx),
+    /// First register = first || second
+    Or(RegIdx, RegIdx),
+    /// Register = !register
+    Not(RegIdx),
+    /// Call first procedure if register is True, second one if False.
+    CallConditionally(RegIdx, ProcIdx, ProcIdx),
+
+    /*
+     * Variables. Unlike registers they keep value between procedure runs
+     * until a program is loaded again. Arithmetic wraps around.
+     */
+    /// Variable = value
+    Set(VarIdx, Word),
+    /// Variable += 1
+    Inc(VarIdx),
+    /// Variable -= 1
+    Dec(VarIdx),
+    /// Variable += value
+    Add(VarIdx, Word),
+    /// Variable = variable % value. Value can't be 0.
+    Mod(VarIdx, Word),
+    /// Register = variable
+    LoadVar(RegIdx, VarIdx),
+    /// Variable = register
+    StoreVar(VarIdx, RegIdx),
+    /// Register = variable == value
+    VarEq(RegIdx, VarIdx, Word),
+    /// Register = variable < value
+    VarLt(RegIdx, VarIdx, Word),
+    /// Register = variable > value
+    VarGt(RegIdx, VarIdx, Word),
+
+    /*
+     * Branching. Relative offsets are counted from the jump opcode itself and
+     * targets must stay within the procedure. Jump to the Stop to return early.
+     */
+    /// Jump by a relative offset.
+    Jump(i8),
+    /// Jump to an absolute address in the program.
+    JumpTo(u16),
+    /// Jump by a relative offset if register is zero.
+    JumpIfZero(RegIdx, i8),
+    /// Jump by a relative offset if register is not zero.
+    JumpIfNotZero(RegIdx, i8),
+    /// Decrement register (loop counter) and jump by a relative offset until
+    /// it reaches zero.
+    Loop(RegIdx, i8),
+
+    /*
+     * Threads. Procedure which waits runs in a thread, so other buttons keep
+     * working meanwhile.
+     */
+    /// Select what happens when a procedure is triggered while still running.
+    SetRetrigger(ProcIdx, Retrigger),
+    /// Cancel all running instances of a procedure.
+    Cancel(ProcIdx),
+
+    /// Call a native handler registered by the firmware with an argument.
+    /// Returned value is stored in register 0.
+    Syscall(SyscallId, u8),
+
+    /// Procedure started when another procedure fails, called with the error
+    /// code and the failed procedure as arguments.
+    SetErrorHandler(ProcIdx),
 
-    // WaitForRelease - maybe?
     // Procedure 0 is executed after loading and it can map the actions initially
+}
+
+/// Replace `ARG0`/`ARG1` by the argument.
+fn arg(value: u8, args: Args) -> u8 {
+    match value {
+        ARG0 => args[0],
+        ARG1 => args[1],
+        _ => value,
+    }
+}
 
-    */
+impl Opcode {
+    /// Opcode with argument references replaced by procedure arguments.
+    pub fn with_args(self, args: Args) -> Self {
+        match self {
+            Opcode::CallWith(proc, first, second) => {
+                Opcode::CallWith(proc, arg(first, args), arg(second, args))
+            }
+            Opcode::Toggle(out_idx) => Opcode::Toggle(arg(out_idx, args)),
+            Opcode::Activate(out_idx) => Opcode::Activate(arg(out_idx, args)),
+            Opcode::Deactivate(out_idx) => Opcode::Deactivate(arg(out_idx, args)),
+            Opcode::ActivateFor(out_idx, ms) => Opcode::ActivateFor(arg(out_idx, args), ms),
+            Opcode::SetRemote(dev, out_idx, state) => {
+                Opcode::SetRemote(dev, arg(out_idx, args), state)
+            }
+            Opcode::LayerPush(layer) => Opcode::LayerPush(arg(layer, args)),
+            Opcode::LayerSet(layer) => Opcode::LayerSet(arg(layer, args)),
+            Opcode::BindCallWith(in_idx, trigger, proc, first, second) => {
+                Opcode::BindCallWith(in_idx, trigger, proc, arg(first, args), arg(second, args))
+            }
+            Opcode::BindShortToggle(in_idx, out_idx) => {
+                Opcode::BindShortToggle(in_idx, arg(out_idx, args))
+            }
+            Opcode::BindLongToggle(in_idx, out_idx) => {
+                Opcode::BindLongToggle(in_idx, arg(out_idx, args))
+            }
+            Opcode::BindLayerHold(in_idx, layer) => Opcode::BindLayerHold(in_idx, arg(layer, args)),
+            Opcode::Load(reg, value) => Opcode::Load(reg, arg(value, args)),
+            Opcode::ReadOutput(reg, out_idx) => Opcode::ReadOutput(reg, arg(out_idx, args)),
+            opcode => opcode,
+        }
+    }
 }
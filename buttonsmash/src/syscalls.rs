@@ -0,0 +1,52 @@
+use crate::consts::{SyscallId, Word, MAX_SYSCALLS};
+
+/// Native handler was unable to perform the syscall.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SyscallFailed;
+
+/// Syscall ID is out of range of the syscall table.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SyscallOutOfRange;
+
+/// Native handler of a syscall. Gets the opcode argument and returns a value
+/// stored in register 0.
+pub type SyscallHandler = Box<dyn FnMut(u8) -> Result<Word, SyscallFailed> + Send>;
+
+/// Native handlers registered by the firmware, so programs can drive board
+/// specific hardware (buzzers, PWM, sensors).
+pub struct Syscalls {
+    handlers: [Option<SyscallHandler>; MAX_SYSCALLS],
+}
+
+impl Default for Syscalls {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Syscalls {
+    pub fn new() -> Self {
+        Self {
+            handlers: [const { None }; MAX_SYSCALLS],
+        }
+    }
+
+    /// Register handler of a syscall, replacing previous one.
+    pub fn register(
+        &mut self,
+        id: SyscallId,
+        handler: SyscallHandler,
+    ) -> Result<(), SyscallOutOfRange> {
+        let slot = self
+            .handlers
+            .get_mut(id as usize)
+            .ok_or(SyscallOutOfRange)?;
+        *slot = Some(handler);
+        Ok(())
+    }
+
+    /// Handler of a syscall, if registered.
+    pub fn get(&mut self, id: SyscallId) -> Option<&mut SyscallHandler> {
+        self.handlers.get_mut(id as usize)?.as_mut()
+    }
+}
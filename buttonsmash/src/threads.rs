@@ -0,0 +1,196 @@
+use tokio::time::Instant;
+
+use crate::consts::*;
+use crate::microvm::VmError;
+
+/// What to do when a procedure is triggered while it's still running.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Retrigger {
+    /// Start another instance alongside the running one.
+    #[default]
+    Parallel,
+    /// Cancel the running instance and start over.
+    Restart,
+    /// Keep the running instance and ignore the trigger.
+    Ignore,
+    /// Cancel the running instance and don't start a new one - pressing the
+    /// same button again stops the procedure.
+    Cancel,
+}
+
+/// Identifies a running procedure. Handle of a finished procedure never
+/// matches a procedure started later in the same slot.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ThreadHandle {
+    slot: u8,
+    id: u16,
+}
+
+/// Fixed-size stack of return addresses and arguments of the callers. Calls
+/// don't recurse natively and don't allocate.
+pub(crate) struct CallStack {
+    frames: [(usize, Args); MAX_CALL_DEPTH],
+    depth: usize,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        Self {
+            frames: [(0, [0; 2]); MAX_CALL_DEPTH],
+            depth: 0,
+        }
+    }
+
+    pub fn push(&mut self, pc: usize, args: Args) -> Result<(), VmError> {
+        if self.depth == MAX_CALL_DEPTH {
+            return Err(VmError::StackOverflow);
+        }
+        self.frames[self.depth] = (pc, args);
+        self.depth += 1;
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<(usize, Args)> {
+        if self.depth == 0 {
+            return None;
+        }
+        self.depth -= 1;
+        Some(self.frames[self.depth])
+    }
+}
+
+/// Execution state of a running procedure.
+pub(crate) struct Thread {
+    id: u16,
+    /// Procedure the thread was started with.
+    pub proc: ProcIdx,
+    /// Address of the next opcode.
+    pub pc: usize,
+    pub stack: CallStack,
+    /// Arguments of the executed procedure.
+    pub args: Args,
+    pub registers: [Word; REGISTERS],
+    /// Zone selected for the layer opcodes.
+    pub zone: ZoneIdx,
+    /// Input which triggered the procedure, 0 if none.
+    pub input: InIdx,
+    /// Time to resume a waiting thread.
+    pub wake_at: Option<Instant>,
+    /// Thread waits for release of its input (or `wake_at` timeout).
+    pub wait_release: bool,
+}
+
+impl Thread {
+    /// Thread executing procedure which starts at `start`.
+    pub fn new(proc: ProcIdx, start: usize, zone: ZoneIdx, input: InIdx, args: Args) -> Self {
+        Self {
+            id: 0,
+            proc,
+            pc: start + 1,
+            stack: CallStack::new(),
+            args,
+            registers: [0; REGISTERS],
+            zone,
+            input,
+            wake_at: None,
+            wait_release: false,
+        }
+    }
+}
+
+/// Fixed pool of suspended threads.
+pub(crate) struct Threads {
+    slots: [Option<Thread>; MAX_THREADS],
+    next_id: u16,
+}
+
+impl Threads {
+    pub fn new() -> Self {
+        Self {
+            slots: [const { None }; MAX_THREADS],
+            next_id: 0,
+        }
+    }
+
+    /// Drop all threads.
+    pub fn clear(&mut self) {
+        for slot in self.slots.iter_mut() {
+            *slot = None;
+        }
+    }
+
+    /// Store a suspended thread.
+    pub fn insert(&mut self, mut thread: Thread) -> Result<ThreadHandle, VmError> {
+        let slot = self
+            .slots
+            .iter()
+            .position(|slot| slot.is_none())
+            .ok_or(VmError::TooManyThreads)?;
+        self.next_id = self.next_id.wrapping_add(1);
+        thread.id = self.next_id;
+        self.slots[slot] = Some(thread);
+        Ok(ThreadHandle {
+            slot: slot as u8,
+            id: self.next_id,
+        })
+    }
+
+    /// Take thread out of the pool to run it. Put it back using `restore`.
+    pub fn take(&mut self, slot: usize) -> Option<Thread> {
+        self.slots[slot].take()
+    }
+
+    /// Put back thread taken from a slot. Keeps the handle valid.
+    pub fn restore(&mut self, slot: usize, thread: Thread) {
+        self.slots[slot] = Some(thread);
+    }
+
+    pub fn is_running(&self, handle: ThreadHandle) -> bool {
+        matches!(&self.slots[handle.slot as usize], Some(thread) if thread.id == handle.id)
+    }
+
+    /// Cancel the thread. Returns false if it has already finished.
+    pub fn cancel(&mut self, handle: ThreadHandle) -> bool {
+        if self.is_running(handle) {
+            self.slots[handle.slot as usize] = None;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Handle of a running instance of the procedure.
+    pub fn find_proc(&self, proc: ProcIdx) -> Option<ThreadHandle> {
+        self.slots.iter().enumerate().find_map(|(slot, thread)| match thread {
+            Some(thread) if thread.proc == proc => Some(ThreadHandle {
+                slot: slot as u8,
+                id: thread.id,
+            }),
+            _ => None,
+        })
+    }
+
+    /// Cancel all running instances of the procedure.
+    pub fn cancel_proc(&mut self, proc: ProcIdx) {
+        for slot in self.slots.iter_mut() {
+            if matches!(slot, Some(thread) if thread.proc == proc) {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Earliest time a thread should be resumed.
+    pub fn next_wake(&self) -> Option<Instant> {
+        self.slots.iter().flatten().filter_map(|thread| thread.wake_at).min()
+    }
+
+    /// Is thread in the slot waiting for release of the input?
+    pub fn is_released(&self, slot: usize, in_idx: InIdx) -> bool {
+        matches!(&self.slots[slot], Some(thread) if thread.wait_release && thread.input == in_idx)
+    }
+
+    /// Is thread in the slot due to be resumed at `now`?
+    pub fn is_due(&self, slot: usize, now: Instant) -> bool {
+        matches!(&self.slots[slot], Some(Thread { wake_at: Some(wake_at), .. }) if *wake_at <= now)
+    }
+}
@@ -0,0 +1,64 @@
+use tokio::time::Instant;
+
+use crate::consts::{OutIdx, MAX_TIMERS};
+
+/// Timer slots were all in use when scheduling a new timer.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TimersFull;
+
+/// Scheduled deactivations of outputs activated for a set amount of time. Each
+/// output has at most one timer; scheduling it again extends the timer.
+pub struct OutputTimers {
+    timers: [Option<(OutIdx, Instant)>; MAX_TIMERS],
+}
+
+impl Default for OutputTimers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OutputTimers {
+    pub fn new() -> Self {
+        Self {
+            timers: [None; MAX_TIMERS],
+        }
+    }
+
+    /// Drop all the timers.
+    pub fn clear(&mut self) {
+        self.timers = [None; MAX_TIMERS];
+    }
+
+    /// Deactivate output at the deadline. Existing timer of this output is
+    /// extended, but never shortened.
+    pub fn schedule(&mut self, out_idx: OutIdx, deadline: Instant) -> Result<(), TimersFull> {
+        for (timer_out_idx, timer_deadline) in self.timers.iter_mut().flatten() {
+            if *timer_out_idx == out_idx {
+                *timer_deadline = (*timer_deadline).max(deadline);
+                return Ok(());
+            }
+        }
+        let slot = self
+            .timers
+            .iter_mut()
+            .find(|timer| timer.is_none())
+            .ok_or(TimersFull)?;
+        *slot = Some((out_idx, deadline));
+        Ok(())
+    }
+
+    /// Earliest deadline, if any timer is scheduled.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.timers.iter().flatten().map(|(_, deadline)| *deadline).min()
+    }
+
+    /// Remove and return one output which timer expired at `now`.
+    pub fn pop_expired(&mut self, now: Instant) -> Option<OutIdx> {
+        let timer = self
+            .timers
+            .iter_mut()
+            .find(|timer| matches!(timer, Some((_, deadline)) if *deadline <= now))?;
+        timer.take().map(|(out_idx, _)| out_idx)
+    }
+}
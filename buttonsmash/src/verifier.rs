@@ -0,0 +1,567 @@
+use core::fmt;
+
+use crate::consts::*;
+use crate::opcodes::Opcode;
+
+/// Problem found in a program.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Problem {
+    /// Procedure 0 (setup executed after loading) is not defined.
+    MissingSetup,
+    /// Procedure is not terminated with a Stop before the next Start or the
+    /// end of program.
+    MissingStop(ProcIdx),
+    /// Opcode (other than Noop) outside of any procedure.
+    OutsideProcedure,
+    /// Procedure with the same ID was already started.
+    DuplicateProcedure(ProcIdx),
+    /// Call or binding to a procedure which is not defined.
+    UnknownProcedure(ProcIdx),
+    /// Procedure calls itself directly or through other procedures.
+    Recursion(ProcIdx),
+    InputOutOfRange(InIdx),
+    OutputOutOfRange(OutIdx),
+    LayerOutOfRange(LayerIdx),
+    ZoneOutOfRange(ZoneIdx),
+    RegisterOutOfRange(RegIdx),
+    VariableOutOfRange(VarIdx),
+    SyscallOutOfRange(SyscallId),
+    /// CAN address of a remote device out of range.
+    DeviceOutOfRange(DevAddr),
+    /// Mod opcode with a zero divisor.
+    ModuloByZero,
+    /// Jump target is outside of the procedure containing the jump.
+    JumpOutOfProcedure(isize),
+    /// Absolute jump too far to be written as a relative one, by the given
+    /// offset. Reported by the decompiler.
+    JumpTooFar(isize),
+}
+
+/// Problem with a location (opcode index) in a program.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Diagnostic {
+    pub pc: usize,
+    pub problem: Problem,
+}
+
+impl Diagnostic {
+    pub fn new(pc: usize, problem: Problem) -> Self {
+        Self { pc, problem }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.pc)?;
+        match self.problem {
+            Problem::MissingSetup => write!(f, "setup procedure 0 is not defined"),
+            Problem::MissingStop(proc) => write!(f, "procedure {} has no Stop", proc),
+            Problem::OutsideProcedure => write!(f, "opcode outside of a procedure"),
+            Problem::DuplicateProcedure(proc) => write!(f, "procedure {} defined twice", proc),
+            Problem::UnknownProcedure(proc) => write!(f, "procedure {} is not defined", proc),
+            Problem::Recursion(proc) => write!(f, "recursive call to procedure {}", proc),
+            Problem::InputOutOfRange(idx) => write!(f, "input {} out of range", idx),
+            Problem::OutputOutOfRange(idx) => write!(f, "output {} out of range", idx),
+            Problem::LayerOutOfRange(idx) => write!(f, "layer {} out of range", idx),
+            Problem::ZoneOutOfRange(idx) => write!(f, "zone {} out of range", idx),
+            Problem::RegisterOutOfRange(idx) => write!(f, "register {} out of range", idx),
+            Problem::VariableOutOfRange(idx) => write!(f, "variable {} out of range", idx),
+            Problem::SyscallOutOfRange(id) => write!(f, "syscall {} out of range", id),
+            Problem::DeviceOutOfRange(dev) => write!(f, "device {} out of range", dev),
+            Problem::ModuloByZero => write!(f, "modulo by zero"),
+            Problem::JumpOutOfProcedure(target) => {
+                write!(f, "jump to {} leaves the procedure", target)
+            }
+            Problem::JumpTooFar(offset) => {
+                write!(f, "jump by {} is too far for a relative jump", offset)
+            }
+        }
+    }
+}
+
+/// Verify the program and return all found problems. Empty list means program
+/// can be loaded.
+pub fn verify(program: &[Opcode]) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    check(program, |diagnostic| diagnostics.push(diagnostic));
+    diagnostics
+}
+
+/// Verify the program and pass found problems to `report`. Doesn't allocate.
+pub fn check(program: &[Opcode], mut report: impl FnMut(Diagnostic)) {
+    let starts = check_structure(program, &mut report);
+    for (pc, opcode) in program.iter().enumerate() {
+        check_operands(pc, opcode, &starts, &mut report);
+    }
+    check_jumps(program, &mut report);
+    check_recursion(program, &starts, &mut report);
+}
+
+/// Check procedure boundaries and return starts of defined procedures.
+fn check_structure(
+    program: &[Opcode],
+    report: &mut impl FnMut(Diagnostic),
+) -> [Option<usize>; PROCEDURE_IDS] {
+    let mut starts = [None; PROCEDURE_IDS];
+    // Procedure we are in and its start.
+    let mut current: Option<(ProcIdx, usize)> = None;
+
+    for (pc, opcode) in program.iter().enumerate() {
+        match *opcode {
+            Opcode::Start(proc) => {
+                if let Some((open_proc, open_pc)) = current {
+                    report(Diagnostic::new(open_pc, Problem::MissingStop(open_proc)));
+                }
+                current = Some((proc, pc));
+                if starts[proc as usize].is_some() {
+                    report(Diagnostic::new(pc, Problem::DuplicateProcedure(proc)));
+                } else {
+                    starts[proc as usize] = Some(pc);
+                }
+            }
+            Opcode::Stop => {
+                if current.take().is_none() {
+                    report(Diagnostic::new(pc, Problem::OutsideProcedure));
+                }
+            }
+            Opcode::Noop => {}
+            _ => {
+                if current.is_none() {
+                    report(Diagnostic::new(pc, Problem::OutsideProcedure));
+                }
+            }
+        }
+    }
+
+    if let Some((open_proc, open_pc)) = current {
+        report(Diagnostic::new(open_pc, Problem::MissingStop(open_proc)));
+    }
+    if starts[0].is_none() {
+        report(Diagnostic::new(0, Problem::MissingSetup));
+    }
+    starts
+}
+
+/// Check that opcode arguments are within limits and called procedures exist.
+fn check_operands(
+    pc: usize,
+    opcode: &Opcode,
+    starts: &[Option<usize>; PROCEDURE_IDS],
+    report: &mut impl FnMut(Diagnostic),
+) {
+    let mut problem = |problem| report(Diagnostic::new(pc, problem));
+    let mut check_proc = |proc: ProcIdx| {
+        if starts[proc as usize].is_none() {
+            problem(Problem::UnknownProcedure(proc));
+        }
+    };
+
+    match *opcode {
+        Opcode::Call(proc)
+        | Opcode::CallWith(proc, _, _)
+        | Opcode::SetRetrigger(proc, _)
+        | Opcode::Cancel(proc)
+        | Opcode::SetErrorHandler(proc) => check_proc(proc),
+        Opcode::BindShortCall(in_idx, proc)
+        | Opcode::BindLongCall(in_idx, proc)
+        | Opcode::BindActivateCall(in_idx, proc)
+        | Opcode::BindDeactivateCall(in_idx, proc)
+        | Opcode::BindLongActivate(in_idx, proc)
+        | Opcode::BindLongDeactivate(in_idx, proc)
+        | Opcode::BindCallWith(in_idx, _, proc, _, _) => {
+            check_proc(proc);
+            check_input(pc, in_idx, report);
+        }
+        Opcode::Toggle(out_idx)
+        | Opcode::Activate(out_idx)
+        | Opcode::Deactivate(out_idx)
+        | Opcode::ActivateFor(out_idx, _) => {
+            check_output(pc, out_idx, report);
+        }
+        Opcode::SetRemote(dev, out_idx, _) => {
+            if dev as usize >= MAX_DEVICES {
+                report(Diagnostic::new(pc, Problem::DeviceOutOfRange(dev)));
+            }
+            check_output(pc, out_idx, report);
+        }
+        Opcode::BindShortToggle(in_idx, out_idx) | Opcode::BindLongToggle(in_idx, out_idx) => {
+            check_input(pc, in_idx, report);
+            check_output(pc, out_idx, report);
+        }
+        Opcode::LayerPush(layer) | Opcode::LayerSet(layer) => {
+            check_layer(pc, layer, report);
+        }
+        Opcode::BindLayerHold(in_idx, layer) => {
+            check_input(pc, in_idx, report);
+            check_layer(pc, layer, report);
+        }
+        Opcode::ZoneAssign(in_idx, zone) => {
+            // Virtual input 0 can be assigned to a zone too.
+            if in_idx as usize >= MAX_INPUTS {
+                report(Diagnostic::new(pc, Problem::InputOutOfRange(in_idx)));
+            }
+            check_zone(pc, zone, report);
+        }
+        Opcode::ZoneSelect(zone) => check_zone(pc, zone, report),
+        Opcode::Load(reg, _) | Opcode::Not(reg) => check_register(pc, reg, report),
+        Opcode::ReadInput(reg, in_idx) => {
+            check_register(pc, reg, report);
+            check_input(pc, in_idx, report);
+        }
+        Opcode::ReadOutput(reg, out_idx) => {
+            check_register(pc, reg, report);
+            check_output(pc, out_idx, report);
+        }
+        Opcode::Eq(first, second)
+        | Opcode::Lt(first, second)
+        | Opcode::Gt(first, second)
+        | Opcode::And(first, second)
+        | Opcode::Or(first, second) => {
+            check_register(pc, first, report);
+            check_register(pc, second, report);
+        }
+        Opcode::CallConditionally(reg, if_true, if_false) => {
+            check_proc(if_true);
+            check_proc(if_false);
+            check_register(pc, reg, report);
+        }
+        Opcode::Set(var, _) | Opcode::Inc(var) | Opcode::Dec(var) | Opcode::Add(var, _) => {
+            check_variable(pc, var, report);
+        }
+        Opcode::Mod(var, divisor) => {
+            check_variable(pc, var, report);
+            if divisor == 0 {
+                report(Diagnostic::new(pc, Problem::ModuloByZero));
+            }
+        }
+        Opcode::LoadVar(reg, var)
+        | Opcode::StoreVar(var, reg)
+        | Opcode::VarEq(reg, var, _)
+        | Opcode::VarLt(reg, var, _)
+        | Opcode::VarGt(reg, var, _) => {
+            check_register(pc, reg, report);
+            check_variable(pc, var, report);
+        }
+        Opcode::JumpIfZero(reg, _) | Opcode::JumpIfNotZero(reg, _) | Opcode::Loop(reg, _) => {
+            check_register(pc, reg, report);
+        }
+        Opcode::Syscall(id, _) => {
+            // Handlers are registered at runtime, only the table size is known.
+            if id as usize >= MAX_SYSCALLS {
+                report(Diagnostic::new(pc, Problem::SyscallOutOfRange(id)));
+            }
+        }
+        // Targets are checked separately.
+        Opcode::Jump(_) | Opcode::JumpTo(_) => {}
+        Opcode::Noop
+        | Opcode::Start(_)
+        | Opcode::Stop
+        | Opcode::LayerPop
+        | Opcode::LayerDefault
+        | Opcode::Wait(_)
+        | Opcode::WaitForRelease(_)
+        | Opcode::BindClearAll => {}
+    }
+}
+
+/// Input 0 is reserved and can't be bound.
+fn check_input(pc: usize, in_idx: InIdx, report: &mut impl FnMut(Diagnostic)) {
+    if in_idx == 0 || in_idx as usize >= MAX_INPUTS {
+        report(Diagnostic::new(pc, Problem::InputOutOfRange(in_idx)));
+    }
+}
+
+/// Argument references are accepted in place of outputs and layers.
+fn check_output(pc: usize, out_idx: OutIdx, report: &mut impl FnMut(Diagnostic)) {
+    if out_idx as usize >= MAX_OUTPUTS && out_idx != ARG0 && out_idx != ARG1 {
+        report(Diagnostic::new(pc, Problem::OutputOutOfRange(out_idx)));
+    }
+}
+
+fn check_layer(pc: usize, layer: LayerIdx, report: &mut impl FnMut(Diagnostic)) {
+    if layer as usize >= MAX_LAYERS && layer != ARG0 && layer != ARG1 {
+        report(Diagnostic::new(pc, Problem::LayerOutOfRange(layer)));
+    }
+}
+
+fn check_zone(pc: usize, zone: ZoneIdx, report: &mut impl FnMut(Diagnostic)) {
+    if zone as usize >= MAX_ZONES {
+        report(Diagnostic::new(pc, Problem::ZoneOutOfRange(zone)));
+    }
+}
+
+fn check_register(pc: usize, reg: RegIdx, report: &mut impl FnMut(Diagnostic)) {
+    if reg as usize >= REGISTERS {
+        report(Diagnostic::new(pc, Problem::RegisterOutOfRange(reg)));
+    }
+}
+
+fn check_variable(pc: usize, var: VarIdx, report: &mut impl FnMut(Diagnostic)) {
+    if var as usize >= VARIABLES {
+        report(Diagnostic::new(pc, Problem::VariableOutOfRange(var)));
+    }
+}
+
+/// Absolute target of a jump opcode located at `pc`. Might be negative for
+/// invalid relative jumps.
+fn jump_target(pc: usize, opcode: &Opcode) -> Option<isize> {
+    match *opcode {
+        Opcode::Jump(offset)
+        | Opcode::JumpIfZero(_, offset)
+        | Opcode::JumpIfNotZero(_, offset)
+        | Opcode::Loop(_, offset) => Some(pc as isize + offset as isize),
+        Opcode::JumpTo(target) => Some(target as isize),
+        _ => None,
+    }
+}
+
+/// Check that jumps land after the Start and at most on the Stop of their
+/// own procedure.
+fn check_jumps(program: &[Opcode], report: &mut impl FnMut(Diagnostic)) {
+    let mut start = 0;
+    while start < program.len() {
+        if !matches!(program[start], Opcode::Start(_)) {
+            start += 1;
+            continue;
+        }
+        // Stop or the opcode which should have been the Stop.
+        let end = program[start + 1..]
+            .iter()
+            .position(|opcode| matches!(opcode, Opcode::Start(_) | Opcode::Stop))
+            .map_or(program.len(), |offset| start + 1 + offset);
+
+        for (pc, opcode) in program.iter().enumerate().take(end).skip(start + 1) {
+            let Some(target) = jump_target(pc, opcode) else {
+                continue;
+            };
+            if target <= start as isize || target > end as isize {
+                report(Diagnostic::new(pc, Problem::JumpOutOfProcedure(target)));
+            }
+        }
+        start = end;
+    }
+}
+
+/// Procedures called by the opcode.
+fn callees(opcode: &Opcode) -> [Option<ProcIdx>; 2] {
+    match *opcode {
+        Opcode::Call(proc) | Opcode::CallWith(proc, _, _) => [Some(proc), None],
+        Opcode::CallConditionally(_, if_true, if_false) => [Some(if_true), Some(if_false)],
+        _ => [None, None],
+    }
+}
+
+/// Find cycles in the static call graph using depth-first search with a fixed
+/// size stack.
+fn check_recursion(
+    program: &[Opcode],
+    starts: &[Option<usize>; PROCEDURE_IDS],
+    report: &mut impl FnMut(Diagnostic),
+) {
+    const UNVISITED: u8 = 0;
+    const ON_STACK: u8 = 1;
+    const DONE: u8 = 2;
+
+    let mut state = [UNVISITED; PROCEDURE_IDS];
+    // Procedures being visited with a position of the scan within them. Scan
+    // position points at the opcode and its callee slot.
+    let mut stack = [(0 as ProcIdx, 0usize, 0usize); PROCEDURE_IDS];
+
+    for root in 0..PROCEDURE_IDS {
+        let Some(root_start) = starts[root] else {
+            continue;
+        };
+        if state[root] != UNVISITED {
+            continue;
+        }
+        state[root] = ON_STACK;
+        stack[0] = (root as ProcIdx, root_start + 1, 0);
+        let mut depth = 1;
+
+        while depth > 0 {
+            let (proc, pc, slot) = stack[depth - 1];
+            let opcode = match program.get(pc) {
+                None | Some(Opcode::Stop) | Some(Opcode::Start(_)) => {
+                    state[proc as usize] = DONE;
+                    depth -= 1;
+                    continue;
+                }
+                Some(opcode) => opcode,
+            };
+            let callees = callees(opcode);
+            stack[depth - 1] = if slot + 1 < callees.len() {
+                (proc, pc, slot + 1)
+            } else {
+                (proc, pc + 1, 0)
+            };
+
+            let Some(callee) = callees[slot] else {
+                continue;
+            };
+            let Some(callee_start) = starts.get(callee as usize).copied().flatten() else {
+                // Reported by the operand check.
+                continue;
+            };
+            match state[callee as usize] {
+                UNVISITED => {
+                    state[callee as usize] = ON_STACK;
+                    stack[depth] = (callee, callee_start + 1, 0);
+                    depth += 1;
+                }
+                ON_STACK => {
+                    report(Diagnostic::new(pc, Problem::Recursion(callee)));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(program: &[Opcode]) -> Vec<(usize, Problem)> {
+        verify(program)
+            .into_iter()
+            .map(|diagnostic| (diagnostic.pc, diagnostic.problem))
+            .collect()
+    }
+
+    #[test]
+    fn it_accepts_valid_program() {
+        let program = [
+            Opcode::Start(0),
+            Opcode::BindShortCall(1, 1),
+            Opcode::BindLayerHold(2, 1),
+            Opcode::Stop,
+            Opcode::Noop,
+            Opcode::Start(1),
+            Opcode::Call(2),
+            Opcode::Call(2),
+            Opcode::Stop,
+            Opcode::Start(2),
+            Opcode::Toggle(10),
+            Opcode::Call(255),
+            Opcode::Stop,
+            Opcode::Start(255),
+            Opcode::Stop,
+        ];
+        assert_eq!(problems(&program), vec![]);
+    }
+
+    #[test]
+    fn it_finds_structure_problems() {
+        let program = [
+            Opcode::Start(0),
+            Opcode::Toggle(1),
+            Opcode::Start(1),
+            Opcode::Stop,
+            Opcode::Toggle(2),
+            Opcode::Start(1),
+            Opcode::Stop,
+            Opcode::Start(2),
+            Opcode::Toggle(3),
+        ];
+        assert_eq!(
+            problems(&program),
+            vec![
+                (0, Problem::MissingStop(0)),
+                (4, Problem::OutsideProcedure),
+                (5, Problem::DuplicateProcedure(1)),
+                (7, Problem::MissingStop(2)),
+            ]
+        );
+        assert_eq!(
+            problems(&[Opcode::Stop]),
+            vec![(0, Problem::OutsideProcedure), (0, Problem::MissingSetup),]
+        );
+    }
+
+    #[test]
+    fn it_finds_invalid_operands() {
+        let program = [
+            Opcode::Start(0),
+            Opcode::Call(3),
+            Opcode::BindShortCall(0, 0),
+            Opcode::Activate(MAX_OUTPUTS as OutIdx),
+            Opcode::LayerPush(200),
+            Opcode::ZoneSelect(MAX_ZONES as ZoneIdx),
+            Opcode::Inc(VARIABLES as VarIdx),
+            Opcode::Mod(0, 0),
+            Opcode::SetRemote(MAX_DEVICES as DevAddr, 1, OutputState::On),
+            Opcode::Stop,
+        ];
+        assert_eq!(
+            problems(&program),
+            vec![
+                (1, Problem::UnknownProcedure(3)),
+                (2, Problem::InputOutOfRange(0)),
+                (3, Problem::OutputOutOfRange(MAX_OUTPUTS as OutIdx)),
+                (4, Problem::LayerOutOfRange(200)),
+                (5, Problem::ZoneOutOfRange(MAX_ZONES as ZoneIdx)),
+                (6, Problem::VariableOutOfRange(VARIABLES as VarIdx)),
+                (7, Problem::ModuloByZero),
+                (8, Problem::DeviceOutOfRange(MAX_DEVICES as DevAddr)),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_keeps_jumps_in_procedure() {
+        let program = [
+            Opcode::Start(0),
+            Opcode::Stop,
+            Opcode::Start(1),
+            Opcode::Load(0, 3),
+            Opcode::Loop(0, 0),
+            Opcode::JumpIfZero(0, 2),
+            Opcode::JumpTo(3),
+            Opcode::Jump(-5),
+            Opcode::JumpIfNotZero(0, 2),
+            Opcode::Stop,
+            Opcode::Start(2),
+            Opcode::Jump(-11),
+            Opcode::Stop,
+        ];
+        assert_eq!(
+            problems(&program),
+            vec![
+                (7, Problem::JumpOutOfProcedure(2)),
+                (8, Problem::JumpOutOfProcedure(10)),
+                (11, Problem::JumpOutOfProcedure(0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_detects_recursion() {
+        let program = [
+            Opcode::Start(0),
+            Opcode::Call(1),
+            Opcode::Stop,
+            Opcode::Start(1),
+            Opcode::Call(2),
+            Opcode::Stop,
+            Opcode::Start(2),
+            Opcode::Call(1),
+            Opcode::Stop,
+            Opcode::Start(3),
+            Opcode::Call(3),
+            Opcode::Stop,
+            Opcode::Start(4),
+            Opcode::CallConditionally(0, 5, 4),
+            Opcode::Stop,
+            Opcode::Start(5),
+            Opcode::Stop,
+        ];
+        assert_eq!(
+            problems(&program),
+            vec![
+                (7, Problem::Recursion(1)),
+                (10, Problem::Recursion(3)),
+                (13, Problem::Recursion(4)),
+            ]
+        );
+    }
+}